@@ -0,0 +1,681 @@
+// Prints a readable timeline of the frames in a capture, or in raw bytes read from one of the UARTs,
+// to help reverse-engineer the rest of the protocol. Repeated frames are collapsed into runs, and
+// Unknown(..) frames are grouped by opcode (the third byte of the frame) at the end.
+
+use desk_controller::{
+    read_capture, CapturedBytes, ChecksumPolicy, DataFrame, DeskToPanelMessage, Direction,
+    FrameDecoder, FrameError, PanelToDeskMessage, DATA_FRAME_SIZE,
+};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter, Write};
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: desk-dissect [--all] <capture.jsonl>
+       desk-dissect [--all] --hex <desk|panel> [<file>]
+
+Prints a timeline of the frames in a capture (see files.capture), or in hex bytes read from
+the desk or panel UART (from <file>, or stdin if it's missing or -). Hex bytes may be separated
+by whitespace, commas or colons, and anything after a # is ignored.
+
+Options:
+  --all    print every frame instead of collapsing repeated frames into runs
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", USAGE);
+        return;
+    }
+
+    match run(&args) {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Input {
+    Capture(PathBuf),
+    // Reads stdin if there's no path
+    Hex(Direction, Option<PathBuf>),
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    all: bool,
+    input: Input,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut all = false;
+    let mut hex_direction = None;
+    let mut paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--all" => all = true,
+            "--hex" => {
+                hex_direction = match args.next().map(String::as_str) {
+                    Some("desk") => Some(Direction::DeskToPanel),
+                    Some("panel") => Some(Direction::PanelToDesk),
+                    _ => return Err("--hex must be followed by desk or panel".to_string()),
+                }
+            }
+            "-" => paths.push(arg),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.len() > 1 {
+        return Err(format!("Expected one file, got {}", paths.len()));
+    }
+    let path = paths.first().map(PathBuf::from);
+
+    let input = match (hex_direction, path) {
+        (Some(direction), path) => {
+            Input::Hex(direction, path.filter(|path| path.to_str() != Some("-")))
+        }
+        (None, Some(path)) => Input::Capture(path),
+        (None, None) => return Err(format!("Missing capture file\n\n{}", USAGE)),
+    };
+
+    Ok(Options { all, input })
+}
+
+fn run(args: &[String]) -> Result<String, Box<dyn Error>> {
+    let options = parse_args(args)?;
+
+    let dissection = match &options.input {
+        Input::Capture(path) => Dissection::from_capture(read_capture(path)?),
+        Input::Hex(direction, path) => {
+            let text = match path {
+                Some(path) => fs::read_to_string(path)?,
+                None => {
+                    let mut text = String::new();
+                    io::stdin().read_to_string(&mut text)?;
+                    text
+                }
+            };
+            Dissection::from_bytes(*direction, &parse_hex(&text)?)
+        }
+    };
+
+    Ok(dissection.render(options.all))
+}
+
+// Bytes are pairs of hex digits, optionally prefixed with 0x
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+
+        for token in line.split(|c: char| c.is_whitespace() || c == ',' || c == ':') {
+            let digits = token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_prefix("0X"))
+                .unwrap_or(token);
+
+            if !digits.bytes().all(|b| b.is_ascii_hexdigit()) || digits.len() % 2 != 0 {
+                return Err(format!("Invalid hex on line {}: {:?}", i + 1, token));
+            }
+
+            for pair in digits.as_bytes().chunks(2) {
+                // Only ASCII hex digits are left, so this can't fail
+                let pair = std::str::from_utf8(pair).unwrap();
+                bytes.push(u8::from_str_radix(pair, 16).unwrap());
+            }
+        }
+    }
+
+    Ok(bytes)
+}
+
+// Where a frame was seen: the time since the capture started, or the offset of its first byte
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Position {
+    Elapsed(Duration),
+    Offset(usize),
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Position::Elapsed(elapsed) => write!(f, "{:.6}s", elapsed.as_secs_f64()),
+            Position::Offset(offset) => write!(f, "byte {}", offset),
+        }
+    }
+}
+
+// Consecutive identical frames in one direction
+#[derive(Debug, PartialEq)]
+struct Run {
+    start: Position,
+    end: Position,
+    direction: Direction,
+    frame: DataFrame,
+    count: usize,
+    // Dropped while looking for the first frame of the run
+    dropped_bytes: usize,
+}
+
+struct Dissection {
+    // In the order they were seen
+    frames: Vec<(Position, Direction, DataFrame, usize)>,
+    // Bytes left over at the end of the input that didn't make up a frame
+    trailing_bytes: usize,
+}
+
+impl Dissection {
+    // Splits each direction's bytes into frames the same way as the transports,
+    // placing each frame at the time of the read that completed it
+    fn from_capture(mut captured: Vec<CapturedBytes>) -> Dissection {
+        // Reads are written as each direction makes them, so they can be slightly out of order
+        captured.sort_by_key(|captured| captured.elapsed);
+
+        // Each direction's decoder, and how many bytes it has read since its last frame
+        let mut desk = (FrameDecoder::new(), 0);
+        let mut panel = (FrameDecoder::new(), 0);
+        let mut frames = Vec::new();
+
+        for captured in &captured {
+            let (decoder, unframed_bytes) = match captured.direction {
+                Direction::DeskToPanel => &mut desk,
+                Direction::PanelToDesk => &mut panel,
+            };
+
+            let mut rest = &captured.bytes[..];
+            // The bytes after the last frame in this read
+            let mut after_frame = rest.len();
+            while let Some(frame) = decoder.decode(&mut rest) {
+                frames.push((
+                    Position::Elapsed(captured.elapsed),
+                    captured.direction,
+                    frame,
+                    decoder.take_dropped_byte_count(),
+                ));
+                *unframed_bytes = 0;
+                after_frame = rest.len();
+            }
+            *unframed_bytes += after_frame;
+        }
+
+        Dissection {
+            frames,
+            trailing_bytes: desk.1 + panel.1,
+        }
+    }
+
+    // Splits the bytes into frames the same way as the transports
+    fn from_bytes(direction: Direction, bytes: &[u8]) -> Dissection {
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        let mut rest = bytes;
+        // Just after the last frame
+        let mut offset = 0;
+
+        while let Some(frame) = decoder.decode(&mut rest) {
+            offset = bytes.len() - rest.len();
+            let position = Position::Offset(offset - DATA_FRAME_SIZE);
+            frames.push((
+                position,
+                direction,
+                frame,
+                decoder.take_dropped_byte_count(),
+            ));
+        }
+
+        Dissection {
+            frames,
+            trailing_bytes: bytes.len() - offset,
+        }
+    }
+
+    fn runs(&self, all: bool) -> Vec<Run> {
+        let mut runs: Vec<Run> = Vec::new();
+        // The run still open in each direction, if any
+        let mut open_desk = None;
+        let mut open_panel = None;
+
+        for (position, direction, frame, dropped_bytes) in &self.frames {
+            let open = match direction {
+                Direction::DeskToPanel => &mut open_desk,
+                Direction::PanelToDesk => &mut open_panel,
+            };
+
+            if let Some(i) = *open {
+                let run: &mut Run = &mut runs[i];
+                if !all && *dropped_bytes == 0 && run.frame == *frame {
+                    run.count += 1;
+                    run.end = *position;
+                    continue;
+                }
+            }
+
+            *open = Some(runs.len());
+            runs.push(Run {
+                start: *position,
+                end: *position,
+                direction: *direction,
+                frame: *frame,
+                count: 1,
+                dropped_bytes: *dropped_bytes,
+            });
+        }
+
+        runs
+    }
+
+    fn render(&self, all: bool) -> String {
+        let runs = self.runs(all);
+        let mut out = String::new();
+
+        for run in &runs {
+            let description = describe(run.direction, &run.frame);
+
+            if run.dropped_bytes > 0 {
+                writeln!(
+                    out,
+                    "{:>14}  {:<11}  dropped {} bytes",
+                    run.start.to_string(),
+                    arrow(run.direction),
+                    run.dropped_bytes
+                )
+                .unwrap();
+            }
+
+            write!(
+                out,
+                "{:>14}  {:<11}  {:<20}  {:<24}  {}",
+                run.start.to_string(),
+                arrow(run.direction),
+                hex(&run.frame),
+                description.status,
+                description.message
+            )
+            .unwrap();
+            if run.count > 1 {
+                write!(out, "  x{} until {}", run.count, run.end).unwrap();
+            }
+            out.push('\n');
+        }
+
+        out.push('\n');
+        for &direction in &[Direction::DeskToPanel, Direction::PanelToDesk] {
+            let frames = self.frames.iter().filter(|(_, d, ..)| *d == direction);
+            let total = frames.clone().count();
+            let invalid = frames
+                .clone()
+                .filter(|(_, _, frame, _)| describe(direction, frame).status != "ok")
+                .count();
+            let dropped_bytes: usize = frames.map(|(.., dropped_bytes)| dropped_bytes).sum();
+
+            writeln!(
+                out,
+                "{}: {} frames, {} invalid, {} bytes dropped",
+                arrow(direction),
+                total,
+                invalid,
+                dropped_bytes
+            )
+            .unwrap();
+        }
+        if self.trailing_bytes > 0 {
+            writeln!(
+                out,
+                "{} bytes at the end didn't make up a frame",
+                self.trailing_bytes
+            )
+            .unwrap();
+        }
+
+        let unknown = unknown_opcodes(&runs);
+        if !unknown.is_empty() {
+            out.push_str("\nUnknown frames by opcode:\n");
+        }
+        for opcode in &unknown {
+            writeln!(
+                out,
+                "  {} opcode 0x{:02x}: {} frames in {} runs, {} payloads, first at {}",
+                arrow(opcode.direction),
+                opcode.opcode,
+                opcode.frames,
+                opcode.runs,
+                opcode.payloads.len(),
+                opcode.first
+            )
+            .unwrap();
+            for (frame, count) in &opcode.payloads {
+                writeln!(out, "    {}  x{}", hex(frame), count).unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+struct Description {
+    // "ok" for valid frames
+    status: String,
+    message: String,
+    // The opcode of a valid frame that decoded to Unknown(..)
+    unknown_opcode: Option<u8>,
+}
+
+fn describe(direction: Direction, frame: &[u8]) -> Description {
+    match direction {
+        Direction::DeskToPanel => describe_with(
+            frame,
+            DeskToPanelMessage::from_frame,
+            DeskToPanelMessage::recover_frame,
+            |message| match message {
+                DeskToPanelMessage::Unknown(_, opcode, ..) => Some(*opcode),
+                _ => None,
+            },
+        ),
+        Direction::PanelToDesk => describe_with(
+            frame,
+            PanelToDeskMessage::from_frame,
+            PanelToDeskMessage::recover_frame,
+            |message| match message {
+                PanelToDeskMessage::Unknown(_, opcode, ..) => Some(*opcode),
+                _ => None,
+            },
+        ),
+    }
+}
+
+fn describe_with<M: Debug>(
+    frame: &[u8],
+    from_frame: fn(&[u8]) -> Result<M, FrameError>,
+    recover_frame: fn(&[u8], ChecksumPolicy) -> Option<M>,
+    unknown_opcode: fn(&M) -> Option<u8>,
+) -> Description {
+    match from_frame(frame) {
+        Ok(message) => Description {
+            status: "ok".to_string(),
+            message: format!("{:?}", message),
+            unknown_opcode: unknown_opcode(&message),
+        },
+        Err(FrameError::InvalidChecksum { expected, .. }) => Description {
+            status: format!("bad checksum (0x{:02x})", expected),
+            message: match recover_frame(frame, ChecksumPolicy::Repair) {
+                Some(message) => format!("{:?} if repaired", message),
+                None => String::new(),
+            },
+            unknown_opcode: None,
+        },
+        Err(e) => Description {
+            status: "invalid".to_string(),
+            message: e.to_string(),
+            unknown_opcode: None,
+        },
+    }
+}
+
+struct UnknownOpcode {
+    direction: Direction,
+    opcode: u8,
+    frames: usize,
+    runs: usize,
+    first: Position,
+    // Each distinct frame with the opcode, and how many times it was seen
+    payloads: Vec<(DataFrame, usize)>,
+}
+
+// Sorted by direction, then opcode
+fn unknown_opcodes(runs: &[Run]) -> Vec<UnknownOpcode> {
+    let mut opcodes: Vec<UnknownOpcode> = Vec::new();
+
+    for run in runs {
+        let opcode = match describe(run.direction, &run.frame).unknown_opcode {
+            Some(opcode) => opcode,
+            None => continue,
+        };
+
+        let i = match opcodes
+            .iter()
+            .position(|o| o.direction == run.direction && o.opcode == opcode)
+        {
+            Some(i) => i,
+            None => {
+                opcodes.push(UnknownOpcode {
+                    direction: run.direction,
+                    opcode,
+                    frames: 0,
+                    runs: 0,
+                    first: run.start,
+                    payloads: Vec::new(),
+                });
+                opcodes.len() - 1
+            }
+        };
+
+        let entry = &mut opcodes[i];
+        entry.frames += run.count;
+        entry.runs += 1;
+        match entry
+            .payloads
+            .iter_mut()
+            .find(|(frame, _)| *frame == run.frame)
+        {
+            Some((_, count)) => *count += run.count,
+            None => entry.payloads.push((run.frame, run.count)),
+        }
+    }
+
+    opcodes.sort_by_key(|o| (o.direction == Direction::PanelToDesk, o.opcode));
+    opcodes
+}
+
+fn arrow(direction: Direction) -> &'static str {
+    match direction {
+        Direction::DeskToPanel => "desk->panel",
+        Direction::PanelToDesk => "panel->desk",
+    }
+}
+
+fn hex(frame: &[u8]) -> String {
+    frame
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse_args(&args(&["capture.jsonl"])).unwrap(),
+            Options {
+                all: false,
+                input: Input::Capture(PathBuf::from("capture.jsonl")),
+            }
+        );
+        assert_eq!(
+            parse_args(&args(&["--all", "--hex", "panel", "-"])).unwrap(),
+            Options {
+                all: true,
+                input: Input::Hex(Direction::PanelToDesk, None),
+            }
+        );
+        assert_eq!(
+            parse_args(&args(&["--hex", "desk", "desk.txt"])).unwrap(),
+            Options {
+                all: false,
+                input: Input::Hex(Direction::DeskToPanel, Some(PathBuf::from("desk.txt"))),
+            }
+        );
+
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["--hex"])).is_err());
+        assert!(parse_args(&args(&["--hex", "both"])).is_err());
+        assert!(parse_args(&args(&["--verbose", "capture.jsonl"])).is_err());
+        assert!(parse_args(&args(&["a.jsonl", "b.jsonl"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(
+            parse_hex("68 01 00\n0x01,5e:60 # height\n\n16").unwrap(),
+            vec![0x68, 0x01, 0x00, 0x01, 0x5e, 0x60, 0x16]
+        );
+        assert_eq!(parse_hex("6801").unwrap(), vec![0x68, 0x01]);
+
+        assert!(parse_hex("68 1").is_err());
+        assert!(parse_hex("68 zz").is_err());
+    }
+
+    #[test]
+    fn test_dissect_bytes() {
+        let mut bytes = vec![0u8, 1u8];
+        for _ in 0..3 {
+            bytes.extend(&DeskToPanelMessage::Height(100.0).as_frame());
+        }
+        let mut bad_checksum = DeskToPanelMessage::Height(100.5).as_frame();
+        bad_checksum[5] += 1;
+        bytes.extend(&bad_checksum);
+        for payload in &[0u8, 0u8, 1u8] {
+            bytes.extend(
+                &DeskToPanelMessage::Unknown(1, 0x20, *payload, 0, 0x21 + *payload).as_frame(),
+            );
+        }
+        bytes.push(104);
+
+        let dissection = Dissection::from_bytes(Direction::DeskToPanel, &bytes);
+        assert_eq!(dissection.trailing_bytes, 1);
+
+        let runs = dissection.runs(false);
+        assert_eq!(
+            runs.iter()
+                .map(|run| (run.start, run.count, run.dropped_bytes))
+                .collect::<Vec<_>>(),
+            vec![
+                (Position::Offset(2), 3, 2),
+                (Position::Offset(23), 1, 0),
+                (Position::Offset(30), 2, 0),
+                (Position::Offset(44), 1, 0),
+            ]
+        );
+        assert_eq!(dissection.runs(true).len(), 7);
+
+        let output = dissection.render(false);
+        for expected in &[
+            "        byte 2  desk->panel  dropped 2 bytes",
+            "        byte 2  desk->panel  68 01 00 01 5e 60 16  ok                        Height(100.0)  x3 until byte 16",
+            "       byte 23  desk->panel  68 01 00 01 63 66 16  bad checksum (0x65)       Height(100.5) if repaired",
+            "desk->panel: 7 frames, 1 invalid, 2 bytes dropped",
+            "panel->desk: 0 frames, 0 invalid, 0 bytes dropped",
+            "1 bytes at the end didn't make up a frame",
+            "  desk->panel opcode 0x20: 3 frames in 2 runs, 2 payloads, first at byte 30",
+            "    68 01 20 00 00 21 16  x2",
+            "    68 01 20 01 00 22 16  x1",
+        ] {
+            assert!(
+                output.lines().any(|line| line == *expected),
+                "missing {:?} in:\n{}",
+                expected,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_dissect_capture() {
+        let bytes = |ms, direction, bytes: &[u8]| {
+            CapturedBytes::new(Duration::from_millis(ms), direction, bytes)
+        };
+        let height = DeskToPanelMessage::Height(75.0).as_frame();
+        let mut up = vec![0u8];
+        up.extend(&PanelToDeskMessage::Up.as_frame());
+
+        let dissection = Dissection::from_capture(vec![
+            bytes(16, Direction::DeskToPanel, &height),
+            bytes(0, Direction::DeskToPanel, &height[..4]),
+            bytes(4, Direction::DeskToPanel, &height[4..]),
+            bytes(8, Direction::PanelToDesk, &up),
+            bytes(
+                24,
+                Direction::DeskToPanel,
+                &DeskToPanelMessage::Height(75.5).as_frame(),
+            ),
+            bytes(32, Direction::DeskToPanel, &height[..2]),
+        ]);
+        assert_eq!(dissection.trailing_bytes, 2);
+
+        // Frames are placed at the read that completed them, and runs continue across frames in the other direction
+        assert_eq!(
+            dissection
+                .runs(false)
+                .iter()
+                .map(|run| (
+                    run.start,
+                    run.end,
+                    run.direction,
+                    run.count,
+                    run.dropped_bytes
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Position::Elapsed(Duration::from_millis(4)),
+                    Position::Elapsed(Duration::from_millis(16)),
+                    Direction::DeskToPanel,
+                    2,
+                    0
+                ),
+                (
+                    Position::Elapsed(Duration::from_millis(8)),
+                    Position::Elapsed(Duration::from_millis(8)),
+                    Direction::PanelToDesk,
+                    1,
+                    1
+                ),
+                (
+                    Position::Elapsed(Duration::from_millis(24)),
+                    Position::Elapsed(Duration::from_millis(24)),
+                    Direction::DeskToPanel,
+                    1,
+                    0
+                ),
+            ]
+        );
+
+        let output = dissection.render(false);
+        for expected in &[
+            "     0.008000s  panel->desk  dropped 1 bytes",
+            "     0.008000s  panel->desk  68 01 01 00 00 02 16  ok                        Up",
+            "panel->desk: 1 frames, 0 invalid, 1 bytes dropped",
+            "2 bytes at the end didn't make up a frame",
+        ] {
+            assert!(
+                output.lines().any(|line| line == *expected),
+                "missing {:?} in:\n{}",
+                expected,
+                output
+            );
+        }
+    }
+}
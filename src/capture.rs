@@ -0,0 +1,330 @@
+// Captures of the raw bytes read from the desk and panel UARTs, for debugging the bus.
+// A capture is a JSON Lines file with one read per line, e.g.
+// {"elapsed_us":8012,"direction":"desk_to_panel","bytes":[104,1,0,1,94,96,22],"frames":[{"bytes":[104,1,0,1,94,96,22],"valid":true}]}
+// The bytes are recorded before they are decoded, so invalid frames and the bytes dropped between
+// frames are kept. `elapsed_us` is measured from the start of the capture with a monotonic clock.
+// `frames` lists the frames that each read completed, with whether their checksum was valid
+// (bytes without a start and end byte in the right places never make a frame).
+
+use crate::decoder::FrameDecoder;
+use crate::protocol;
+use crate::protocol::DataFrame;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    DeskToPanel,
+    PanelToDesk,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::DeskToPanel => "desk_to_panel",
+            Direction::PanelToDesk => "panel_to_desk",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedBytes {
+    pub elapsed: Duration,
+    pub direction: Direction,
+    // As read, so they may hold any number of frames, parts of frames or bytes between frames
+    pub bytes: Vec<u8>,
+    // The frames completed by these bytes (which may have started in earlier reads)
+    pub frames: Vec<CapturedFrame>,
+}
+
+impl CapturedBytes {
+    // Without any frames - CaptureWriter::record decodes them
+    pub fn new(elapsed: Duration, direction: Direction, bytes: &[u8]) -> CapturedBytes {
+        CapturedBytes {
+            elapsed,
+            direction,
+            bytes: bytes.to_vec(),
+            frames: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    pub bytes: DataFrame,
+    // Whether the checksum was valid
+    pub valid: bool,
+}
+
+impl CapturedFrame {
+    pub fn new(frame: DataFrame) -> CapturedFrame {
+        CapturedFrame {
+            bytes: frame,
+            valid: protocol::validate_checksum(&frame).is_ok(),
+        }
+    }
+}
+
+// One line of a capture file
+#[derive(Serialize, Deserialize)]
+struct Record {
+    elapsed_us: u64,
+    direction: Direction,
+    bytes: Vec<u8>,
+    #[serde(default)]
+    frames: Vec<CapturedFrame>,
+}
+
+pub struct CaptureWriter {
+    out: Mutex<Box<dyn Write + Send>>,
+    started_at: Instant,
+    // Separate from the controller's decoders, so that the capture doesn't depend on how they're used
+    desk_decoder: Mutex<FrameDecoder>,
+    panel_decoder: Mutex<FrameDecoder>,
+}
+
+impl CaptureWriter {
+    // Truncates any existing capture at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<CaptureWriter, CaptureError> {
+        Ok(CaptureWriter::new(LineWriter::new(File::create(path)?)))
+    }
+
+    pub fn new<W: Write + Send + 'static>(out: W) -> CaptureWriter {
+        CaptureWriter {
+            out: Mutex::new(Box::new(out)),
+            started_at: Instant::now(),
+            desk_decoder: Mutex::new(FrameDecoder::new()),
+            panel_decoder: Mutex::new(FrameDecoder::new()),
+        }
+    }
+
+    // Records bytes as read now, along with the frames that they complete
+    pub fn record(&self, direction: Direction, bytes: &[u8]) -> Result<(), CaptureError> {
+        let elapsed = self.started_at.elapsed();
+
+        let decoder = match direction {
+            Direction::DeskToPanel => &self.desk_decoder,
+            Direction::PanelToDesk => &self.panel_decoder,
+        };
+        let mut frames = Vec::new();
+        {
+            let mut decoder = decoder.lock().unwrap();
+            let mut remaining = bytes;
+            while let Some(frame) = decoder.decode(&mut remaining) {
+                frames.push(CapturedFrame::new(frame));
+            }
+            decoder.take_dropped_byte_count();
+        }
+
+        self.write(&CapturedBytes {
+            elapsed,
+            direction,
+            bytes: bytes.to_vec(),
+            frames,
+        })
+    }
+
+    pub fn write(&self, captured: &CapturedBytes) -> Result<(), CaptureError> {
+        let mut line = serde_json::to_string(&Record {
+            elapsed_us: captured.elapsed.as_micros() as u64,
+            direction: captured.direction,
+            bytes: captured.bytes.clone(),
+            frames: captured.frames.clone(),
+        })?;
+        line.push('\n');
+
+        self.out.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), CaptureError> {
+        self.out.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+pub fn read_capture<P: AsRef<Path>>(path: P) -> Result<Vec<CapturedBytes>, CaptureError> {
+    parse_capture(BufReader::new(File::open(path)?))
+}
+
+// Blank lines are skipped
+pub fn parse_capture<R: BufRead>(reader: R) -> Result<Vec<CapturedBytes>, CaptureError> {
+    let mut captured = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: Record =
+            serde_json::from_str(&line).map_err(|e| CaptureError::InvalidLine {
+                line: i + 1,
+                message: e.to_string(),
+            })?;
+
+        captured.push(CapturedBytes {
+            elapsed: Duration::from_micros(record.elapsed_us),
+            direction: record.direction,
+            bytes: record.bytes,
+            frames: record.frames,
+        });
+    }
+
+    Ok(captured)
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(io::Error),
+    Serialize(serde_json::Error),
+    InvalidLine { line: usize, message: String },
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(e: io::Error) -> CaptureError {
+        CaptureError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for CaptureError {
+    fn from(e: serde_json::Error) -> CaptureError {
+        CaptureError::Serialize(e)
+    }
+}
+
+impl Display for CaptureError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Io(e) => write!(f, "Capture file error: {}", e),
+            CaptureError::Serialize(e) => write!(f, "Failed to serialize captured bytes: {}", e),
+            CaptureError::InvalidLine { line, message } => {
+                write!(f, "Invalid capture line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl Error for CaptureError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{DeskToPanelMessage, PanelToDeskMessage};
+    use std::sync::Arc;
+
+    // Lets the test read back what a CaptureWriter wrote
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture_round_trip() {
+        let buffer = SharedBuffer::default();
+        let writer = CaptureWriter::new(buffer.clone());
+
+        let height = DeskToPanelMessage::Height(100.0).as_frame();
+
+        writer.record(Direction::DeskToPanel, &height[..4]).unwrap();
+        writer
+            .record(Direction::PanelToDesk, &PanelToDeskMessage::Up.as_frame())
+            .unwrap();
+        let mut bad_checksum = height;
+        bad_checksum[5] ^= 1;
+        writer.record(Direction::DeskToPanel, &height[4..]).unwrap();
+        writer
+            .record(Direction::DeskToPanel, &bad_checksum)
+            .unwrap();
+
+        let contents = buffer.0.lock().unwrap().clone();
+        let captured = parse_capture(&contents[..]).unwrap();
+
+        assert_eq!(captured.len(), 4);
+        assert_eq!(captured[0].direction, Direction::DeskToPanel);
+        assert_eq!(captured[0].bytes, height[..4].to_vec());
+        assert_eq!(captured[1].direction, Direction::PanelToDesk);
+        assert_eq!(
+            captured[1].bytes,
+            PanelToDeskMessage::Up.as_frame().to_vec()
+        );
+        assert_eq!(captured[2].bytes, height[4..].to_vec());
+
+        // Frames are recorded with the read that completes them
+        assert_eq!(captured[0].frames, vec![]);
+        assert_eq!(
+            captured[1].frames,
+            vec![CapturedFrame::new(PanelToDeskMessage::Up.as_frame())]
+        );
+        assert_eq!(
+            captured[2].frames,
+            vec![CapturedFrame {
+                bytes: height,
+                valid: true
+            }]
+        );
+        assert_eq!(
+            captured[3].frames,
+            vec![CapturedFrame {
+                bytes: bad_checksum,
+                valid: false
+            }]
+        );
+
+        assert!(
+            captured[0].elapsed <= captured[1].elapsed
+                && captured[1].elapsed <= captured[2].elapsed
+        );
+    }
+
+    #[test]
+    fn test_parse_capture() {
+        let capture = r#"{"elapsed_us":8000,"direction":"desk_to_panel","bytes":[0,104,1,0,1,94,96,22]}
+
+{"elapsed_us":16000,"direction":"panel_to_desk","bytes":[104,1,3],"frames":[{"bytes":[104,3,3,0,0,6,22],"valid":true}]}
+"#;
+
+        assert_eq!(
+            parse_capture(capture.as_bytes()).unwrap(),
+            vec![
+                CapturedBytes {
+                    elapsed: Duration::from_millis(8),
+                    direction: Direction::DeskToPanel,
+                    bytes: vec![0, 104, 1, 0, 1, 94, 96, 22],
+                    frames: vec![],
+                },
+                CapturedBytes {
+                    elapsed: Duration::from_millis(16),
+                    direction: Direction::PanelToDesk,
+                    bytes: vec![104, 1, 3],
+                    frames: vec![CapturedFrame {
+                        bytes: [104, 3, 3, 0, 0, 6, 22],
+                        valid: true,
+                    }],
+                },
+            ]
+        );
+
+        match parse_capture(&b"{\"elapsed_us\":0}\n"[..]) {
+            Err(CaptureError::InvalidLine { line: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,840 @@
+// Daemon configuration.
+// Settings are read from a TOML file, then overridden by environment variables, then by command line
+// arguments (e.g. `--desk.min_height_cm 70`). Everything is validated before the controller is started.
+
+use desk_controller::transport::{InvalidTransportError, TransportKind, UartConfig};
+use desk_controller::{ControllerConfig, InvalidConfigError};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// e.g. DESK_CONTROLLER_CONFIG=/etc/desk_controller.toml
+const CONFIG_ENV_VAR: &str = "DESK_CONTROLLER_CONFIG";
+// Only loaded if it exists. A file given through the env var or `--config` must exist.
+const DEFAULT_CONFIG_PATH: &str = "desk_controller.toml";
+
+struct Setting {
+    key: &'static str,
+    env_var: &'static str,
+    description: &'static str,
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "transport",
+        env_var: "DESK_CONTROLLER_TRANSPORT",
+        description:
+            "uart, serial, mock, simulator, replay:<desk path>,<panel path> or capture:<path>[,<speed>]",
+    },
+    Setting {
+        key: "uart.desk_path",
+        env_var: "DESK_CONTROLLER_DESK_UART_PATH",
+        description: "serial device connected to the desk",
+    },
+    Setting {
+        key: "uart.panel_path",
+        env_var: "DESK_CONTROLLER_PANEL_UART_PATH",
+        description: "serial device connected to the panel",
+    },
+    Setting {
+        key: "uart.baud_rate",
+        env_var: "DESK_CONTROLLER_BAUD_RATE",
+        description: "baud rate of both serial devices",
+    },
+    Setting {
+        key: "uart.led_gpio_pin",
+        env_var: "DESK_CONTROLLER_LED_GPIO_PIN",
+        description: "BCM GPIO pin of the status LED",
+    },
+    Setting {
+        key: "desk.min_height_cm",
+        env_var: "DESK_CONTROLLER_MIN_HEIGHT_CM",
+        description: "lowest height the controller will move to",
+    },
+    Setting {
+        key: "desk.max_height_cm",
+        env_var: "DESK_CONTROLLER_MAX_HEIGHT_CM",
+        description: "highest height the controller will move to",
+    },
+    Setting {
+        key: "desk.at_target_tolerance_cm",
+        env_var: "DESK_CONTROLLER_AT_TARGET_TOLERANCE_CM",
+        description: "how close to the target height counts as there",
+    },
+    Setting {
+        key: "desk.panel_key_reset_timeout_ms",
+        env_var: "DESK_CONTROLLER_PANEL_KEY_RESET_TIMEOUT_MS",
+        description: "how long without panel frames before the panel key is cleared",
+    },
+    Setting {
+        key: "desk.interrupt_timeout_ms",
+        env_var: "DESK_CONTROLLER_INTERRUPT_TIMEOUT_MS",
+        description: "how long the run loop waits for an interrupt",
+    },
+    Setting {
+        key: "web.address",
+        env_var: "DESK_CONTROLLER_ADDRESS",
+        description: "address to listen on (defaults to Rocket's for ROCKET_ENV)",
+    },
+    Setting {
+        key: "web.port",
+        env_var: "DESK_CONTROLLER_PORT",
+        description: "port to listen on",
+    },
+    Setting {
+        key: "mqtt.host",
+        env_var: "DESK_CONTROLLER_MQTT_HOST",
+        description: "MQTT broker to connect to (MQTT is disabled unless set)",
+    },
+    Setting {
+        key: "mqtt.port",
+        env_var: "DESK_CONTROLLER_MQTT_PORT",
+        description: "MQTT broker port",
+    },
+    Setting {
+        key: "mqtt.username",
+        env_var: "DESK_CONTROLLER_MQTT_USERNAME",
+        description: "MQTT username",
+    },
+    Setting {
+        key: "mqtt.password",
+        env_var: "DESK_CONTROLLER_MQTT_PASSWORD",
+        description: "MQTT password",
+    },
+    Setting {
+        key: "mqtt.client_id",
+        env_var: "DESK_CONTROLLER_MQTT_CLIENT_ID",
+        description: "MQTT client id, also used to identify the desk in Home Assistant",
+    },
+    Setting {
+        key: "mqtt.topic_prefix",
+        env_var: "DESK_CONTROLLER_MQTT_TOPIC_PREFIX",
+        description: "prefix of the state and command topics",
+    },
+    Setting {
+        key: "mqtt.discovery_prefix",
+        env_var: "DESK_CONTROLLER_MQTT_DISCOVERY_PREFIX",
+        description: "Home Assistant discovery prefix (empty to disable discovery)",
+    },
+    Setting {
+        key: "files.coast_profile",
+        env_var: "DESK_CONTROLLER_COAST_PROFILE",
+        description: "where the learned coast distances are stored",
+    },
+    Setting {
+        key: "files.presets",
+        env_var: "DESK_CONTROLLER_PRESETS",
+        description: "where named presets are stored",
+    },
+    Setting {
+        key: "files.panel_memory",
+        env_var: "DESK_CONTROLLER_PANEL_MEMORY",
+        description: "where panel memory slots programmed by the controller are stored",
+    },
+    Setting {
+        key: "files.schedule",
+        env_var: "DESK_CONTROLLER_SCHEDULE",
+        description: "where the sit/stand schedule is stored",
+    },
+    Setting {
+        key: "files.history",
+        env_var: "DESK_CONTROLLER_HISTORY",
+        description: "SQLite database of heights, movements and stalls",
+    },
+    Setting {
+        key: "files.capture",
+        env_var: "DESK_CONTROLLER_CAPTURE",
+        description: "records every byte read from the desk and panel (disabled unless set)",
+    },
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebConfig {
+    pub address: Option<String>,
+    pub port: u16,
+}
+
+impl Default for WebConfig {
+    fn default() -> WebConfig {
+        WebConfig {
+            address: None,
+            port: 8000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttConfig {
+    pub host: Option<String>,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    pub topic_prefix: String,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            host: None,
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: "desk_controller".to_string(),
+            topic_prefix: "desk_controller".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilesConfig {
+    pub coast_profile: PathBuf,
+    pub presets: PathBuf,
+    pub panel_memory: PathBuf,
+    pub schedule: PathBuf,
+    pub history: PathBuf,
+    pub capture: Option<PathBuf>,
+}
+
+impl Default for FilesConfig {
+    fn default() -> FilesConfig {
+        FilesConfig {
+            coast_profile: PathBuf::from("coast_profile.toml"),
+            presets: PathBuf::from("presets.toml"),
+            panel_memory: PathBuf::from("panel_memory.toml"),
+            schedule: PathBuf::from("schedule.toml"),
+            history: PathBuf::from("history.sqlite3"),
+            capture: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    // The UART (or serial) config is taken from `uart`, whatever `transport` was parsed with
+    transport: TransportKind,
+    pub uart: UartConfig,
+    pub controller: ControllerConfig,
+    pub web: WebConfig,
+    pub mqtt: MqttConfig,
+    pub files: FilesConfig,
+}
+
+// Where a setting's value came from, for error messages
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    File(PathBuf),
+    Env(&'static str),
+    CommandLine,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "config file {:?}", path),
+            Source::Env(name) => write!(f, "environment variable {}", name),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+impl Config {
+    // Loads the config file, then applies overrides from `env` and the command line `args`
+    pub fn load<E>(args: &[String], env: E) -> Result<Config, ConfigError>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let overrides = parse_args(args)?;
+
+        let mut config = Config::default();
+
+        let explicit_path = overrides
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| PathBuf::from(value))
+            .or_else(|| env(CONFIG_ENV_VAR).map(PathBuf::from));
+        match explicit_path {
+            Some(path) => config.apply_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                config.apply_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => {}
+        }
+
+        for setting in SETTINGS {
+            if let Some(value) = env(setting.env_var) {
+                config.set(setting.key, &value, &Source::Env(setting.env_var))?;
+            }
+        }
+
+        for (key, value) in overrides.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value, &Source::CommandLine)?;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn transport_kind(&self) -> TransportKind {
+        match &self.transport {
+            TransportKind::Uart(_) => TransportKind::Uart(self.uart.clone()),
+            TransportKind::Serial(_) => TransportKind::Serial(self.uart.clone()),
+            other => other.clone(),
+        }
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Read {
+            path: path.to_path_buf(),
+            source: e,
+        })?;
+
+        self.apply_toml(&contents, &Source::File(path.to_path_buf()))
+    }
+
+    fn apply_toml(&mut self, contents: &str, source: &Source) -> Result<(), ConfigError> {
+        let parse_error = |message: String| ConfigError::Parse {
+            source: source.clone(),
+            message,
+        };
+
+        let table = match contents.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => return Err(parse_error("expected a table".to_string())),
+            Err(e) => return Err(parse_error(e.to_string())),
+        };
+
+        for (name, value) in table {
+            match value {
+                toml::Value::Table(section) => {
+                    for (section_name, value) in section {
+                        let key = format!("{}.{}", name, section_name);
+                        let value = toml_value_to_string(&key, value, source)?;
+                        self.set(&key, &value, source)?;
+                    }
+                }
+                value => {
+                    let value = toml_value_to_string(&name, value, source)?;
+                    self.set(&name, &value, source)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str, source: &Source) -> Result<(), ConfigError> {
+        let invalid = |message: String| ConfigError::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            source: source.clone(),
+            message,
+        };
+
+        match key {
+            "transport" => {
+                self.transport = value
+                    .parse()
+                    .map_err(|e: InvalidTransportError| invalid(e.to_string()))?
+            }
+            "uart.desk_path" => self.uart.desk_path = PathBuf::from(value),
+            "uart.panel_path" => self.uart.panel_path = PathBuf::from(value),
+            "uart.baud_rate" => self.uart.baud_rate = parse(value).map_err(invalid)?,
+            "uart.led_gpio_pin" => self.uart.led_gpio_pin = parse(value).map_err(invalid)?,
+            "desk.min_height_cm" => {
+                self.controller.min_height_cm = parse(value).map_err(invalid)?
+            }
+            "desk.max_height_cm" => {
+                self.controller.max_height_cm = parse(value).map_err(invalid)?
+            }
+            "desk.at_target_tolerance_cm" => {
+                self.controller.at_target_tolerance_cm = parse(value).map_err(invalid)?
+            }
+            "desk.panel_key_reset_timeout_ms" => {
+                self.controller.panel_key_reset_timeout =
+                    Duration::from_millis(parse(value).map_err(invalid)?)
+            }
+            "desk.interrupt_timeout_ms" => {
+                self.controller.interrupt_timeout =
+                    Duration::from_millis(parse(value).map_err(invalid)?)
+            }
+            "web.address" => self.web.address = Some(value.to_string()),
+            "web.port" => self.web.port = parse(value).map_err(invalid)?,
+            "mqtt.host" => self.mqtt.host = Some(value.to_string()),
+            "mqtt.port" => self.mqtt.port = parse(value).map_err(invalid)?,
+            "mqtt.username" => self.mqtt.username = Some(value.to_string()),
+            "mqtt.password" => self.mqtt.password = Some(value.to_string()),
+            "mqtt.client_id" => self.mqtt.client_id = value.to_string(),
+            "mqtt.topic_prefix" => self.mqtt.topic_prefix = value.to_string(),
+            "mqtt.discovery_prefix" => self.mqtt.discovery_prefix = value.to_string(),
+            "files.coast_profile" => self.files.coast_profile = PathBuf::from(value),
+            "files.presets" => self.files.presets = PathBuf::from(value),
+            "files.panel_memory" => self.files.panel_memory = PathBuf::from(value),
+            "files.schedule" => self.files.schedule = PathBuf::from(value),
+            "files.history" => self.files.history = PathBuf::from(value),
+            "files.capture" => self.files.capture = Some(PathBuf::from(value)),
+            _ => {
+                return Err(ConfigError::UnknownSetting {
+                    key: key.to_string(),
+                    source: source.clone(),
+                })
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let in_section = |section: &str, e: InvalidConfigError| {
+            ConfigError::Invalid(InvalidConfigError::new(
+                format!("{}.{}", section, e.field()),
+                e.message(),
+            ))
+        };
+
+        if let TransportKind::Uart(_) | TransportKind::Serial(_) = self.transport {
+            self.uart.validate().map_err(|e| in_section("uart", e))?;
+        }
+
+        self.controller
+            .validate()
+            .map_err(|e| in_section("desk", e))?;
+
+        if self.web.port == 0 {
+            return Err(ConfigError::Invalid(InvalidConfigError::new(
+                "web.port",
+                "must not be zero",
+            )));
+        }
+
+        if self.web.address.as_deref() == Some("") {
+            return Err(ConfigError::Invalid(InvalidConfigError::new(
+                "web.address",
+                "must not be empty",
+            )));
+        }
+
+        self.validate_mqtt()?;
+
+        for (key, path) in &[
+            ("files.coast_profile", &self.files.coast_profile),
+            ("files.presets", &self.files.presets),
+            ("files.panel_memory", &self.files.panel_memory),
+            ("files.schedule", &self.files.schedule),
+            ("files.history", &self.files.history),
+        ] {
+            if path.as_os_str().is_empty() {
+                return Err(ConfigError::Invalid(InvalidConfigError::new(
+                    *key,
+                    "must not be empty",
+                )));
+            }
+        }
+
+        if let Some(path) = &self.files.capture {
+            if path.as_os_str().is_empty() {
+                return Err(ConfigError::Invalid(InvalidConfigError::new(
+                    "files.capture",
+                    "must not be empty",
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_mqtt(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: &str| {
+            Err(ConfigError::Invalid(InvalidConfigError::new(key, message)))
+        };
+
+        if self.mqtt.host.as_deref() == Some("") {
+            return invalid("mqtt.host", "must not be empty");
+        }
+
+        if self.mqtt.port == 0 {
+            return invalid("mqtt.port", "must not be zero");
+        }
+
+        if self.mqtt.username.is_some() != self.mqtt.password.is_some() {
+            return invalid(
+                "mqtt.password",
+                "mqtt.username and mqtt.password must be set together",
+            );
+        }
+
+        // The client id is also used in topic names, where it must be a single level
+        if self.mqtt.client_id.is_empty()
+            || !self
+                .mqtt
+                .client_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return invalid(
+                "mqtt.client_id",
+                "must only contain letters, digits, _ and -",
+            );
+        }
+
+        for (key, prefix) in &[
+            ("mqtt.topic_prefix", &self.mqtt.topic_prefix),
+            ("mqtt.discovery_prefix", &self.mqtt.discovery_prefix),
+        ] {
+            if prefix.contains(|c| c == '+' || c == '#') || prefix.ends_with('/') {
+                return invalid(key, "must not contain wildcards or end with /");
+            }
+        }
+
+        if self.mqtt.topic_prefix.is_empty() {
+            return invalid("mqtt.topic_prefix", "must not be empty");
+        }
+
+        Ok(())
+    }
+}
+
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.trim().parse().map_err(|e: T::Err| e.to_string())
+}
+
+fn toml_value_to_string(
+    key: &str,
+    value: toml::Value,
+    source: &Source,
+) -> Result<String, ConfigError> {
+    match value {
+        toml::Value::String(s) => Ok(s),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        value => Err(ConfigError::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            source: source.clone(),
+            message: format!("unsupported {}", value.type_str()),
+        }),
+    }
+}
+
+// Parses `--key value` and `--key=value` pairs
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut overrides = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg
+            .strip_prefix("--")
+            .ok_or_else(|| ConfigError::UnexpectedArgument(arg.to_string()))?;
+
+        let (key, value) = match arg.find('=') {
+            Some(i) => (&arg[..i], arg[i + 1..].to_string()),
+            None => match args.next() {
+                Some(value) => (arg, value.to_string()),
+                None => return Err(ConfigError::MissingValue(arg.to_string())),
+            },
+        };
+
+        overrides.push((key.to_string(), value));
+    }
+
+    Ok(overrides)
+}
+
+pub fn usage() -> String {
+    let mut usage = format!(
+        "Usage: desk_controller [--config <path>] [--<setting> <value>]...\n\n\
+         Settings are read from the config file ({} by default, or ${}),\n\
+         then from environment variables, then from the command line.\n\n",
+        DEFAULT_CONFIG_PATH, CONFIG_ENV_VAR
+    );
+
+    for setting in SETTINGS {
+        usage.push_str(&format!(
+            "  --{:<32} ${:<44} {}\n",
+            setting.key, setting.env_var, setting.description
+        ));
+    }
+
+    usage
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        source: Source,
+        message: String,
+    },
+    UnknownSetting {
+        key: String,
+        source: Source,
+    },
+    InvalidValue {
+        key: String,
+        value: String,
+        source: Source,
+        message: String,
+    },
+    Invalid(InvalidConfigError),
+    UnexpectedArgument(String),
+    MissingValue(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "Failed to read config file {:?}: {}", path, source)
+            }
+            ConfigError::Parse { source, message } => {
+                write!(f, "Failed to parse {}: {}", source, message)
+            }
+            ConfigError::UnknownSetting { key, source } => {
+                write!(f, "Unknown setting in {}: {}", source, key)
+            }
+            ConfigError::InvalidValue {
+                key,
+                value,
+                source,
+                message,
+            } => write!(
+                f,
+                "Invalid value for {} in {}: {:?} - {}",
+                key, source, value, message
+            ),
+            ConfigError::Invalid(e) => write!(f, "{}", e),
+            ConfigError::UnexpectedArgument(arg) => write!(
+                f,
+                "Unexpected argument: {} - settings must be given as --<setting> <value>",
+                arg
+            ),
+            ConfigError::MissingValue(key) => write!(f, "Missing value for --{}", key),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        Config::load(&args, |name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn test_config_from_toml() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config.apply_toml(
+            r#"
+                transport = "uart"
+
+                [uart]
+                desk_path = "/dev/ttyUSB1"
+                panel_path = "/dev/ttyUSB0"
+                baud_rate = 19200
+
+                [desk]
+                min_height_cm = 70
+                max_height_cm = 120.5
+                interrupt_timeout_ms = 5000
+
+                [web]
+                port = 8080
+            "#,
+            &Source::CommandLine,
+        )?;
+        config.validate()?;
+
+        assert_eq!(
+            config.transport_kind(),
+            TransportKind::Uart(UartConfig {
+                desk_path: PathBuf::from("/dev/ttyUSB1"),
+                panel_path: PathBuf::from("/dev/ttyUSB0"),
+                baud_rate: 19200,
+                led_gpio_pin: 22,
+            })
+        );
+        assert_eq!(
+            config.controller,
+            ControllerConfig {
+                min_height_cm: 70.0,
+                max_height_cm: 120.5,
+                interrupt_timeout: Duration::from_secs(5),
+                ..ControllerConfig::default()
+            }
+        );
+        assert_eq!(config.web.port, 8080);
+        assert_eq!(config.files, FilesConfig::default());
+
+        let err = config
+            .apply_toml("[desk]\nmax_height = 100", &Source::CommandLine)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown setting in command line: desk.max_height"
+        );
+
+        let err = config
+            .apply_toml("[web]\nport = \"eighty\"", &Source::CommandLine)
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue { ref key, .. } if key == "web.port"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_example_config_is_the_default() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config.apply_toml(
+            include_str!("../desk_controller.example.toml"),
+            &Source::CommandLine,
+        )?;
+
+        assert_eq!(
+            config.transport_kind(),
+            TransportKind::Uart(UartConfig::default())
+        );
+        assert_eq!(config.controller, ControllerConfig::default());
+        assert_eq!(config.web, WebConfig::default());
+        assert_eq!(config.mqtt, MqttConfig::default());
+        assert_eq!(config.files, FilesConfig::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_overrides() -> Result<(), Box<dyn Error>> {
+        let config = load(
+            &["--web.port", "9000", "--desk.min_height_cm=72.5"],
+            &[
+                (
+                    "DESK_CONTROLLER_CONFIG",
+                    "/nonexistent/desk_controller.toml",
+                ),
+                ("DESK_CONTROLLER_PORT", "8500"),
+                ("DESK_CONTROLLER_MAX_HEIGHT_CM", "110"),
+            ],
+        );
+        // A config file that was asked for must exist
+        assert!(matches!(config, Err(ConfigError::Read { .. })));
+
+        let config = load(
+            &[
+                "--web.port",
+                "9000",
+                "--desk.min_height_cm=72.5",
+                "--transport",
+                "mock",
+            ],
+            &[
+                ("DESK_CONTROLLER_PORT", "8500"),
+                ("DESK_CONTROLLER_MAX_HEIGHT_CM", "110"),
+            ],
+        )?;
+        // The command line wins over the environment
+        assert_eq!(config.web.port, 9000);
+        assert_eq!(
+            config.controller,
+            ControllerConfig {
+                min_height_cm: 72.5,
+                max_height_cm: 110.0,
+                ..ControllerConfig::default()
+            }
+        );
+        assert_eq!(config.transport_kind(), TransportKind::Mock);
+        assert_eq!(config.files.capture, None);
+
+        let config = load(
+            &["--transport", "capture:bus.jsonl,inf"],
+            &[("DESK_CONTROLLER_CAPTURE", "replayed.jsonl")],
+        )?;
+        assert_eq!(
+            config.transport_kind(),
+            TransportKind::Capture {
+                path: PathBuf::from("bus.jsonl"),
+                speed: f64::INFINITY,
+            }
+        );
+        assert_eq!(config.files.capture, Some(PathBuf::from("replayed.jsonl")));
+
+        assert!(matches!(
+            load(&["--web.port"], &[]),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            load(&["web.port", "9000"], &[]),
+            Err(ConfigError::UnexpectedArgument(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let err = load(&["--desk.min_height_cm", "60"], &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid desk.min_height_cm: 60 is outside of the desk's range (65 to 129.5)"
+        );
+
+        let err = load(
+            &["--desk.min_height_cm", "100", "--desk.max_height_cm", "90"],
+            &[],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid desk.min_height_cm: 100 must be less than max_height_cm (90)"
+        );
+
+        let err = load(&["--web.port", "0"], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid web.port: must not be zero");
+
+        let err = load(&["--files.capture="], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid files.capture: must not be empty");
+
+        let err = load(&["--mqtt.username", "desk"], &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid mqtt.password: mqtt.username and mqtt.password must be set together"
+        );
+        let err = load(&["--mqtt.client_id", "my desk"], &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid mqtt.client_id: must only contain letters, digits, _ and -"
+        );
+
+        // The UART settings only matter when using the UART or serial transport
+        assert!(load(&["--uart.baud_rate", "0", "--transport", "mock"], &[]).is_ok());
+        let err = load(&["--uart.baud_rate", "0", "--transport", "uart"], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid uart.baud_rate: must not be zero");
+        let err = load(&["--uart.baud_rate", "0", "--transport", "serial"], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid uart.baud_rate: must not be zero");
+    }
+}
@@ -0,0 +1,2213 @@
+use crate::capture::Direction;
+use crate::events::{Event, EventBus, Subscription};
+use crate::history::{
+    History, HistoryError, Incident, IncidentKind, MovementSource, MovementTracker,
+};
+use crate::memory::{MemorySlot, PanelMemory, PanelMemoryError, PanelMemorySlots};
+use crate::metrics::{Metrics, MetricsRecorder};
+use crate::motion::{CoastProfile, CoastProfileError, VelocityEstimator};
+use crate::presets::{PresetError, Presets};
+use crate::protocol::{DataFrame, DeskToPanelMessage, PanelToDeskMessage};
+use crate::schedule::{Schedule, ScheduleAction, ScheduleError};
+use crate::stall::{StallAction, StallConfig, StallDetector};
+use crate::transport::Transport;
+use crate::{
+    validate_height_within, ChecksumPolicy, ControllerConfig, DeskError, FrameCounts, Health,
+    InvalidConfigError, InvalidHeightError, MotionState, UnknownDeskFrame, MAX_DESK_HEIGHT_CM,
+    MIN_DESK_HEIGHT_CM,
+};
+use chrono::{Datelike, Local, Timelike, Weekday};
+use crossbeam_channel::{select, unbounded};
+use log::{debug, info, warn};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+// Releasing the key when the desk is slower than this tells us little about how far it coasts
+const MIN_LEARNING_SPEED_CM_PER_S: f32 = 1.0;
+
+// Long enough for every thread to finish a blocking read (the mock transport blocks for 3 s)
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How many NoKey frames to send on shutdown, so that the desk doesn't think a key is still held
+const SHUTDOWN_NO_KEY_FRAMES: usize = 25;
+
+// How many frames to hold a ResetOne/Two/Three key for when programming a panel memory slot
+const MEMORY_STORE_FRAMES: usize = 50;
+
+// How often the scheduler checks whether a rule is due
+const SCHEDULE_TICK: Duration = Duration::from_secs(1);
+
+// How long a worker waits after a failed read or write before trying again, to avoid spinning
+const ERROR_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Where the desk was, and how fast it was moving, when we released the key
+#[derive(Clone, Copy, Debug)]
+struct Release {
+    height: f32,
+    velocity: f32,
+}
+
+// A handle to a desk controller. Cloning the handle does not create a new controller:
+// all clones share the same state, threads and transport.
+#[derive(Clone)]
+pub struct DeskController {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    transport: Arc<dyn Transport>,
+    current_height: RwLock<f32>,
+    target_height: RwLock<Option<f32>>,
+    current_panel_key: RwLock<Option<PanelToDeskMessage>>,
+    last_unknown_desk_frame: RwLock<Option<UnknownDeskFrame>>,
+    // When the frame the current panel key came from was read, until it is passed on to the desk
+    panel_key_read_at: Mutex<Option<Instant>>,
+    desk_frame_counts: RwLock<FrameCounts>,
+    panel_frame_counts: RwLock<FrameCounts>,
+    desk_checksum_policy: RwLock<ChecksumPolicy>,
+    panel_checksum_policy: RwLock<ChecksumPolicy>,
+    motion_state: RwLock<MotionState>,
+    health: RwLock<Health>,
+    config: RwLock<ControllerConfig>,
+    stall_config: RwLock<StallConfig>,
+    velocity_estimator: Mutex<VelocityEstimator>,
+    coast_profile: RwLock<CoastProfile>,
+    coast_profile_path: RwLock<Option<PathBuf>>,
+    presets: RwLock<Presets>,
+    presets_path: RwLock<Option<PathBuf>>,
+    panel_memory: Mutex<PanelMemory>,
+    panel_memory_path: RwLock<Option<PathBuf>>,
+    schedule: RwLock<Schedule>,
+    schedule_path: RwLock<Option<PathBuf>>,
+    schedule_paused_until: RwLock<Option<Instant>>,
+    history: RwLock<Option<Arc<History>>>,
+    movement_tracker: Mutex<MovementTracker>,
+    movement_source: RwLock<MovementSource>,
+    metrics: Mutex<MetricsRecorder>,
+    release: Mutex<Option<Release>>,
+    pressed_key: RwLock<Option<(PanelToDeskMessage, Instant)>>,
+    events: EventBus,
+    interrupt_tx: crossbeam_channel::Sender<()>,
+    interrupt_rx: crossbeam_channel::Receiver<()>,
+    shutdown_tx: crossbeam_channel::Sender<()>,
+    shutdown_rx: crossbeam_channel::Receiver<()>,
+}
+
+impl DeskController {
+    pub fn new(transport: Arc<dyn Transport>) -> DeskController {
+        let (interrupt_tx, interrupt_rx) = unbounded::<()>();
+        let (shutdown_tx, shutdown_rx) = unbounded::<()>();
+
+        DeskController {
+            inner: Arc::new(Inner {
+                transport,
+                current_height: RwLock::new(0.0),
+                target_height: RwLock::new(None),
+                current_panel_key: RwLock::new(None),
+                last_unknown_desk_frame: RwLock::new(None),
+                panel_key_read_at: Mutex::new(None),
+                desk_frame_counts: RwLock::new(FrameCounts::default()),
+                panel_frame_counts: RwLock::new(FrameCounts::default()),
+                desk_checksum_policy: RwLock::new(ChecksumPolicy::default()),
+                panel_checksum_policy: RwLock::new(ChecksumPolicy::default()),
+                motion_state: RwLock::new(MotionState::Idle),
+                health: RwLock::new(Health::new()),
+                config: RwLock::new(ControllerConfig::default()),
+                stall_config: RwLock::new(StallConfig::default()),
+                velocity_estimator: Mutex::new(VelocityEstimator::new()),
+                coast_profile: RwLock::new(CoastProfile::default()),
+                coast_profile_path: RwLock::new(None),
+                presets: RwLock::new(Presets::new()),
+                presets_path: RwLock::new(None),
+                panel_memory: Mutex::new(PanelMemory::default()),
+                panel_memory_path: RwLock::new(None),
+                schedule: RwLock::new(Schedule::default()),
+                schedule_path: RwLock::new(None),
+                schedule_paused_until: RwLock::new(None),
+                history: RwLock::new(None),
+                movement_tracker: Mutex::new(MovementTracker::Unknown),
+                metrics: Mutex::new(MetricsRecorder::new()),
+                movement_source: RwLock::new(MovementSource::Panel),
+                release: Mutex::new(None),
+                pressed_key: RwLock::new(None),
+                events: EventBus::new(),
+                interrupt_tx,
+                interrupt_rx,
+                shutdown_tx,
+                shutdown_rx,
+            }),
+        }
+    }
+
+    pub fn initialize(&self) -> Result<(), DeskError> {
+        self.inner.transport.initialize()
+    }
+
+    // Asks `run` to shut down. Equivalent to sending on the `ctl_rx` channel passed to `run`.
+    pub fn shutdown(&self) -> Result<(), DeskError> {
+        self.inner.shutdown_tx.send(())?;
+        Ok(())
+    }
+
+    // Runs until a shutdown is requested (via `ctl_rx` or `shutdown`), then stops every thread,
+    // leaves the desk receiving NoKey and shuts down the transport before returning.
+    pub fn run(&self, ctl_rx: crossbeam_channel::Receiver<bool>) -> Result<(), DeskError> {
+        let (c1_tx, c1_rx) = unbounded::<bool>();
+        let (c2_tx, c2_rx) = unbounded::<bool>();
+        let (c3_tx, c3_rx) = unbounded::<bool>();
+        let (c4_tx, c4_rx) = unbounded::<bool>();
+        let (c5_tx, c5_rx) = unbounded::<bool>();
+        let (c6_tx, c6_rx) = unbounded::<bool>();
+
+        let (exited_tx, exited_rx) = unbounded::<&'static str>();
+        let mut threads = Vec::new();
+
+        let controller = self.clone();
+        threads.push(self.spawn_worker("run", &exited_tx, move || {
+            let mut stall_detector = StallDetector::new();
+
+            loop {
+                // A frame takes about 7 ms to send and the desk sends one frame every 8 ms
+                // i.e. it pauses for about one ms between the end of one frame and the start of the next
+                let interrupt_timeout = controller.controller_config().interrupt_timeout;
+
+                select! {
+                    recv(c3_rx) -> msg => {
+                        debug!("Run: received val on c3_rx: {:?}. Shutting down.", msg);
+                        return
+                    },
+                    recv(controller.inner.interrupt_rx) -> _ =>{
+                        debug!("Run: received interrupt");
+                    },
+                    default(interrupt_timeout) => {
+                        debug!("Run: no interrupt received in {:?}", interrupt_timeout);
+                    }
+                };
+
+                let current_height = controller.current_height();
+                debug!("Run: current height: {:?}", current_height);
+
+                // A key pressed through the API takes precedence over the panel
+                let pressed_key = controller.pressed_key();
+                let panel_key = pressed_key.or_else(|| controller.current_panel_key());
+                let target_height = controller.target_height();
+
+                debug!(
+                    "Run: Current height: {:?}. Target height: {:?}. Panel key: {:?}",
+                    current_height, target_height, panel_key
+                );
+
+                // A programmed memory slot is stored once the desk has come to rest at its height
+                if target_height.is_none()
+                    && matches!(panel_key, None | Some(PanelToDeskMessage::NoKey))
+                    && controller.is_settled()
+                {
+                    controller.store_pending_panel_memory_slot(current_height);
+                }
+
+                let velocity = controller.velocity();
+                let maybe_message_info = calculate_panel_to_desk_message(
+                    panel_key,
+                    target_height,
+                    current_height,
+                    velocity,
+                    &controller.coast_profile(),
+                    controller.controller_config().at_target_tolerance_cm,
+                );
+
+                // Only watch for stalls while we're the ones driving the desk toward the target height
+                let driving = matches!(panel_key, None | Some(PanelToDeskMessage::NoKey))
+                    && matches!(
+                        maybe_message_info,
+                        Some((PanelToDeskMessage::Up, _, _))
+                            | Some((PanelToDeskMessage::Down, _, _))
+                    );
+
+                if !driving {
+                    stall_detector.reset();
+                    if controller.motion_state() != MotionState::Stalled {
+                        controller.set_motion_state(MotionState::Idle);
+                    }
+                } else {
+                    let config = controller.stall_config();
+                    match stall_detector.observe(&config, current_height, Instant::now()) {
+                        StallAction::None => {
+                            if stall_detector.attempts() == 0 {
+                                controller.set_motion_state(MotionState::Moving);
+                            }
+                        }
+                        StallAction::Recover {
+                            attempt,
+                            reset_desk,
+                        } => {
+                            warn!(
+                                "Desk has not moved from {:?} in {:?} - recovery attempt {:?} of {:?}",
+                                current_height, config.window, attempt, config.max_retries
+                            );
+                            controller.set_motion_state(MotionState::Recovering);
+                            controller.record_incident(
+                                IncidentKind::StallRecovery,
+                                format!("Recovery attempt {} of {}", attempt, config.max_retries),
+                            );
+                            controller.recover_from_stall(config.no_key_burst, reset_desk);
+                            controller.record_result("run", controller.interrupt());
+                            continue;
+                        }
+                        StallAction::GiveUp => {
+                            warn!(
+                                "Desk stalled at {:?} after {:?} recovery attempts - giving up on target height: {:?}",
+                                current_height, config.max_retries, target_height
+                            );
+                            stall_detector.reset();
+                            controller.set_motion_state(MotionState::Stalled);
+                            controller.record_incident(
+                                IncidentKind::Stall,
+                                format!(
+                                    "Gave up on target height {:?} after {} recovery attempts",
+                                    target_height, config.max_retries
+                                ),
+                            );
+                            controller.store_target_height(None);
+                            controller.recover_from_stall(config.no_key_burst, false);
+                            continue;
+                        }
+                    }
+                }
+
+                if let Some((message, times, reset_target_height)) = maybe_message_info {
+                    debug!("Run: writing {:?} message {:?} times.", message, times);
+
+                    if reset_target_height {
+                        controller.record_release(current_height, velocity);
+                    }
+
+                    // Panel keys are passed through unchanged unless a key pressed through the API overrides them
+                    if pressed_key.is_none() && panel_key == Some(message) {
+                        controller.record_panel_key_passed_through();
+                    }
+
+                    let written = (0..times)
+                        .try_for_each(|_| controller.inner.transport.write_to_desk(message));
+                    if controller.record_result("run", written).is_none() {
+                        // Try again shortly, rather than waiting for the next interrupt
+                        thread::sleep(ERROR_RETRY_DELAY);
+                        controller.record_result("run", controller.interrupt());
+                        continue;
+                    }
+
+                    if reset_target_height {
+                        info!("At target height of: {:?}.", target_height);
+
+                        debug!("Run: resetting target height to None");
+                        controller.set_target_height(None);
+                    } else if target_height.is_some() {
+                        debug!("Run: not yet at target_height - sending interrupt");
+                        controller.record_result("run", controller.interrupt());
+                    } else if pressed_key.is_some() {
+                        debug!("Run: key still pressed - sending interrupt");
+                        controller.record_result("run", controller.interrupt());
+                    }
+                }
+            }
+        }));
+
+        // Each message is sent with when its frame was read, to measure how long it takes to pass through
+        let (write_to_panel_tx, write_to_panel_rx) = unbounded::<(DeskToPanelMessage, Instant)>();
+
+        let controller = self.clone();
+        threads.push(self.spawn_worker("desk reader", &exited_tx, move || loop {
+            select! {
+            recv(c1_rx) -> _=> {
+                debug!("Received shutdown signal - exiting run (desk->panel) loop");
+                return
+            },
+            default => {
+                let (maybe_frame,dropped_byte_count) = controller
+                    .record_result("desk reader", controller.inner.transport.read_desk())
+                    .unwrap_or_else(|| {
+                        thread::sleep(ERROR_RETRY_DELAY);
+                        (None, 0)
+                    });
+                let read_at = Instant::now();
+
+                controller.inner.desk_frame_counts.write().unwrap().dropped_bytes += dropped_byte_count;
+
+                if let Some(message) = maybe_frame.and_then(|frame| controller.decode_desk_frame(&frame)) {
+                    controller.inner.desk_frame_counts.write().unwrap().found_frames += 1;
+
+                    match message {
+                        DeskToPanelMessage::Height(h) => {
+                            controller.set_current_height(h);
+
+                            if !(MIN_DESK_HEIGHT_CM..=MAX_DESK_HEIGHT_CM).contains(&h){
+                                debug!(
+                                    "received abnormal height from desk: {:?} - {:?}",
+                                    h,
+                                    message.as_frame()
+                                );
+                            }
+                        }
+                        DeskToPanelMessage::Unknown(..) => {
+                            debug!(
+                                "received other desk-to-panel message: {:?} - {:?}",
+                                message,
+                                message.as_frame()
+                            );
+                            controller.set_last_unknown_desk_frame(message);
+                        }
+                    }
+
+                    if write_to_panel_tx.send((message, read_at)).is_err() {
+                        debug!("Panel writer has exited - exiting run (desk->panel) loop");
+                        return
+                    }
+                }
+            },
+            }
+        }));
+
+        let controller = self.clone();
+        threads.push(self.spawn_worker("panel writer", &exited_tx, move || loop {
+            select! {
+                recv(c4_rx) -> _=> {
+                    debug!("Received shutdown signal (c4_rx) - exiting run (desk->panel) loop");
+                    return
+                },
+                recv(write_to_panel_rx) -> msg =>{
+                    let (message, read_at) = match msg {
+                        Ok(message) => message,
+                        Err(_) => {
+                            debug!("Desk reader has exited - exiting panel writer loop");
+                            return
+                        }
+                    };
+                    controller.inner.metrics.lock().unwrap().record_pass_through(Direction::DeskToPanel, read_at.elapsed());
+                    controller.record_result("panel writer", controller.inner.transport.write_to_panel(message));
+                },
+            }
+        }));
+
+        let (panel_to_desk_tx, panel_to_desk_rx) =
+            unbounded::<(Option<DataFrame>, usize, Instant)>();
+
+        // Keep this as a separate loop so that we can have a default timeout in the recv select loop
+        let controller = self.clone();
+        threads.push(self.spawn_worker("panel reader", &exited_tx, move || loop {
+            select! {
+                recv(c5_rx) -> _ => {
+                    debug!("Received shutdown signal - exiting panel reader loop");
+                    return
+                },
+                default => {
+                    let (maybe_frame, dropped_byte_count) = controller
+                        .record_result("panel reader", controller.inner.transport.read_panel())
+                        .unwrap_or_else(|| {
+                            thread::sleep(ERROR_RETRY_DELAY);
+                            (None, 0)
+                        });
+                    if panel_to_desk_tx.send((maybe_frame, dropped_byte_count, Instant::now())).is_err() {
+                        debug!("Panel->desk loop has exited - exiting panel reader loop");
+                        return
+                    }
+                },
+            }
+        }));
+
+        let controller = self.clone();
+        threads.push(self.spawn_worker("panel->desk", &exited_tx, move || loop {
+            let panel_key_reset_timeout = controller.controller_config().panel_key_reset_timeout;
+            select! {
+                recv(c2_rx) -> _=> {
+                    debug!("Received shutdown signal - exiting run (panel->desk) loop");
+                    return;
+                },
+                recv(panel_to_desk_rx) -> msg => {
+                    let (maybe_frame,dropped_byte_count,read_at) = match msg {
+                        Ok(result) => result,
+                        Err(_) => {
+                            debug!("Panel reader has exited - exiting run (panel->desk) loop");
+                            return;
+                        }
+                    };
+
+                    controller.inner.panel_frame_counts.write().unwrap().dropped_bytes += dropped_byte_count;
+
+                    let maybe_message = maybe_frame.and_then(|frame| controller.decode_panel_frame(&frame));
+                    if let Some(message) = maybe_message {
+                        controller.inner.panel_frame_counts.write().unwrap().found_frames += 1;
+
+                        match message{
+                            PanelToDeskMessage::NoKey => {}
+                            _ => {
+                                debug!(
+                                    "panel-to-desk message: {:?} - {:?}",
+                                    message,
+                                    message.as_frame()
+                                );
+                            },
+                        }
+
+                        // Someone is at the desk, so the schedule shouldn't move it for a while
+                        if !matches!(message, PanelToDeskMessage::NoKey | PanelToDeskMessage::Unknown(..)) {
+                            controller.pause_schedule();
+                            controller.set_movement_source(MovementSource::Panel);
+                        }
+
+                        let message = controller.observe_panel_memory(message);
+                        *controller.inner.panel_key_read_at.lock().unwrap() = Some(read_at);
+                        controller.set_current_panel_key(Some(message));
+                    }
+                },
+                default(panel_key_reset_timeout) => {
+                    let current_panel_key = controller.current_panel_key();
+                    if current_panel_key.is_some(){
+                        debug!("No panel key received in {:?} - resetting to None (from: {:?})",panel_key_reset_timeout,current_panel_key);
+                        controller.set_current_panel_key(None);
+                    }
+                },
+            }
+        }));
+
+        let controller = self.clone();
+        threads.push(self.spawn_worker("scheduler", &exited_tx, move || {
+            let mut last_minute = None;
+
+            loop {
+                select! {
+                    recv(c6_rx) -> _ => {
+                        debug!("Received shutdown signal - exiting scheduler loop");
+                        return
+                    },
+                    default(SCHEDULE_TICK) => {
+                        let now = Local::now();
+                        let minute = (now.num_days_from_ce(), now.hour() * 60 + now.minute());
+
+                        // Each minute is only checked once
+                        if last_minute != Some(minute) {
+                            last_minute = Some(minute);
+                            controller.run_schedule(now.weekday(), minute.1);
+                        }
+                    },
+                }
+            }
+        }));
+
+        let x = select! {
+            recv(ctl_rx) -> msg => msg?,
+            recv(self.inner.shutdown_rx) -> msg => {
+                msg?;
+                true
+            },
+        };
+        info!("Shutting down");
+
+        // A worker that has already exited doesn't need telling
+        for tx in &[c1_tx, c2_tx, c3_tx, c4_tx, c5_tx, c6_tx] {
+            let _ = tx.send(x);
+        }
+
+        let still_running = join_workers(threads, &exited_rx, SHUTDOWN_TIMEOUT);
+
+        // Nothing else writes to the desk now, so this is the last thing it hears from us
+        self.store_target_height(None);
+        self.set_motion_state(MotionState::Idle);
+        for _ in 0..SHUTDOWN_NO_KEY_FRAMES {
+            self.inner
+                .transport
+                .write_to_desk(PanelToDeskMessage::NoKey)?;
+        }
+
+        self.inner.transport.shutdown()?;
+
+        // The desk's height isn't known while the controller isn't running
+        self.with_history(|history| history.record_height(SystemTime::now(), None));
+
+        if !still_running.is_empty() {
+            return Err(DeskError::Timeout {
+                waiting_for: format!("threads to exit: {}", still_running.join(", ")),
+                after: SHUTDOWN_TIMEOUT,
+            });
+        }
+
+        info!("Shutdown complete");
+        Ok(())
+    }
+
+    pub fn move_to_height(&self, height_in_cm: f32) -> Result<(), DeskError> {
+        Ok(self.move_to_height_from(height_in_cm, MovementSource::Api)?)
+    }
+
+    pub(crate) fn move_to_height_from(
+        &self,
+        height_in_cm: f32,
+        source: MovementSource,
+    ) -> Result<(), InvalidHeightError> {
+        info!("Moving to height: {:?}", height_in_cm);
+
+        let config = self.controller_config();
+        validate_height_within(height_in_cm, config.min_height_cm, config.max_height_cm)?;
+        self.set_movement_source(source);
+
+        // Anything the desk does from now on isn't coasting
+        *self.inner.release.lock().unwrap() = None;
+
+        self.set_motion_state(MotionState::Idle);
+        self.set_target_height(Some(height_in_cm));
+
+        Ok(())
+    }
+
+    pub fn move_to_preset(&self, name: &str) -> Result<(), PresetError> {
+        self.move_to_preset_from(name, MovementSource::Api)
+    }
+
+    fn move_to_preset_from(&self, name: &str, source: MovementSource) -> Result<(), PresetError> {
+        let height_in_cm = self
+            .preset(name)
+            .ok_or_else(|| PresetError::NotFound(name.to_string()))?;
+
+        info!("Moving to preset: {}", name);
+        self.move_to_height_from(height_in_cm, source)
+            .map_err(PresetError::InvalidHeight)
+    }
+
+    pub fn presets(&self) -> Presets {
+        self.inner.presets.read().unwrap().clone()
+    }
+
+    pub fn preset(&self, name: &str) -> Option<f32> {
+        self.inner.presets.read().unwrap().get(name)
+    }
+
+    // Adds a preset, or replaces the height of an existing one
+    pub fn save_preset(&self, name: &str, height_in_cm: f32) -> Result<(), PresetError> {
+        info!("Saving preset: {} - {:?} cm", name, height_in_cm);
+
+        let config = self.controller_config();
+        validate_height_within(height_in_cm, config.min_height_cm, config.max_height_cm)
+            .map_err(PresetError::InvalidHeight)?;
+
+        let mut presets = self.inner.presets.write().unwrap();
+        let mut updated = presets.clone();
+        updated.insert(name, height_in_cm)?;
+
+        self.store_presets(&updated)?;
+        *presets = updated;
+
+        Ok(())
+    }
+
+    // Returns the height of the deleted preset
+    pub fn delete_preset(&self, name: &str) -> Result<f32, PresetError> {
+        info!("Deleting preset: {}", name);
+
+        let mut presets = self.inner.presets.write().unwrap();
+        let mut updated = presets.clone();
+        let height_in_cm = updated.remove(name)?;
+
+        self.store_presets(&updated)?;
+        *presets = updated;
+
+        Ok(height_in_cm)
+    }
+
+    // Loads presets from `path` (if it exists) and saves them there whenever they change
+    pub fn set_presets_path<P: AsRef<Path>>(&self, path: P) -> Result<(), PresetError> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            let presets = Presets::load(path)?;
+            let config = self.controller_config();
+            presets.validate_heights(config.min_height_cm, config.max_height_cm)?;
+            info!("Loaded {} presets from {:?}", presets.len(), path);
+            *self.inner.presets.write().unwrap() = presets;
+        }
+
+        *self.inner.presets_path.write().unwrap() = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    fn store_presets(&self, presets: &Presets) -> Result<(), PresetError> {
+        match self.inner.presets_path.read().unwrap().as_ref() {
+            Some(path) => presets.save(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn panel_memory_slots(&self) -> PanelMemorySlots {
+        self.inner.panel_memory.lock().unwrap().slots()
+    }
+
+    // Moves to `height_in_cm` and stores it in the panel's memory `slot`.
+    // Until the slot is stored on the panel again, recalling it from the panel moves to this height.
+    pub fn program_panel_memory_slot(
+        &self,
+        slot: MemorySlot,
+        height_in_cm: f32,
+    ) -> Result<(), PanelMemoryError> {
+        info!(
+            "Programming panel memory slot: {:?} - {:?} cm",
+            slot, height_in_cm
+        );
+
+        let config = self.controller_config();
+        validate_height_within(height_in_cm, config.min_height_cm, config.max_height_cm)
+            .map_err(PanelMemoryError::InvalidHeight)?;
+
+        let mut programmed = self.inner.panel_memory.lock().unwrap().programmed();
+        programmed.set(slot, Some(height_in_cm));
+        self.store_programmed_panel_memory(&programmed)?;
+
+        self.move_to_height_from(height_in_cm, MovementSource::Api)
+            .map_err(PanelMemoryError::InvalidHeight)?;
+        self.inner
+            .panel_memory
+            .lock()
+            .unwrap()
+            .program(slot, height_in_cm);
+
+        Ok(())
+    }
+
+    // Loads the programmed slots from `path` (if it exists) and saves them there whenever they change
+    pub fn set_panel_memory_path<P: AsRef<Path>>(&self, path: P) -> Result<(), PanelMemoryError> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            let config = self.controller_config();
+            let programmed =
+                PanelMemorySlots::load(path, config.min_height_cm, config.max_height_cm)?;
+            info!(
+                "Loaded programmed panel memory slots from {:?}: {:?}",
+                path, programmed
+            );
+            self.inner
+                .panel_memory
+                .lock()
+                .unwrap()
+                .restore_programmed(programmed);
+        }
+
+        *self.inner.panel_memory_path.write().unwrap() = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    fn store_programmed_panel_memory(
+        &self,
+        programmed: &PanelMemorySlots,
+    ) -> Result<(), PanelMemoryError> {
+        match self.inner.panel_memory_path.read().unwrap().as_ref() {
+            Some(path) => programmed.save(path),
+            None => Ok(()),
+        }
+    }
+
+    // Learns the panel's memory slots from what it sends, and rewrites recalls of programmed slots
+    fn observe_panel_memory(&self, message: PanelToDeskMessage) -> PanelToDeskMessage {
+        let current_height = self.current_height();
+        let mut panel_memory = self.inner.panel_memory.lock().unwrap();
+        let programmed = panel_memory.programmed();
+        let forwarded = panel_memory.observe(message, current_height);
+
+        // Storing a slot on the panel replaces what was programmed
+        if panel_memory.programmed() != programmed {
+            if let Err(e) = self.store_programmed_panel_memory(&panel_memory.programmed()) {
+                warn!("Failed to save programmed panel memory slots: {}", e);
+            }
+        }
+        drop(panel_memory);
+
+        if forwarded != message {
+            debug!(
+                "Rewrote panel memory recall: {:?} -> {:?}",
+                message, forwarded
+            );
+        }
+
+        forwarded
+    }
+
+    // Holds the store key for a programmed slot, saving the desk's current height in it
+    fn store_pending_panel_memory_slot(&self, current_height: f32) {
+        let (slot, height_in_cm) =
+            match self.inner.panel_memory.lock().unwrap().take_pending_store() {
+                Some(pending) => pending,
+                None => return,
+            };
+
+        if (current_height - height_in_cm).abs() > self.controller_config().at_target_tolerance_cm {
+            warn!(
+                "Desk came to rest at {:?} rather than {:?} - not storing panel memory slot: {:?}",
+                current_height, height_in_cm, slot
+            );
+            return;
+        }
+
+        info!(
+            "Storing {:?} cm in panel memory slot: {:?}",
+            current_height, slot
+        );
+
+        let written = (0..MEMORY_STORE_FRAMES)
+            .try_for_each(|_| self.inner.transport.write_to_desk(slot.store_message()));
+        self.record_result("run", written);
+    }
+
+    pub fn schedule(&self) -> Schedule {
+        self.inner.schedule.read().unwrap().clone()
+    }
+
+    pub fn set_schedule(&self, schedule: Schedule) -> Result<(), ScheduleError> {
+        info!("Setting schedule: {:?}", schedule);
+
+        let config = self.controller_config();
+        schedule.validate_heights(config.min_height_cm, config.max_height_cm)?;
+
+        let mut current = self.inner.schedule.write().unwrap();
+        if let Some(path) = self.inner.schedule_path.read().unwrap().as_ref() {
+            schedule.save(path)?;
+        }
+        *current = schedule;
+
+        Ok(())
+    }
+
+    // Loads the schedule from `path` (if it exists) and saves it there whenever it changes
+    pub fn set_schedule_path<P: AsRef<Path>>(&self, path: P) -> Result<(), ScheduleError> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            let schedule = Schedule::load(path)?;
+            let config = self.controller_config();
+            schedule.validate_heights(config.min_height_cm, config.max_height_cm)?;
+            info!(
+                "Loaded {} schedule rules from {:?}",
+                schedule.rules().len(),
+                path
+            );
+            *self.inner.schedule.write().unwrap() = schedule;
+        }
+
+        *self.inner.schedule_path.write().unwrap() = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    // How much longer the schedule is paused for, after a key was pressed on the panel
+    pub fn schedule_paused_for(&self) -> Option<Duration> {
+        let paused_until = (*self.inner.schedule_paused_until.read().unwrap())?;
+        let now = Instant::now();
+
+        if paused_until > now {
+            Some(paused_until - now)
+        } else {
+            None
+        }
+    }
+
+    pub fn resume_schedule(&self) {
+        info!("Resuming schedule");
+        *self.inner.schedule_paused_until.write().unwrap() = None;
+    }
+
+    fn pause_schedule(&self) {
+        let cooldown = self.inner.schedule.read().unwrap().cooldown();
+        match Instant::now().checked_add(cooldown) {
+            Some(paused_until) => {
+                *self.inner.schedule_paused_until.write().unwrap() = Some(paused_until)
+            }
+            None => warn!("Schedule cooldown is too long to pause for: {:?}", cooldown),
+        }
+    }
+
+    // Carries out the schedule's action for this minute, if any
+    fn run_schedule(&self, weekday: Weekday, minute_of_day: u32) {
+        let action = match self.schedule().action_at(weekday, minute_of_day) {
+            Some(action) => action.clone(),
+            None => return,
+        };
+
+        if let Some(paused_for) = self.schedule_paused_for() {
+            info!(
+                "Schedule paused for another {:?} - skipping scheduled move to: {}",
+                paused_for, action
+            );
+            return;
+        }
+
+        info!("Scheduled move to: {}", action);
+        let result = match &action {
+            ScheduleAction::MoveToHeight(h) => self
+                .move_to_height_from(*h, MovementSource::Schedule)
+                .map_err(|e| e.to_string()),
+            ScheduleAction::MoveToPreset(name) => self
+                .move_to_preset_from(name, MovementSource::Schedule)
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to carry out scheduled action: {} - {}", action, e);
+            self.record_incident(
+                IncidentKind::Error,
+                format!("Failed to carry out scheduled move to {}: {}", action, e),
+            );
+        }
+    }
+
+    pub fn clear_target_height(&self) {
+        info!("Clearing target height");
+        self.set_motion_state(MotionState::Idle);
+        self.set_target_height(None);
+    }
+
+    pub fn current_height(&self) -> f32 {
+        *self.inner.current_height.read().unwrap()
+    }
+
+    fn set_current_height(&self, h: f32) {
+        let previous = mem::replace(&mut *self.inner.current_height.write().unwrap(), h);
+        if previous.to_bits() != h.to_bits() {
+            self.inner.events.publish(Event::Height(h));
+        }
+
+        let now = Instant::now();
+        self.inner.metrics.lock().unwrap().observe_height(h, now);
+
+        let settled = {
+            let mut velocity_estimator = self.inner.velocity_estimator.lock().unwrap();
+            velocity_estimator.observe(h, now);
+            velocity_estimator.is_settled()
+        };
+
+        self.track_movement(h, settled);
+
+        if settled {
+            if let Some(release) = self.inner.release.lock().unwrap().take() {
+                self.learn_coast(release, h);
+            }
+
+            // Let the run loop store a programmed memory slot
+            if self.inner.panel_memory.lock().unwrap().has_pending_store() {
+                self.wake_run_loop();
+            }
+        }
+    }
+
+    // Records the height whenever the desk comes to rest, and the movement that brought it there
+    fn track_movement(&self, height: f32, settled: bool) {
+        let now = SystemTime::now();
+        let source = *self.inner.movement_source.read().unwrap();
+
+        let observation = self
+            .inner
+            .movement_tracker
+            .lock()
+            .unwrap()
+            .observe(height, settled, now, source);
+
+        if let Some((settled_height, movement)) = observation {
+            if let Some(movement) = &movement {
+                self.inner.metrics.lock().unwrap().record_movement(movement);
+            }
+
+            self.with_history(|history| {
+                // Time spent moving counts as neither sitting nor standing
+                if let Some(movement) = movement {
+                    history.record_movement(&movement)?;
+                    history.record_height(movement.started_at, None)?;
+                }
+                history.record_height(now, Some(settled_height))
+            });
+        }
+    }
+
+    fn set_movement_source(&self, source: MovementSource) {
+        *self.inner.movement_source.write().unwrap() = source;
+    }
+
+    fn record_incident(&self, kind: IncidentKind, message: String) {
+        self.inner.metrics.lock().unwrap().record_incident(kind);
+
+        let incident = Incident {
+            at: SystemTime::now(),
+            kind,
+            height: self.current_height(),
+            message,
+        };
+        self.with_history(|history| history.record_incident(&incident));
+    }
+
+    // Recording history is best effort: the desk keeps working without it
+    fn with_history<F>(&self, f: F)
+    where
+        F: FnOnce(&History) -> Result<(), HistoryError>,
+    {
+        if let Some(history) = self.history() {
+            if let Err(e) = f(&history) {
+                warn!("Failed to record history: {}", e);
+            }
+        }
+    }
+
+    // Counters kept since the controller was created, whether or not history is being recorded
+    pub fn metrics(&self) -> Metrics {
+        self.inner.metrics.lock().unwrap().metrics()
+    }
+
+    // Each panel frame is only counted the first time its key is written to the desk
+    fn record_panel_key_passed_through(&self) {
+        if let Some(read_at) = self.inner.panel_key_read_at.lock().unwrap().take() {
+            self.inner
+                .metrics
+                .lock()
+                .unwrap()
+                .record_pass_through(Direction::PanelToDesk, read_at.elapsed());
+        }
+    }
+
+    pub fn history(&self) -> Option<Arc<History>> {
+        self.inner.history.read().unwrap().clone()
+    }
+
+    // Opens (or creates) the history database at `path` and starts recording to it
+    pub fn set_history_path<P: AsRef<Path>>(&self, path: P) -> Result<(), HistoryError> {
+        let path = path.as_ref();
+        let history = History::open(path)?;
+        info!("Recording history to {:?}", path);
+
+        *self.inner.history.write().unwrap() = Some(Arc::new(history));
+
+        Ok(())
+    }
+
+    fn is_settled(&self) -> bool {
+        self.inner.velocity_estimator.lock().unwrap().is_settled()
+    }
+
+    // Velocity in cm/s, estimated from recent height readings. Positive is up.
+    pub fn velocity(&self) -> f32 {
+        self.inner.velocity_estimator.lock().unwrap().velocity()
+    }
+
+    pub fn coast_profile(&self) -> CoastProfile {
+        *self.inner.coast_profile.read().unwrap()
+    }
+
+    // Loads the learned coast profile from `path` (if it exists) and saves it there whenever it changes
+    pub fn set_coast_profile_path<P: AsRef<Path>>(&self, path: P) -> Result<(), CoastProfileError> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            let profile = CoastProfile::load(path)?;
+            info!("Loaded coast profile from {:?}: {:?}", path, profile);
+            *self.inner.coast_profile.write().unwrap() = profile;
+        }
+
+        *self.inner.coast_profile_path.write().unwrap() = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    fn record_release(&self, height: f32, velocity: f32) {
+        let release = if velocity.abs() >= MIN_LEARNING_SPEED_CM_PER_S {
+            debug!(
+                "Released key at {:?} cm moving at {:?} cm/s",
+                height, velocity
+            );
+            Some(Release { height, velocity })
+        } else {
+            None
+        };
+
+        *self.inner.release.lock().unwrap() = release;
+    }
+
+    fn learn_coast(&self, release: Release, settled_height: f32) {
+        let distance = (settled_height - release.height) * release.velocity.signum();
+
+        let profile = {
+            let mut profile = self.inner.coast_profile.write().unwrap();
+            profile.learn(release.velocity, distance);
+            *profile
+        };
+        info!(
+            "Desk coasted {:?} cm after release at {:?} cm/s - coast profile is now: {:?}",
+            distance, release.velocity, profile
+        );
+
+        if let Some(path) = self.inner.coast_profile_path.read().unwrap().as_ref() {
+            if let Err(e) = profile.save(path) {
+                warn!("Failed to save coast profile to {:?}: {}", path, e);
+                self.record_incident(
+                    IncidentKind::Error,
+                    format!("Failed to save coast profile to {:?}: {}", path, e),
+                );
+            }
+        }
+    }
+
+    pub fn target_height(&self) -> Option<f32> {
+        *self.inner.target_height.read().unwrap()
+    }
+
+    fn set_target_height(&self, h: Option<f32>) {
+        self.store_target_height(h);
+        self.wake_run_loop();
+    }
+
+    // Sets the target height without waking the run loop
+    fn store_target_height(&self, h: Option<f32>) {
+        let previous = mem::replace(&mut *self.inner.target_height.write().unwrap(), h);
+        if previous != h {
+            self.inner.events.publish(Event::TargetHeight(h));
+        }
+    }
+
+    pub fn current_panel_key(&self) -> Option<PanelToDeskMessage> {
+        *self.inner.current_panel_key.read().unwrap()
+    }
+
+    fn set_current_panel_key(&self, key: Option<PanelToDeskMessage>) {
+        if !matches!(key, None | Some(PanelToDeskMessage::NoKey)) {
+            *self.inner.release.lock().unwrap() = None;
+        }
+
+        let previous = mem::replace(&mut *self.inner.current_panel_key.write().unwrap(), key);
+        if previous != key {
+            self.inner.events.publish(Event::PanelKey(key));
+        }
+
+        self.wake_run_loop();
+    }
+
+    pub fn last_unknown_desk_frame(&self) -> Option<UnknownDeskFrame> {
+        *self.inner.last_unknown_desk_frame.read().unwrap()
+    }
+
+    // The desk repeats a frame for as long as the panel should show it, so only changes are published
+    fn set_last_unknown_desk_frame(&self, message: DeskToPanelMessage) {
+        let frame = UnknownDeskFrame {
+            message,
+            received_at: SystemTime::now(),
+        };
+
+        let previous = self
+            .inner
+            .last_unknown_desk_frame
+            .write()
+            .unwrap()
+            .replace(frame);
+        if previous.map(|previous| previous.message) != Some(message) {
+            self.inner.events.publish(Event::DeskFrame(message));
+        }
+    }
+
+    // Holds `key` down for `duration`, as if it were pressed on the panel
+    pub fn press_key(&self, key: PanelToDeskMessage, duration: Duration) -> Result<(), DeskError> {
+        info!("Pressing key: {:?} for {:?}", key, duration);
+
+        // The desk moves to whatever height is sent with a memory key
+        if let PanelToDeskMessage::One(h)
+        | PanelToDeskMessage::Two(h)
+        | PanelToDeskMessage::Three(h) = key
+        {
+            let config = self.controller_config();
+            validate_height_within(h, config.min_height_cm, config.max_height_cm)?;
+        }
+
+        *self.inner.release.lock().unwrap() = None;
+        self.set_movement_source(MovementSource::Api);
+        *self.inner.pressed_key.write().unwrap() = Some((key, Instant::now() + duration));
+        self.interrupt()
+    }
+
+    pub fn pressed_key(&self) -> Option<PanelToDeskMessage> {
+        match *self.inner.pressed_key.read().unwrap() {
+            Some((key, until)) if Instant::now() < until => Some(key),
+            _ => None,
+        }
+    }
+
+    pub fn desk_frame_counts(&self) -> FrameCounts {
+        *self.inner.desk_frame_counts.read().unwrap()
+    }
+
+    pub fn panel_frame_counts(&self) -> FrameCounts {
+        *self.inner.panel_frame_counts.read().unwrap()
+    }
+
+    pub fn desk_checksum_policy(&self) -> ChecksumPolicy {
+        *self.inner.desk_checksum_policy.read().unwrap()
+    }
+
+    pub fn set_desk_checksum_policy(&self, policy: ChecksumPolicy) {
+        info!("Setting desk checksum policy: {:?}", policy);
+        *self.inner.desk_checksum_policy.write().unwrap() = policy;
+    }
+
+    pub fn panel_checksum_policy(&self) -> ChecksumPolicy {
+        *self.inner.panel_checksum_policy.read().unwrap()
+    }
+
+    pub fn set_panel_checksum_policy(&self, policy: ChecksumPolicy) {
+        info!("Setting panel checksum policy: {:?}", policy);
+        *self.inner.panel_checksum_policy.write().unwrap() = policy;
+    }
+
+    pub fn motion_state(&self) -> MotionState {
+        *self.inner.motion_state.read().unwrap()
+    }
+
+    fn set_motion_state(&self, state: MotionState) {
+        let mut motion_state = self.inner.motion_state.write().unwrap();
+        if *motion_state != state {
+            debug!("Motion state: {:?} -> {:?}", *motion_state, state);
+            *motion_state = state;
+            self.inner.events.publish(Event::MotionState(state));
+        }
+    }
+
+    // Subscribes to changes in height, target height, panel key, motion state and health.
+    // Changes are delivered in batches at most once every `min_interval`.
+    pub fn subscribe(&self, min_interval: Duration) -> Subscription {
+        self.inner.events.subscribe(min_interval)
+    }
+
+    pub fn controller_config(&self) -> ControllerConfig {
+        *self.inner.config.read().unwrap()
+    }
+
+    pub fn set_controller_config(
+        &self,
+        config: ControllerConfig,
+    ) -> Result<(), InvalidConfigError> {
+        config.validate()?;
+
+        info!("Setting controller config: {:?}", config);
+        *self.inner.config.write().unwrap() = config;
+
+        Ok(())
+    }
+
+    pub fn stall_config(&self) -> StallConfig {
+        *self.inner.stall_config.read().unwrap()
+    }
+
+    pub fn set_stall_config(&self, config: StallConfig) {
+        info!("Setting stall config: {:?}", config);
+        *self.inner.stall_config.write().unwrap() = config;
+    }
+
+    // Releases whatever key the desk thinks is held, then (optionally) resets it
+    fn recover_from_stall(&self, no_key_burst: usize, reset_desk: bool) {
+        let mut written = (0..no_key_burst).try_for_each(|_| {
+            self.inner
+                .transport
+                .write_to_desk(PanelToDeskMessage::NoKey)
+        });
+
+        if reset_desk && written.is_ok() {
+            written = self
+                .inner
+                .transport
+                .write_to_desk(PanelToDeskMessage::DeskReset);
+        }
+
+        self.record_result("run", written);
+    }
+
+    // Wakes the run loop so that it reacts to a change in state.
+    // Only fails if the run loop's end of the channel has gone.
+    fn interrupt(&self) -> Result<(), DeskError> {
+        self.inner.interrupt_tx.send(())?;
+        Ok(())
+    }
+
+    // Wakes the run loop from code that can't return the error
+    fn wake_run_loop(&self) {
+        if let Err(e) = self.interrupt() {
+            warn!("Failed to wake the run loop: {}", e);
+        }
+    }
+
+    fn spawn_worker<F>(
+        &self,
+        name: &'static str,
+        exited_tx: &crossbeam_channel::Sender<&'static str>,
+        f: F,
+    ) -> (&'static str, JoinHandle<()>)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let notifier = ExitNotifier {
+            name,
+            controller: self.clone(),
+            exited_tx: exited_tx.clone(),
+        };
+
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let _notifier = notifier;
+                f()
+            })
+            .expect("failed to spawn thread");
+
+        (name, handle)
+    }
+
+    pub fn health(&self) -> Health {
+        self.inner.health.read().unwrap().clone()
+    }
+
+    // Returns the value of a read or write by `worker`, or None (having recorded the error) if it failed.
+    // The worker is degraded from its first failure until it next succeeds.
+    fn record_result<T>(&self, worker: &'static str, result: Result<T, DeskError>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                // Checked first so that the usual case only needs the read lock
+                if self.inner.health.read().unwrap().failing.contains(&worker) {
+                    info!("{} recovered", worker);
+                    self.update_health(|health| health.failing.retain(|w| *w != worker));
+                }
+                Some(value)
+            }
+            Err(e) => {
+                let message = format!("{}: {}", worker, e);
+                let first_failure = !self.inner.health.read().unwrap().failing.contains(&worker);
+                if first_failure {
+                    warn!("{}", message);
+                    self.record_incident(IncidentKind::Error, message.clone());
+                } else {
+                    debug!("{}", message);
+                }
+
+                self.update_health(|health| {
+                    health.error_count += 1;
+                    health.last_error = Some(message);
+                    if first_failure {
+                        health.failing.push(worker);
+                    }
+                });
+                None
+            }
+        }
+    }
+
+    fn record_worker_stopped(&self, worker: &'static str) {
+        let message = format!("{} stopped unexpectedly", worker);
+        warn!("{}", message);
+
+        self.update_health(|health| {
+            health.last_error = Some(message.clone());
+            health.stopped.push(worker);
+        });
+        self.record_incident(IncidentKind::Error, message);
+    }
+
+    fn update_health<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Health),
+    {
+        // A worker that panicked while holding the lock mustn't stop the others reporting their health
+        let mut health = match self.inner.health.write() {
+            Ok(health) => health,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let previous = health.status;
+        f(&mut health);
+        health.update_status();
+        let status = health.status;
+        drop(health);
+
+        if status != previous {
+            info!("Health: {:?} -> {:?}", previous, status);
+            self.inner.events.publish(Event::Health(status));
+        }
+    }
+
+    fn decode_desk_frame(&self, frame: &[u8]) -> Option<DeskToPanelMessage> {
+        match DeskToPanelMessage::from_frame(frame).map_err(DeskError::from) {
+            Ok(message) => Some(message),
+            Err(e @ DeskError::Checksum { .. }) => {
+                self.inner
+                    .desk_frame_counts
+                    .write()
+                    .unwrap()
+                    .checksum_failures += 1;
+
+                let policy = self.desk_checksum_policy();
+                debug!(
+                    "{} from desk: {:?} - applying {:?} policy",
+                    e, frame, policy
+                );
+                DeskToPanelMessage::recover_frame(frame, policy)
+            }
+            Err(e) => {
+                debug!("{} from desk: {:?}", e, frame);
+                self.inner.desk_frame_counts.write().unwrap().dropped_bytes += frame.len();
+                None
+            }
+        }
+    }
+
+    fn decode_panel_frame(&self, frame: &[u8]) -> Option<PanelToDeskMessage> {
+        match PanelToDeskMessage::from_frame(frame).map_err(DeskError::from) {
+            Ok(message) => Some(message),
+            Err(e @ DeskError::Checksum { .. }) => {
+                self.inner
+                    .panel_frame_counts
+                    .write()
+                    .unwrap()
+                    .checksum_failures += 1;
+
+                let policy = self.panel_checksum_policy();
+                debug!(
+                    "{} from panel: {:?} - applying {:?} policy",
+                    e, frame, policy
+                );
+                PanelToDeskMessage::recover_frame(frame, policy)
+            }
+            Err(e) => {
+                debug!("{} from panel: {:?}", e, frame);
+                self.inner.panel_frame_counts.write().unwrap().dropped_bytes += frame.len();
+                None
+            }
+        }
+    }
+}
+
+// Notifies the thread waiting on shutdown when a worker thread exits, even if it panics.
+// A worker that panics is reported in the controller's health, as it won't be doing its job.
+struct ExitNotifier {
+    name: &'static str,
+    controller: DeskController,
+    exited_tx: crossbeam_channel::Sender<&'static str>,
+}
+
+impl Drop for ExitNotifier {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.controller.record_worker_stopped(self.name);
+        }
+
+        let _ = self.exited_tx.send(self.name);
+    }
+}
+
+// Waits up to `timeout` for every worker to exit. Returns the names of the workers that are still running.
+fn join_workers(
+    workers: Vec<(&'static str, JoinHandle<()>)>,
+    exited_rx: &crossbeam_channel::Receiver<&'static str>,
+    timeout: Duration,
+) -> Vec<&'static str> {
+    let deadline = Instant::now() + timeout;
+    let mut running = workers;
+    let mut still_running = Vec::new();
+
+    while !running.is_empty() {
+        let name = match exited_rx.recv_deadline(deadline) {
+            Ok(name) => name,
+            Err(_) => {
+                still_running = running.iter().map(|(name, _)| *name).collect();
+                warn!(
+                    "Timed out after {:?} waiting for threads to exit: {:?}",
+                    timeout, still_running
+                );
+                break;
+            }
+        };
+
+        if let Some(index) = running.iter().position(|(n, _)| *n == name) {
+            let (name, handle) = running.swap_remove(index);
+            match handle.join() {
+                Ok(()) => debug!("Thread exited: {}", name),
+                Err(_) => warn!("Thread panicked: {}", name),
+            }
+        }
+    }
+
+    still_running
+}
+
+// (message to write to the desk, number of times to write it, whether the target height has been reached)
+type PanelToDeskMessageInfo = (PanelToDeskMessage, usize, bool);
+
+fn calculate_panel_to_desk_message(
+    received_panel_key: Option<PanelToDeskMessage>,
+    target_height: Option<f32>,
+    current_height: f32,
+    velocity: f32,
+    coast_profile: &CoastProfile,
+    at_target_tolerance_cm: f32,
+) -> Option<PanelToDeskMessageInfo> {
+    match received_panel_key {
+        Some(PanelToDeskMessage::NoKey) => {
+            if target_height.is_none() {
+                return Some((PanelToDeskMessage::NoKey, 1, false));
+            }
+        }
+        Some(key) => return Some((key, 1, false)),
+        None => {
+            // continue
+        }
+    }
+
+    let target_height = match target_height {
+        None => {
+            return None;
+        }
+        Some(t) => t,
+    };
+
+    // Positive if the desk needs to move up
+    let remaining = target_height - current_height;
+
+    if remaining.abs() <= at_target_tolerance_cm {
+        return Some((PanelToDeskMessage::NoKey, 200, true));
+    }
+
+    // Release the key early if the desk will coast the rest of the way
+    if velocity * remaining > 0.0 && coast_profile.predict(velocity) >= remaining.abs() {
+        return Some((PanelToDeskMessage::NoKey, 200, true));
+    }
+
+    if remaining > 0.0 {
+        Some((PanelToDeskMessage::Up, 1, false))
+    } else {
+        Some((PanelToDeskMessage::Down, 1, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use crate::HealthStatus;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const TOLERANCE_CM: f32 = 0.2;
+
+    // Records everything written to the desk. Never receives anything.
+    #[derive(Default)]
+    struct RecordingTransport {
+        written_to_desk: Mutex<Vec<PanelToDeskMessage>>,
+        shut_down: AtomicBool,
+    }
+
+    impl Transport for RecordingTransport {
+        fn shutdown(&self) -> Result<(), DeskError> {
+            self.shut_down.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+            thread::sleep(Duration::from_millis(10));
+            Ok((None, 0))
+        }
+
+        fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+            thread::sleep(Duration::from_millis(10));
+            Ok((None, 0))
+        }
+
+        fn write_to_desk(&self, message: PanelToDeskMessage) -> Result<(), DeskError> {
+            self.written_to_desk.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        fn write_to_panel(&self, _: DeskToPanelMessage) -> Result<(), DeskError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_desk_controller_move_to_height() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        assert_eq!(controller.target_height(), None);
+
+        controller.move_to_height(100.0).unwrap();
+        assert_eq!(controller.target_height(), Some(100.0));
+
+        assert!(controller.move_to_height(50.0).is_err());
+        assert!(controller.move_to_height(130.0).is_err());
+        assert!(controller.move_to_height(100.2).is_err());
+        assert_eq!(controller.target_height(), Some(100.0));
+
+        controller.clear_target_height();
+        assert_eq!(controller.target_height(), None);
+    }
+
+    #[test]
+    fn test_desk_controller_configured_height_range() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+
+        let config = ControllerConfig {
+            min_height_cm: 70.0,
+            max_height_cm: 120.0,
+            ..ControllerConfig::default()
+        };
+        controller.set_controller_config(config).unwrap();
+
+        let e = match controller.move_to_height(125.0) {
+            Err(DeskError::InvalidHeight(e)) => e,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert!(e.is_out_of_range());
+        assert_eq!(
+            e.to_string(),
+            "Invalid height: 125 - must be between 70 and 120"
+        );
+
+        let e = match controller.move_to_height(100.2) {
+            Err(DeskError::InvalidHeight(e)) => e,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert!(e.is_not_multiple_of_zero_point_five() && !e.is_out_of_range());
+        assert_eq!(
+            e.to_string(),
+            "Invalid height: 100.2 - must be a multiple of 0.5 cm"
+        );
+        controller.move_to_height(120.0).unwrap();
+
+        // Presets and schedules are checked against the configured range when they're saved
+        assert!(matches!(
+            controller.save_preset("stand", 125.0),
+            Err(PresetError::InvalidHeight(_))
+        ));
+        controller.save_preset("stand", 120.0).unwrap();
+        let schedule = Schedule::new(
+            vec!["weekdays 10:00 125".parse().unwrap()],
+            Duration::from_secs(0),
+        )
+        .unwrap();
+        assert!(matches!(
+            controller.set_schedule(schedule),
+            Err(ScheduleError::InvalidHeight(_))
+        ));
+
+        // The configured range must lie within the desk's range
+        let config = ControllerConfig {
+            max_height_cm: 140.0,
+            ..ControllerConfig::default()
+        };
+        assert_eq!(
+            controller
+                .set_controller_config(config)
+                .unwrap_err()
+                .field(),
+            "max_height_cm"
+        );
+        assert_eq!(
+            controller.controller_config().max_height_cm.to_bits(),
+            120f32.to_bits()
+        );
+    }
+
+    #[test]
+    fn test_desk_controller_presets() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+
+        controller.save_preset("stand", 110.0).unwrap();
+        assert!(controller.save_preset("stand", 200.0).is_err());
+        assert_eq!(controller.preset("stand"), Some(110.0));
+
+        controller.move_to_preset("stand").unwrap();
+        assert_eq!(controller.target_height(), Some(110.0));
+
+        assert!(matches!(
+            controller.move_to_preset("sit"),
+            Err(PresetError::NotFound(_))
+        ));
+
+        controller.delete_preset("stand").unwrap();
+        assert!(controller.presets().is_empty());
+    }
+
+    #[test]
+    fn test_desk_controller_schedule() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        controller
+            .set_schedule(
+                Schedule::new(
+                    vec!["weekdays 10:00 110".parse().unwrap()],
+                    Duration::from_secs(5 * 60),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        controller.run_schedule(Weekday::Sat, 600);
+        assert_eq!(controller.target_height(), None);
+        controller.run_schedule(Weekday::Mon, 600);
+        assert_eq!(controller.target_height(), Some(110.0));
+
+        // A key pressed on the panel pauses the schedule
+        controller.clear_target_height();
+        controller.pause_schedule();
+        assert!(controller.schedule_paused_for().unwrap() > Duration::from_secs(4 * 60));
+        controller.run_schedule(Weekday::Mon, 600);
+        assert_eq!(controller.target_height(), None);
+
+        controller.resume_schedule();
+        assert_eq!(controller.schedule_paused_for(), None);
+        controller.run_schedule(Weekday::Mon, 600);
+        assert_eq!(controller.target_height(), Some(110.0));
+    }
+
+    #[test]
+    fn test_desk_controller_records_history() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        let history = Arc::new(History::open_in_memory().unwrap());
+        *controller.inner.history.write().unwrap() = Some(history.clone());
+        controller
+            .set_schedule(
+                Schedule::new(
+                    vec!["daily 10:00 110".parse().unwrap()],
+                    Duration::from_secs(30 * 60),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        controller.track_movement(72.5, true);
+        controller.run_schedule(Weekday::Mon, 600);
+        controller.track_movement(80.0, false);
+        controller.track_movement(110.0, true);
+
+        let movements = history.movements(10).unwrap();
+        assert_eq!(movements.len(), 1);
+        assert_eq!(movements[0].source, MovementSource::Schedule);
+        assert!((movements[0].end_height - 110.0).abs() < 0.001);
+
+        controller.record_incident(IncidentKind::Stall, "stalled".to_string());
+        assert_eq!(history.incidents(10).unwrap()[0].message, "stalled");
+
+        let metrics = controller.metrics();
+        assert_eq!(metrics.movement_durations[2].0, MovementSource::Schedule);
+        assert_eq!(metrics.movement_durations[2].1.count, 1);
+        assert_eq!(metrics.incidents[1], (IncidentKind::Stall, 1));
+    }
+
+    #[test]
+    fn test_desk_controller_panel_memory() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        controller.set_current_height(80.0);
+
+        assert_eq!(
+            controller.observe_panel_memory(PanelToDeskMessage::Two(110.0)),
+            PanelToDeskMessage::Two(110.0)
+        );
+        assert_eq!(controller.panel_memory_slots().two, Some(110.0));
+
+        assert!(controller
+            .program_panel_memory_slot(MemorySlot::Two, 200.0)
+            .is_err());
+        controller
+            .program_panel_memory_slot(MemorySlot::Two, 115.0)
+            .unwrap();
+        assert_eq!(controller.target_height(), Some(115.0));
+
+        assert_eq!(
+            controller.observe_panel_memory(PanelToDeskMessage::Two(110.0)),
+            PanelToDeskMessage::Two(115.0)
+        );
+        assert_eq!(controller.panel_memory_slots().two, Some(115.0));
+    }
+
+    #[test]
+    fn test_desk_controller_panel_memory_path() {
+        let path = std::env::temp_dir().join(format!(
+            "desk_controller_panel_memory_{}.toml",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        controller.set_panel_memory_path(&path).unwrap();
+        controller
+            .program_panel_memory_slot(MemorySlot::Two, 115.0)
+            .unwrap();
+
+        // Programmed slots are still rewritten after a restart
+        let restarted = DeskController::new(Arc::new(MockTransport::new()));
+        restarted.set_panel_memory_path(&path).unwrap();
+        assert_eq!(restarted.panel_memory_slots().two, Some(115.0));
+        assert_eq!(
+            restarted.observe_panel_memory(PanelToDeskMessage::Two(110.0)),
+            PanelToDeskMessage::Two(115.0)
+        );
+
+        // ...until the slot is stored on the panel
+        restarted.set_current_height(90.0);
+        restarted.observe_panel_memory(PanelToDeskMessage::ResetTwo);
+        assert_eq!(
+            PanelMemorySlots::load(&path, MIN_DESK_HEIGHT_CM, MAX_DESK_HEIGHT_CM).unwrap(),
+            PanelMemorySlots::default()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_desk_controller_events() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        let mut subscription = controller.subscribe(Duration::from_millis(0));
+
+        controller.set_current_height(80.0);
+        controller.set_current_height(80.0);
+        controller.move_to_height(100.0).unwrap();
+        controller.set_current_panel_key(Some(PanelToDeskMessage::NoKey));
+        controller.set_current_panel_key(Some(PanelToDeskMessage::NoKey));
+        controller.set_motion_state(MotionState::Moving);
+        controller.set_current_height(80.1);
+
+        assert_eq!(
+            subscription.next_batch(Duration::from_millis(1)),
+            Some(vec![
+                Event::Height(80.1),
+                Event::TargetHeight(Some(100.0)),
+                Event::PanelKey(Some(PanelToDeskMessage::NoKey)),
+                Event::MotionState(MotionState::Moving),
+            ])
+        );
+        assert_eq!(
+            subscription.next_batch(Duration::from_millis(1)),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn test_desk_controller_last_unknown_desk_frame() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        let mut subscription = controller.subscribe(Duration::from_millis(0));
+        assert_eq!(controller.last_unknown_desk_frame(), None);
+
+        let fault = DeskToPanelMessage::Unknown(1, 2, 3, 4, 10);
+        controller.set_last_unknown_desk_frame(fault);
+        controller.set_last_unknown_desk_frame(fault);
+        assert_eq!(controller.last_unknown_desk_frame().unwrap().message, fault);
+
+        assert_eq!(
+            subscription.next_batch(Duration::from_millis(1)),
+            Some(vec![Event::DeskFrame(fault)])
+        );
+    }
+
+    // Fails the first few reads from the desk, and panics reading from the panel
+    struct FlakyTransport {
+        desk_failures: AtomicUsize,
+    }
+
+    impl Transport for FlakyTransport {
+        fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+            thread::sleep(Duration::from_millis(8));
+
+            if self.desk_failures.load(Ordering::SeqCst) > 0 {
+                self.desk_failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(DeskError::from(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "unplugged",
+                )));
+            }
+
+            Ok((Some(DeskToPanelMessage::Height(80.0).as_frame()), 0))
+        }
+
+        fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+            panic!("panel reader failed");
+        }
+
+        fn write_to_desk(&self, _: PanelToDeskMessage) -> Result<(), DeskError> {
+            Ok(())
+        }
+
+        fn write_to_panel(&self, _: DeskToPanelMessage) -> Result<(), DeskError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_desk_controller_health() {
+        let controller = DeskController::new(Arc::new(FlakyTransport {
+            desk_failures: AtomicUsize::new(3),
+        }));
+        let history = Arc::new(History::open_in_memory().unwrap());
+        *controller.inner.history.write().unwrap() = Some(history.clone());
+        assert_eq!(controller.health(), Health::new());
+
+        let (_ctl_tx, ctl_rx) = unbounded::<bool>();
+        let run_controller = controller.clone();
+        let run = thread::spawn(move || run_controller.run(ctl_rx).is_ok());
+
+        let started_at = Instant::now();
+        while (controller.current_height() == 0.0 || controller.health().stopped.is_empty())
+            && started_at.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        controller.shutdown().unwrap();
+        assert!(run.join().unwrap());
+
+        // The desk reader kept going after its reads failed, and recovered once they succeeded
+        assert_eq!(controller.current_height().to_bits(), 80f32.to_bits());
+        let health = controller.health();
+        assert_eq!(health.status, HealthStatus::Failed);
+        assert!(health.failing.is_empty());
+        assert_eq!(health.stopped, vec!["panel reader"]);
+        assert_eq!(health.error_count, 3);
+
+        // Repeated failures are only recorded once
+        let mut incidents = history
+            .incidents(10)
+            .unwrap()
+            .into_iter()
+            .map(|incident| incident.message)
+            .collect::<Vec<_>>();
+        incidents.sort();
+        assert_eq!(
+            incidents,
+            vec![
+                "desk reader: Transport error: unplugged",
+                "panel reader stopped unexpectedly",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_desk_controller_press_key() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        assert_eq!(controller.pressed_key(), None);
+
+        controller
+            .press_key(PanelToDeskMessage::Up, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(controller.pressed_key(), Some(PanelToDeskMessage::Up));
+
+        controller
+            .press_key(PanelToDeskMessage::Down, Duration::from_secs(0))
+            .unwrap();
+        assert_eq!(controller.pressed_key(), None);
+
+        // Memory keys carry the height that the desk moves to
+        assert!(matches!(
+            controller.press_key(PanelToDeskMessage::One(300.0), Duration::from_secs(10)),
+            Err(DeskError::InvalidHeight(_))
+        ));
+        assert_eq!(controller.pressed_key(), None);
+        controller
+            .press_key(PanelToDeskMessage::Two(110.0), Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            controller.pressed_key(),
+            Some(PanelToDeskMessage::Two(110.0))
+        );
+    }
+
+    #[test]
+    fn test_desk_controller_motion_state() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        assert_eq!(controller.motion_state(), MotionState::Idle);
+
+        controller.set_motion_state(MotionState::Stalled);
+        controller.move_to_height(100.0).unwrap();
+        assert_eq!(controller.motion_state(), MotionState::Idle);
+
+        controller.set_motion_state(MotionState::Stalled);
+        controller.clear_target_height();
+        assert_eq!(controller.motion_state(), MotionState::Idle);
+    }
+
+    #[test]
+    fn test_desk_controller_shutdown() {
+        let transport = Arc::new(RecordingTransport::default());
+        let controller = DeskController::new(transport.clone());
+
+        let (_ctl_tx, ctl_rx) = unbounded::<bool>();
+        let run_controller = controller.clone();
+        let run = thread::spawn(move || run_controller.run(ctl_rx).is_ok());
+
+        // Drive the desk up so that it's holding a key when we shut down
+        controller.move_to_height(100.0).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(transport
+            .written_to_desk
+            .lock()
+            .unwrap()
+            .contains(&PanelToDeskMessage::Up));
+
+        controller.shutdown().unwrap();
+        assert!(run.join().unwrap());
+
+        let written_to_desk = transport.written_to_desk.lock().unwrap();
+        assert_eq!(
+            written_to_desk[written_to_desk.len() - SHUTDOWN_NO_KEY_FRAMES..],
+            [PanelToDeskMessage::NoKey; SHUTDOWN_NO_KEY_FRAMES]
+        );
+        assert!(transport.shut_down.load(Ordering::SeqCst));
+        assert_eq!(controller.target_height(), None);
+    }
+
+    #[test]
+    fn test_desk_controllers_are_independent() {
+        let controller_1 = DeskController::new(Arc::new(MockTransport::new()));
+        let controller_2 = DeskController::new(Arc::new(MockTransport::new()));
+        let controller_1_handle = controller_1.clone();
+
+        controller_1.move_to_height(100.0).unwrap();
+        controller_1.set_desk_checksum_policy(ChecksumPolicy::Repair);
+
+        assert_eq!(controller_1_handle.target_height(), Some(100.0));
+        assert_eq!(
+            controller_1_handle.desk_checksum_policy(),
+            ChecksumPolicy::Repair
+        );
+        assert_eq!(controller_2.target_height(), None);
+        assert_eq!(controller_2.desk_checksum_policy(), ChecksumPolicy::Forward);
+    }
+
+    #[test]
+    fn test_desk_controller_frame_counts() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+
+        let mut bad_checksum = DeskToPanelMessage::Height(100.0).as_frame();
+        bad_checksum[5] += 1;
+
+        assert_eq!(
+            controller.decode_desk_frame(&DeskToPanelMessage::Height(100.0).as_frame()),
+            Some(DeskToPanelMessage::Height(100.0))
+        );
+        // A bad checksum is passed through unchanged unless the policy says otherwise
+        assert_eq!(
+            controller
+                .decode_desk_frame(&bad_checksum)
+                .map(|message| message.as_frame()),
+            Some(bad_checksum)
+        );
+        controller.set_desk_checksum_policy(ChecksumPolicy::Drop);
+        assert_eq!(controller.decode_desk_frame(&bad_checksum), None);
+        assert_eq!(controller.decode_desk_frame(&[0u8; 7]), None);
+
+        assert_eq!(
+            controller.desk_frame_counts(),
+            FrameCounts {
+                found_frames: 0,
+                dropped_bytes: 7,
+                checksum_failures: 2,
+            }
+        );
+        assert_eq!(controller.panel_frame_counts(), FrameCounts::default());
+    }
+
+    #[test]
+    fn test_calculate_panel_to_desk_message_no_key_no_target_height() {
+        let current_height = 70.0;
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                None,
+                current_height,
+                0.0,
+                &CoastProfile::default(),
+                TOLERANCE_CM,
+            ),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_calculate_panel_to_desk_message_no_key_target_greater_than_current() {
+        let target_height = Some(100.0);
+        let current_height = 70.0;
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                target_height,
+                current_height,
+                0.0,
+                &CoastProfile::default(),
+                TOLERANCE_CM,
+            ),
+            Some((PanelToDeskMessage::Up, 1, false))
+        );
+    }
+
+    #[test]
+    fn test_calculate_panel_to_desk_message_no_key_target_less_than_current() {
+        let target_height = Some(60.0);
+        let current_height = 70.0;
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                target_height,
+                current_height,
+                0.0,
+                &CoastProfile::default(),
+                TOLERANCE_CM,
+            ),
+            Some((PanelToDeskMessage::Down, 1, false))
+        );
+    }
+
+    #[test]
+    fn test_calculate_panel_to_desk_message_no_key_target_equal_to_current() {
+        let target_height = Some(70.0);
+        let current_height = 70.0;
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                target_height,
+                current_height,
+                0.0,
+                &CoastProfile::default(),
+                TOLERANCE_CM,
+            ),
+            Some((PanelToDeskMessage::NoKey, 200, true))
+        );
+    }
+
+    #[test]
+    fn test_calculate_panel_to_desk_message_current_key_nokey_target_greater_than_current() {
+        let target_height = Some(100.0);
+        let current_height = 70.0;
+        let current_panel_key = Some(PanelToDeskMessage::NoKey);
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                current_panel_key,
+                target_height,
+                current_height,
+                0.0,
+                &CoastProfile::default(),
+                TOLERANCE_CM,
+            ),
+            Some((PanelToDeskMessage::Up, 1, false))
+        );
+    }
+
+    #[test]
+    fn test_calculate_panel_to_desk_message_current_key_nokey_no_target() {
+        let target_height = None;
+        let current_height = 70.0;
+        let current_panel_key = Some(PanelToDeskMessage::NoKey);
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                current_panel_key,
+                target_height,
+                current_height,
+                0.0,
+                &CoastProfile::default(),
+                TOLERANCE_CM,
+            ),
+            Some((PanelToDeskMessage::NoKey, 1, false))
+        );
+    }
+
+    #[test]
+    fn test_calculate_panel_to_desk_message_current_key_other_target_greater_current() {
+        let target_height = Some(100.0);
+        let current_height = 70.0;
+        let current_panel_key = Some(PanelToDeskMessage::Two(120.0));
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                current_panel_key,
+                target_height,
+                current_height,
+                0.0,
+                &CoastProfile::default(),
+                TOLERANCE_CM,
+            ),
+            Some((PanelToDeskMessage::Two(120.0), 1, false))
+        );
+    }
+
+    #[test]
+    fn test_calculate_panel_to_desk_message_releases_early_when_moving_toward_target() {
+        let target_height = Some(100.0);
+        let coast_profile = CoastProfile::default();
+
+        // Coasting at full speed would take the desk past the target
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                target_height,
+                99.6,
+                3.8,
+                &coast_profile,
+                TOLERANCE_CM
+            ),
+            Some((PanelToDeskMessage::NoKey, 200, true))
+        );
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                target_height,
+                100.4,
+                -3.8,
+                &coast_profile,
+                TOLERANCE_CM
+            ),
+            Some((PanelToDeskMessage::NoKey, 200, true))
+        );
+
+        // Too far away to coast the rest of the way
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                target_height,
+                99.0,
+                3.8,
+                &coast_profile,
+                TOLERANCE_CM
+            ),
+            Some((PanelToDeskMessage::Up, 1, false))
+        );
+
+        // Not moving, or moving the wrong way
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                target_height,
+                99.6,
+                0.0,
+                &coast_profile,
+                TOLERANCE_CM
+            ),
+            Some((PanelToDeskMessage::Up, 1, false))
+        );
+        assert_eq!(
+            calculate_panel_to_desk_message(
+                None,
+                target_height,
+                100.4,
+                3.8,
+                &coast_profile,
+                TOLERANCE_CM
+            ),
+            Some((PanelToDeskMessage::Down, 1, false))
+        );
+    }
+
+    #[test]
+    fn test_desk_controller_learns_coast_distance() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+
+        controller.record_release(100.0, 4.0);
+        controller.learn_coast(controller.inner.release.lock().unwrap().unwrap(), 100.7);
+
+        let profile = controller.coast_profile();
+        assert!((profile.up.distance_cm - 0.6).abs() < 0.001);
+        assert_eq!(profile.down, CoastProfile::default().down);
+
+        // Releasing a (nearly) stationary desk isn't learned from
+        controller.record_release(100.0, 0.1);
+        assert!(controller.inner.release.lock().unwrap().is_none());
+    }
+}
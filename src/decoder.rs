@@ -0,0 +1,161 @@
+// Incrementally decodes frames from a stream of bytes, which can arrive in chunks of any size.
+// Only the start and end bytes are checked: frames with a bad checksum are still returned,
+// so that the checksum policy can decide what to do with them.
+// Nothing is allocated, so this is cheap enough to run on every byte read from the UARTs.
+
+use crate::protocol;
+use crate::protocol::{DataFrame, DATA_FRAME_SIZE};
+use log::debug;
+
+#[derive(Clone, Debug)]
+pub struct FrameDecoder {
+    // The bytes of the frame decoded so far. Once it has any, the first is always a start byte.
+    frame: DataFrame,
+    len: usize,
+    dropped_byte_count: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            frame: [0u8; DATA_FRAME_SIZE],
+            len: 0,
+            dropped_byte_count: 0,
+        }
+    }
+
+    // Consumes bytes from the front of `bytes` up to the end of the next complete frame,
+    // leaving any bytes after it for the next call. Returns None once `bytes` is used up
+    // without completing a frame; the partial frame is kept until more bytes arrive.
+    pub fn decode(&mut self, bytes: &mut &[u8]) -> Option<DataFrame> {
+        while let Some((&b, rest)) = bytes.split_first() {
+            *bytes = rest;
+
+            if let Some(frame) = self.push(b) {
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    pub fn push(&mut self, b: u8) -> Option<DataFrame> {
+        if self.len == 0 && !protocol::is_start_byte(b) {
+            self.dropped_byte_count += 1;
+            return None;
+        }
+
+        self.frame[self.len] = b;
+        self.len += 1;
+
+        if self.len < DATA_FRAME_SIZE {
+            return None;
+        }
+
+        match protocol::validate_frame(&self.frame) {
+            Ok(()) => {
+                self.len = 0;
+                Some(self.frame)
+            }
+            Err(e) => {
+                debug!("{}: {:?}", e, self.frame);
+                self.resynchronize();
+                None
+            }
+        }
+    }
+
+    // Bytes dropped while looking for frames since the last call
+    pub fn take_dropped_byte_count(&mut self) -> usize {
+        std::mem::replace(&mut self.dropped_byte_count, 0)
+    }
+
+    // Drops the start of an invalid frame, keeping the bytes from the next start byte in it (if any)
+    // as they may be the start of a real frame that the invalid one overlapped
+    fn resynchronize(&mut self) {
+        let next_start = self.frame[1..self.len]
+            .iter()
+            .position(|&b| protocol::is_start_byte(b))
+            .map_or(self.len, |i| i + 1);
+
+        self.frame.copy_within(next_start..self.len, 0);
+        self.len -= next_start;
+        self.dropped_byte_count += next_start;
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> FrameDecoder {
+        FrameDecoder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{DeskToPanelMessage, PanelToDeskMessage};
+
+    fn decode_all(decoder: &mut FrameDecoder, mut bytes: &[u8]) -> Vec<(DataFrame, usize)> {
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.decode(&mut bytes) {
+            frames.push((frame, decoder.take_dropped_byte_count()));
+        }
+        frames
+    }
+
+    #[test]
+    fn test_frame_decoder_chunks() {
+        let up = PanelToDeskMessage::Up.as_frame();
+        let down = PanelToDeskMessage::Down.as_frame();
+
+        let mut bytes = vec![0u8, 1u8];
+        bytes.extend(&up);
+        bytes.extend(&down);
+        bytes.extend(&up[..3]);
+
+        // The same frames come out however the bytes are split up
+        for chunk_size in 1..=bytes.len() {
+            let mut decoder = FrameDecoder::new();
+            let mut frames = Vec::new();
+            for chunk in bytes.chunks(chunk_size) {
+                frames.extend(decode_all(&mut decoder, chunk));
+            }
+
+            assert_eq!(
+                frames,
+                vec![(up, 2), (down, 0)],
+                "chunk size {}",
+                chunk_size
+            );
+            assert_eq!(decoder.take_dropped_byte_count(), 0);
+
+            // The partial frame is completed by the next chunk
+            assert_eq!(decode_all(&mut decoder, &up[3..]), vec![(up, 0)]);
+        }
+    }
+
+    #[test]
+    fn test_frame_decoder_resynchronizes() {
+        let height = DeskToPanelMessage::Height(100.0).as_frame();
+
+        // A frame cut short by a real one, whose start byte is inside the invalid frame
+        let mut bytes = height[..4].to_vec();
+        bytes.extend(&height);
+
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decode_all(&mut decoder, &bytes), vec![(height, 4)]);
+
+        // A start byte in the payload of an invalid frame that turns out not to start a frame either
+        let mut bytes = vec![104u8, 1u8, 104u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8];
+        bytes.extend(&height);
+        assert_eq!(decode_all(&mut decoder, &bytes), vec![(height, 9)]);
+
+        // A bad checksum is left for the checksum policy
+        let mut bad_checksum = height;
+        bad_checksum[5] += 1;
+        assert_eq!(
+            decode_all(&mut decoder, &bad_checksum),
+            vec![(bad_checksum, 0)]
+        );
+    }
+}
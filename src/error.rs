@@ -0,0 +1,150 @@
+use crate::capture::CaptureError;
+use crate::protocol::FrameError;
+use crate::{InvalidConfigError, InvalidHeightError};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum DeskError {
+    // Reading from or writing to the desk or panel failed
+    Transport(Box<dyn Error + Send + Sync>),
+    // A frame's length, start byte or end byte was wrong
+    Frame(FrameError),
+    Checksum {
+        expected: u8,
+        actual: u8,
+    },
+    InvalidHeight(InvalidHeightError),
+    // A transport was opened with an invalid config
+    InvalidConfig(InvalidConfigError),
+    // Gave up waiting for something (e.g. for threads to exit on shutdown)
+    Timeout {
+        waiting_for: String,
+        after: Duration,
+    },
+    // The controller has shut down, or is shutting down
+    Shutdown,
+}
+
+impl DeskError {
+    pub fn transport<E: Into<Box<dyn Error + Send + Sync>>>(e: E) -> DeskError {
+        DeskError::Transport(e.into())
+    }
+}
+
+impl From<io::Error> for DeskError {
+    fn from(e: io::Error) -> DeskError {
+        DeskError::transport(e)
+    }
+}
+
+impl From<CaptureError> for DeskError {
+    fn from(e: CaptureError) -> DeskError {
+        DeskError::transport(e)
+    }
+}
+
+impl From<FrameError> for DeskError {
+    fn from(e: FrameError) -> DeskError {
+        match e {
+            FrameError::InvalidChecksum { expected, actual } => {
+                DeskError::Checksum { expected, actual }
+            }
+            _ => DeskError::Frame(e),
+        }
+    }
+}
+
+impl From<InvalidHeightError> for DeskError {
+    fn from(e: InvalidHeightError) -> DeskError {
+        DeskError::InvalidHeight(e)
+    }
+}
+
+impl From<InvalidConfigError> for DeskError {
+    fn from(e: InvalidConfigError) -> DeskError {
+        DeskError::InvalidConfig(e)
+    }
+}
+
+impl<T> From<crossbeam_channel::SendError<T>> for DeskError {
+    fn from(_: crossbeam_channel::SendError<T>) -> DeskError {
+        DeskError::Shutdown
+    }
+}
+
+impl From<crossbeam_channel::RecvError> for DeskError {
+    fn from(_: crossbeam_channel::RecvError) -> DeskError {
+        DeskError::Shutdown
+    }
+}
+
+impl Display for DeskError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DeskError::Transport(e) => write!(f, "Transport error: {}", e),
+            DeskError::Frame(e) => write!(f, "{}", e),
+            DeskError::Checksum { expected, actual } => write!(
+                f,
+                "Invalid frame: checksum {} - expected {}",
+                actual, expected
+            ),
+            DeskError::InvalidHeight(e) => write!(f, "{}", e),
+            DeskError::InvalidConfig(e) => write!(f, "{}", e),
+            DeskError::Timeout { waiting_for, after } => {
+                write!(f, "Timed out after {:?} waiting for {}", after, waiting_for)
+            }
+            DeskError::Shutdown => write!(f, "The desk controller has shut down"),
+        }
+    }
+}
+
+impl Error for DeskError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DeskError::Transport(e) => Some(e.as_ref()),
+            DeskError::Frame(e) => Some(e),
+            DeskError::InvalidHeight(e) => Some(e),
+            DeskError::InvalidConfig(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_desk_error_from() {
+        match DeskError::from(FrameError::InvalidChecksum {
+            expected: 2,
+            actual: 99,
+        }) {
+            DeskError::Checksum {
+                expected: 2,
+                actual: 99,
+            } => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        match DeskError::from(FrameError::InvalidEndByte(0)) {
+            DeskError::Frame(FrameError::InvalidEndByte(0)) => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let e = DeskError::from(io::Error::new(io::ErrorKind::BrokenPipe, "unplugged"));
+        assert_eq!(e.to_string(), "Transport error: unplugged");
+        assert!(e.source().is_some());
+
+        let (tx, rx) = crossbeam_channel::unbounded::<()>();
+        drop(rx);
+        assert!(matches!(
+            DeskError::from(tx.send(()).unwrap_err()),
+            DeskError::Shutdown
+        ));
+    }
+}
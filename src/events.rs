@@ -0,0 +1,191 @@
+// State changes published by the controller, for anything that wants to follow the desk as it moves.
+// The desk reports its height every 8 ms, so subscribers receive changes in batches, with only the latest
+// height in each. Every other change is kept, in order, up to a limit per subscriber, so that a slow
+// subscriber can't build up a backlog.
+
+use crate::protocol::{DeskToPanelMessage, PanelToDeskMessage};
+use crate::{HealthStatus, MotionState};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Changes other than heights that are kept for a subscriber before the oldest are dropped
+const MAX_PENDING_EVENTS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Height(f32),
+    TargetHeight(Option<f32>),
+    PanelKey(Option<PanelToDeskMessage>),
+    MotionState(MotionState),
+    Health(HealthStatus),
+    // A desk-to-panel frame other than a height (e.g. a fault), when it differs from the last one
+    DeskFrame(DeskToPanelMessage),
+}
+
+pub struct Subscription {
+    pending: Arc<Mutex<Vec<Event>>>,
+    // Woken when there are pending events
+    wake_rx: Receiver<()>,
+    min_interval: Duration,
+    last_batch: Option<Instant>,
+}
+
+impl Subscription {
+    // Blocks until there is at least one event, or until `timeout` passes (returning an empty batch).
+    // Batches are at least `min_interval` apart, and only contain the latest height.
+    // Returns None once the controller has gone away.
+    pub fn next_batch(&mut self, timeout: Duration) -> Option<Vec<Event>> {
+        if let Some(last_batch) = self.last_batch {
+            let next_batch = last_batch + self.min_interval;
+            let now = Instant::now();
+            if next_batch > now {
+                thread::sleep(next_batch - now);
+            }
+        }
+
+        match self.wake_rx.recv_timeout(timeout) {
+            Ok(()) => {}
+            Err(RecvTimeoutError::Timeout) => return Some(Vec::new()),
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+
+        self.last_batch = Some(Instant::now());
+        Some(mem::take(&mut *self.pending.lock().unwrap()))
+    }
+}
+
+struct Subscriber {
+    pending: Arc<Mutex<Vec<Event>>>,
+    wake_tx: Sender<()>,
+}
+
+pub(crate) struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventBus {
+    pub(crate) fn new() -> EventBus {
+        EventBus {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn subscribe(&self, min_interval: Duration) -> Subscription {
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        // One wake-up is enough, however many events are published before the subscriber gets to them
+        let (wake_tx, wake_rx) = bounded(1);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            pending: pending.clone(),
+            wake_tx,
+        });
+
+        Subscription {
+            pending,
+            wake_rx,
+            min_interval,
+            last_batch: None,
+        }
+    }
+
+    pub(crate) fn publish(&self, event: Event) {
+        // Subscriptions that have been dropped are removed here
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            coalesce(&mut subscriber.pending.lock().unwrap(), event);
+            match subscriber.wake_tx.try_send(()) {
+                Ok(()) | Err(TrySendError::Full(())) => true,
+                Err(TrySendError::Disconnected(())) => false,
+            }
+        });
+    }
+}
+
+// Keeps only the latest height (where the first height was), and every other change in order.
+// Once there are too many changes, the oldest is dropped.
+fn coalesce(events: &mut Vec<Event>, event: Event) {
+    if let Event::Height(_) = event {
+        if let Some(existing) = events.iter_mut().find(|e| matches!(e, Event::Height(_))) {
+            *existing = event;
+            return;
+        }
+    }
+
+    events.push(event);
+
+    if events.len() > MAX_PENDING_EVENTS {
+        if let Some(oldest) = events.iter().position(|e| !matches!(e, Event::Height(_))) {
+            events.remove(oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce() {
+        let mut events = Vec::new();
+        for &event in &[
+            Event::Height(80.0),
+            Event::MotionState(MotionState::Moving),
+            Event::Height(80.1),
+            Event::TargetHeight(None),
+            Event::MotionState(MotionState::Recovering),
+            Event::MotionState(MotionState::Moving),
+            Event::Height(80.2),
+        ] {
+            coalesce(&mut events, event);
+        }
+
+        // Every motion state is kept, so that subscribers see each transition
+        assert_eq!(
+            events,
+            vec![
+                Event::Height(80.2),
+                Event::MotionState(MotionState::Moving),
+                Event::TargetHeight(None),
+                Event::MotionState(MotionState::Recovering),
+                Event::MotionState(MotionState::Moving),
+            ]
+        );
+
+        // ...up to a limit
+        for i in 0..MAX_PENDING_EVENTS {
+            coalesce(&mut events, Event::TargetHeight(Some(i as f32)));
+        }
+        assert_eq!(events.len(), MAX_PENDING_EVENTS);
+        assert_eq!(events[0], Event::Height(80.2));
+        assert_eq!(events[1], Event::TargetHeight(Some(1.0)));
+    }
+
+    #[test]
+    fn test_event_bus() {
+        let bus = EventBus::new();
+        let mut subscription = bus.subscribe(Duration::from_millis(0));
+        let dropped_subscription = bus.subscribe(Duration::from_millis(0));
+        drop(dropped_subscription);
+
+        assert_eq!(
+            subscription.next_batch(Duration::from_millis(1)),
+            Some(vec![])
+        );
+
+        for i in 0..1000 {
+            bus.publish(Event::Height(80.0 + i as f32 / 10.0));
+        }
+        bus.publish(Event::Height(80.1));
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(subscription.pending.lock().unwrap().len(), 1);
+
+        assert_eq!(
+            subscription.next_batch(Duration::from_millis(1)),
+            Some(vec![Event::Height(80.1)])
+        );
+
+        drop(bus);
+        assert_eq!(subscription.next_batch(Duration::from_millis(1)), None);
+    }
+}
@@ -0,0 +1,571 @@
+// Height and movement history, stored in a local SQLite database.
+// Heights are recorded when the desk comes to rest, and each one lasts until the next recorded height.
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, NaiveDate, TimeZone};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// At or above this height, the desk is counted as standing
+pub const STANDING_MIN_HEIGHT_CM: f32 = 95.0;
+
+// Smaller changes in the settled height are noise rather than movements
+const MIN_MOVEMENT_CM: f32 = 0.5;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS heights (
+        at INTEGER NOT NULL,
+        height REAL
+    );
+    CREATE INDEX IF NOT EXISTS heights_at ON heights (at);
+    CREATE TABLE IF NOT EXISTS movements (
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER NOT NULL,
+        start_height REAL NOT NULL,
+        end_height REAL NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS incidents (
+        at INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        height REAL NOT NULL,
+        message TEXT NOT NULL
+    );
+";
+
+// What started a movement
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MovementSource {
+    Panel,
+    Api,
+    Schedule,
+}
+
+impl MovementSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MovementSource::Panel => "panel",
+            MovementSource::Api => "api",
+            MovementSource::Schedule => "schedule",
+        }
+    }
+}
+
+impl FromStr for MovementSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MovementSource, String> {
+        match s {
+            "panel" => Ok(MovementSource::Panel),
+            "api" => Ok(MovementSource::Api),
+            "schedule" => Ok(MovementSource::Schedule),
+            _ => Err(format!("Unknown movement source: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Movement {
+    pub started_at: SystemTime,
+    pub ended_at: SystemTime,
+    pub start_height: f32,
+    pub end_height: f32,
+    pub source: MovementSource,
+}
+
+impl Movement {
+    pub fn duration(&self) -> Duration {
+        self.ended_at
+            .duration_since(self.started_at)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentKind {
+    StallRecovery,
+    Stall,
+    Error,
+}
+
+impl IncidentKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IncidentKind::StallRecovery => "stall_recovery",
+            IncidentKind::Stall => "stall",
+            IncidentKind::Error => "error",
+        }
+    }
+}
+
+impl FromStr for IncidentKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IncidentKind, String> {
+        match s {
+            "stall_recovery" => Ok(IncidentKind::StallRecovery),
+            "stall" => Ok(IncidentKind::Stall),
+            "error" => Ok(IncidentKind::Error),
+            _ => Err(format!("Unknown incident kind: {}", s)),
+        }
+    }
+}
+
+// A stall, or an error that the controller carried on from
+#[derive(Clone, Debug, PartialEq)]
+pub struct Incident {
+    pub at: SystemTime,
+    pub kind: IncidentKind,
+    pub height: f32,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SitStand {
+    pub sitting: Duration,
+    pub standing: Duration,
+}
+
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+impl History {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<History, HistoryError> {
+        History::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<History, HistoryError> {
+        History::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> Result<History, HistoryError> {
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(History {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // A height of None means that the height is no longer known from `at`, e.g. because the controller stopped
+    pub fn record_height(&self, at: SystemTime, height: Option<f32>) -> Result<(), HistoryError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO heights (at, height) VALUES (?1, ?2)",
+            params![to_millis(at), height.map(f64::from)],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_movement(&self, movement: &Movement) -> Result<(), HistoryError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO movements (started_at, ended_at, start_height, end_height, source) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                to_millis(movement.started_at),
+                to_millis(movement.ended_at),
+                f64::from(movement.start_height),
+                f64::from(movement.end_height),
+                movement.source.as_str(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_incident(&self, incident: &Incident) -> Result<(), HistoryError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO incidents (at, kind, height, message) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                to_millis(incident.at),
+                incident.kind.as_str(),
+                f64::from(incident.height),
+                incident.message,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Most recent first
+    pub fn movements(&self, limit: u32) -> Result<Vec<Movement>, HistoryError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT started_at, ended_at, start_height, end_height, source FROM movements ORDER BY started_at DESC LIMIT ?1",
+        )?;
+
+        let rows = statement.query_map(params![limit], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, f64>(2)?,
+                row.get::<_, f64>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+
+        rows.map(|row| {
+            let (started_at, ended_at, start_height, end_height, source) = row?;
+            Ok(Movement {
+                started_at: from_millis(started_at),
+                ended_at: from_millis(ended_at),
+                start_height: start_height as f32,
+                end_height: end_height as f32,
+                source: source.parse().map_err(HistoryError::InvalidRow)?,
+            })
+        })
+        .collect()
+    }
+
+    // Most recent first
+    pub fn incidents(&self, limit: u32) -> Result<Vec<Incident>, HistoryError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT at, kind, height, message FROM incidents ORDER BY at DESC LIMIT ?1")?;
+
+        let rows = statement.query_map(params![limit], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, f64>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+
+        rows.map(|row| {
+            let (at, kind, height, message) = row?;
+            Ok(Incident {
+                at: from_millis(at),
+                kind: kind.parse().map_err(HistoryError::InvalidRow)?,
+                height: height as f32,
+                message,
+            })
+        })
+        .collect()
+    }
+
+    // Time spent sitting and standing in each period (start inclusive, end exclusive), up to `now`
+    pub fn sit_stand(
+        &self,
+        periods: &[(SystemTime, SystemTime)],
+        now: SystemTime,
+    ) -> Result<Vec<SitStand>, HistoryError> {
+        let (first, last) = match (
+            periods.iter().map(|p| p.0).min(),
+            periods.iter().map(|p| p.1).max(),
+        ) {
+            (Some(first), Some(last)) => (to_millis(first), to_millis(last)),
+            _ => return Ok(Vec::new()),
+        };
+
+        // Starting from the height that was current at the start of the first period
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT at, height FROM heights
+             WHERE at >= COALESCE((SELECT MAX(at) FROM heights WHERE at <= ?1), ?1) AND at < ?2
+             ORDER BY at",
+        )?;
+        let heights = statement
+            .query_map(params![first, last], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<f64>>(1)?.map(|h| h as f32),
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let now = to_millis(now);
+        Ok(periods
+            .iter()
+            .map(|&(start, end)| sit_stand_between(&heights, to_millis(start), to_millis(end), now))
+            .collect())
+    }
+
+    // The last `days` days (including today), oldest first
+    pub fn sit_stand_by_day(
+        &self,
+        days: u32,
+        now: DateTime<Local>,
+    ) -> Result<Vec<(NaiveDate, SitStand)>, HistoryError> {
+        let today = now.naive_local().date();
+        let dates = (0..days)
+            .rev()
+            .map(|n| today - ChronoDuration::days(i64::from(n)))
+            .collect::<Vec<_>>();
+
+        self.sit_stand_by_period(dates, ChronoDuration::days(1), now)
+    }
+
+    // The last `weeks` weeks (including this week), oldest first. Weeks start on Monday.
+    pub fn sit_stand_by_week(
+        &self,
+        weeks: u32,
+        now: DateTime<Local>,
+    ) -> Result<Vec<(NaiveDate, SitStand)>, HistoryError> {
+        let today = now.naive_local().date();
+        let this_week =
+            today - ChronoDuration::days(i64::from(today.weekday().num_days_from_monday()));
+        let week_starts = (0..weeks)
+            .rev()
+            .map(|n| this_week - ChronoDuration::weeks(i64::from(n)))
+            .collect::<Vec<_>>();
+
+        self.sit_stand_by_period(week_starts, ChronoDuration::weeks(1), now)
+    }
+
+    fn sit_stand_by_period(
+        &self,
+        starts: Vec<NaiveDate>,
+        length: ChronoDuration,
+        now: DateTime<Local>,
+    ) -> Result<Vec<(NaiveDate, SitStand)>, HistoryError> {
+        let periods = starts
+            .iter()
+            .map(|&start| (local_midnight(start), local_midnight(start + length)))
+            .collect::<Vec<_>>();
+
+        let durations = self.sit_stand(&periods, SystemTime::from(now))?;
+        Ok(starts.into_iter().zip(durations).collect())
+    }
+}
+
+// Follows the desk's height, to find when it comes to rest and the movement that brought it there
+#[derive(Debug)]
+pub(crate) enum MovementTracker {
+    Unknown,
+    Settled(f32),
+    Moving {
+        started_at: SystemTime,
+        start_height: f32,
+        source: MovementSource,
+    },
+}
+
+impl MovementTracker {
+    // Returns the height when the desk comes to rest, along with the movement (if there was one)
+    pub(crate) fn observe(
+        &mut self,
+        height: f32,
+        settled: bool,
+        now: SystemTime,
+        source: MovementSource,
+    ) -> Option<(f32, Option<Movement>)> {
+        match *self {
+            MovementTracker::Unknown if settled => {
+                *self = MovementTracker::Settled(height);
+                Some((height, None))
+            }
+            MovementTracker::Settled(start_height) if !settled => {
+                *self = MovementTracker::Moving {
+                    started_at: now,
+                    start_height,
+                    source,
+                };
+                None
+            }
+            MovementTracker::Moving {
+                started_at,
+                start_height,
+                source,
+            } if settled => {
+                if (height - start_height).abs() < MIN_MOVEMENT_CM {
+                    *self = MovementTracker::Settled(start_height);
+                    return None;
+                }
+
+                *self = MovementTracker::Settled(height);
+                Some((
+                    height,
+                    Some(Movement {
+                        started_at,
+                        ended_at: now,
+                        start_height,
+                        end_height: height,
+                        source,
+                    }),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+// `heights` are (time, height) in time order, each lasting until the next
+fn sit_stand_between(heights: &[(i64, Option<f32>)], start: i64, end: i64, now: i64) -> SitStand {
+    let mut sit_stand = SitStand::default();
+    let end = end.min(now);
+
+    for (i, &(at, height)) in heights.iter().enumerate() {
+        let until = heights.get(i + 1).map_or(now, |&(next, _)| next);
+        let overlap = until.min(end) - at.max(start);
+
+        if let (Some(height), true) = (height, overlap > 0) {
+            let overlap = Duration::from_millis(overlap as u64);
+            if height >= STANDING_MIN_HEIGHT_CM {
+                sit_stand.standing += overlap;
+            } else {
+                sit_stand.sitting += overlap;
+            }
+        }
+    }
+
+    sit_stand
+}
+
+fn local_midnight(date: NaiveDate) -> SystemTime {
+    // Midnight doesn't exist on some daylight saving changes
+    let start = Local
+        .from_local_datetime(&date.and_hms(0, 0, 0))
+        .earliest()
+        .or_else(|| Local.from_local_datetime(&date.and_hms(1, 0, 0)).earliest())
+        .expect("failed to find the start of the day");
+
+    SystemTime::from(start)
+}
+
+fn to_millis(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+fn from_millis(ms: i64) -> SystemTime {
+    if ms >= 0 {
+        UNIX_EPOCH + Duration::from_millis(ms as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_millis(ms.unsigned_abs())
+    }
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Database(rusqlite::Error),
+    InvalidRow(String),
+}
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e: rusqlite::Error) -> HistoryError {
+        HistoryError::Database(e)
+    }
+}
+
+impl Display for HistoryError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            HistoryError::Database(e) => write!(f, "History database error: {}", e),
+            HistoryError::InvalidRow(e) => write!(f, "Invalid history row: {}", e),
+        }
+    }
+}
+
+impl Error for HistoryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(s)
+    }
+
+    #[test]
+    fn test_sit_stand() {
+        let history = History::open_in_memory().unwrap();
+        history.record_height(at(100), Some(72.5)).unwrap();
+        history.record_height(at(200), Some(110.0)).unwrap();
+        history.record_height(at(300), None).unwrap();
+        history.record_height(at(400), Some(72.5)).unwrap();
+
+        assert_eq!(
+            history
+                .sit_stand(&[(at(0), at(150)), (at(150), at(1000))], at(450))
+                .unwrap(),
+            vec![
+                SitStand {
+                    sitting: Duration::from_secs(50),
+                    standing: Duration::from_secs(0),
+                },
+                SitStand {
+                    sitting: Duration::from_secs(100),
+                    standing: Duration::from_secs(100),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_movements_and_incidents() {
+        let history = History::open_in_memory().unwrap();
+        let movement = Movement {
+            started_at: at(100),
+            ended_at: at(110),
+            start_height: 72.5,
+            end_height: 110.0,
+            source: MovementSource::Schedule,
+        };
+        history.record_movement(&movement).unwrap();
+        history
+            .record_movement(&Movement {
+                started_at: at(200),
+                ..movement
+            })
+            .unwrap();
+        history
+            .record_incident(&Incident {
+                at: at(300),
+                kind: IncidentKind::Stall,
+                height: 80.0,
+                message: "stalled".to_string(),
+            })
+            .unwrap();
+
+        let movements = history.movements(10).unwrap();
+        assert_eq!(movements.len(), 2);
+        assert_eq!(movements[1], movement);
+        assert_eq!(movements[1].duration(), Duration::from_secs(10));
+        assert_eq!(history.movements(1).unwrap()[0].started_at, at(200));
+
+        assert_eq!(history.incidents(10).unwrap()[0].kind, IncidentKind::Stall);
+    }
+
+    #[test]
+    fn test_movement_tracker() {
+        let mut tracker = MovementTracker::Unknown;
+        let source = MovementSource::Panel;
+
+        assert_eq!(tracker.observe(72.5, false, at(0), source), None);
+        assert_eq!(
+            tracker.observe(72.5, true, at(1), source),
+            Some((72.5, None))
+        );
+        assert_eq!(tracker.observe(72.5, true, at(2), source), None);
+
+        // Too small to be a movement
+        assert_eq!(tracker.observe(72.6, false, at(3), source), None);
+        assert_eq!(tracker.observe(72.6, true, at(4), source), None);
+
+        assert_eq!(
+            tracker.observe(80.0, false, at(5), MovementSource::Api),
+            None
+        );
+        assert_eq!(tracker.observe(110.0, false, at(6), source), None);
+        assert_eq!(
+            tracker.observe(110.0, true, at(15), source),
+            Some((
+                110.0,
+                Some(Movement {
+                    started_at: at(5),
+                    ended_at: at(15),
+                    start_height: 72.5,
+                    end_height: 110.0,
+                    source: MovementSource::Api,
+                })
+            ))
+        );
+    }
+}
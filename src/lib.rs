@@ -1,504 +1,407 @@
+mod capture;
+mod controller;
+mod decoder;
+mod error;
+mod events;
+mod history;
+mod memory;
+mod metrics;
+mod motion;
+mod presets;
 mod protocol;
-
-#[cfg_attr(all(target_os = "linux", target_arch = "arm"), path = "rpi.rs")]
-#[cfg_attr(
-    not(all(target_os = "linux", target_arch = "arm")),
-    path = "not_rpi.rs"
-)]
-mod os;
+mod schedule;
+mod stall;
+pub mod transport;
 
 #[macro_use]
 extern crate lazy_static;
 
-pub use crate::protocol::DATA_FRAME_SIZE;
-use crate::protocol::{DeskToPanelMessage, PanelToDeskMessage};
-use crossbeam_channel::{select, unbounded};
-use log::{debug, info};
+pub use crate::capture::{
+    parse_capture, read_capture, CaptureError, CaptureWriter, CapturedBytes, CapturedFrame,
+    Direction,
+};
+pub use crate::controller::DeskController;
+pub use crate::decoder::FrameDecoder;
+pub use crate::error::DeskError;
+pub use crate::events::{Event, Subscription};
+pub use crate::history::{
+    History, HistoryError, Incident, IncidentKind, Movement, MovementSource, SitStand,
+    STANDING_MIN_HEIGHT_CM,
+};
+pub use crate::memory::{InvalidMemorySlotError, MemorySlot, PanelMemoryError, PanelMemorySlots};
+pub use crate::metrics::{
+    HeightBand, Histogram, Metrics, PassThroughLatency, HEIGHT_BAND_CM,
+    MOVEMENT_DURATION_BUCKETS_S, PASS_THROUGH_LATENCY_BUCKETS_S,
+};
+pub use crate::motion::{Coast, CoastProfile, CoastProfileError};
+pub use crate::presets::{PresetError, Presets};
+pub use crate::protocol::{
+    ChecksumPolicy, DataFrame, DeskToPanelMessage, FrameError, PanelToDeskMessage, DATA_FRAME_SIZE,
+};
+pub use crate::schedule::{Days, Schedule, ScheduleAction, ScheduleError, ScheduleRule};
+pub use crate::stall::StallConfig;
+use crate::transport::TransportKind;
+use serde::Serialize;
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::sync::RwLock;
-use std::thread::spawn;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+
+// The desk's physical range. Heights outside of it are always rejected,
+// and the configured range must lie within it.
+pub const MIN_DESK_HEIGHT_CM: f32 = 65.0;
+pub const MAX_DESK_HEIGHT_CM: f32 = 129.5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ControllerConfig {
+    // The range of heights that the controller will move the desk to
+    pub min_height_cm: f32,
+    pub max_height_cm: f32,
+    // How close to the target height the desk must be to be considered there
+    pub at_target_tolerance_cm: f32,
+    // How long after the last panel frame before the panel key is reset to None
+    pub panel_key_reset_timeout: Duration,
+    // How long the run loop waits for an interrupt before checking in
+    pub interrupt_timeout: Duration,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> ControllerConfig {
+        ControllerConfig {
+            min_height_cm: MIN_DESK_HEIGHT_CM,
+            max_height_cm: MAX_DESK_HEIGHT_CM,
+            at_target_tolerance_cm: 0.2,
+            panel_key_reset_timeout: Duration::from_millis(1000),
+            interrupt_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ControllerConfig {
+    pub fn validate(&self) -> Result<(), InvalidConfigError> {
+        for &(field, value) in &[
+            ("min_height_cm", self.min_height_cm),
+            ("max_height_cm", self.max_height_cm),
+        ] {
+            if !(MIN_DESK_HEIGHT_CM..=MAX_DESK_HEIGHT_CM).contains(&value) {
+                return Err(InvalidConfigError::new(
+                    field,
+                    format!(
+                        "{} is outside of the desk's range ({} to {})",
+                        value, MIN_DESK_HEIGHT_CM, MAX_DESK_HEIGHT_CM
+                    ),
+                ));
+            }
+        }
 
-const PANEL_KEY_RESET_TIMEOUT: Duration = Duration::from_millis(1000);
-const INTERRUPT_TIMEOUT_DURATION: Duration = Duration::from_secs(10);
+        if self.min_height_cm >= self.max_height_cm {
+            return Err(InvalidConfigError::new(
+                "min_height_cm",
+                format!(
+                    "{} must be less than max_height_cm ({})",
+                    self.min_height_cm, self.max_height_cm
+                ),
+            ));
+        }
+
+        // A tolerance below the frame resolution could never be met, and one above a few cm isn't a target
+        if !(0.1..=5.0).contains(&self.at_target_tolerance_cm) {
+            return Err(InvalidConfigError::new(
+                "at_target_tolerance_cm",
+                format!("{} must be between 0.1 and 5", self.at_target_tolerance_cm),
+            ));
+        }
 
-const MIN_DESK_HEIGHT_CM: f32 = 65.0;
-const MAX_DESK_HEIGHT_CM: f32 = 129.5;
+        for &(field, value) in &[
+            ("panel_key_reset_timeout", self.panel_key_reset_timeout),
+            ("interrupt_timeout", self.interrupt_timeout),
+        ] {
+            if value == Duration::from_secs(0) {
+                return Err(InvalidConfigError::new(field, "must not be zero"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidConfigError {
+    field: String,
+    message: String,
+}
+
+impl InvalidConfigError {
+    pub fn new<F: Into<String>, M: Into<String>>(field: F, message: M) -> InvalidConfigError {
+        InvalidConfigError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for InvalidConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Invalid {}: {}", self.field, self.message)
+    }
+}
+
+impl Error for InvalidConfigError {}
 
 #[derive(Debug)]
 pub struct InvalidHeightError {
     height: f32,
-    out_of_range: bool,
-    not_multiple_of_zero_point_five: bool,
+    min_height: f32,
+    max_height: f32,
+    cause: InvalidHeightCause,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InvalidHeightCause {
+    OutOfRange,
+    NotMultipleOfZeroPointFive,
 }
 
 impl InvalidHeightError {
-    fn new_out_of_range(height: f32) -> InvalidHeightError {
+    fn new(
+        height: f32,
+        min_height: f32,
+        max_height: f32,
+        cause: InvalidHeightCause,
+    ) -> InvalidHeightError {
         InvalidHeightError {
             height,
-            out_of_range: true,
-            not_multiple_of_zero_point_five: false,
+            min_height,
+            max_height,
+            cause,
         }
     }
-    fn new_not_multiple_of_zero_point_five(height: f32) -> InvalidHeightError {
-        InvalidHeightError {
-            height,
-            out_of_range: false,
-            not_multiple_of_zero_point_five: true,
-        }
+
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+
+    // The range the height was checked against
+    pub fn min_height(&self) -> f32 {
+        self.min_height
+    }
+
+    pub fn max_height(&self) -> f32 {
+        self.max_height
+    }
+
+    pub fn is_out_of_range(&self) -> bool {
+        self.cause == InvalidHeightCause::OutOfRange
+    }
+
+    pub fn is_not_multiple_of_zero_point_five(&self) -> bool {
+        self.cause == InvalidHeightCause::NotMultipleOfZeroPointFive
     }
 }
 
 impl Display for InvalidHeightError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        if self.out_of_range {
-            return write!(
+        match self.cause {
+            InvalidHeightCause::OutOfRange => write!(
                 f,
                 "Invalid height: {} - must be between {} and {}",
-                self.height, MIN_DESK_HEIGHT_CM, MAX_DESK_HEIGHT_CM
-            );
-        }
-
-        if self.not_multiple_of_zero_point_five {
-            return write!(
+                self.height, self.min_height, self.max_height
+            ),
+            InvalidHeightCause::NotMultipleOfZeroPointFive => write!(
                 f,
                 "Invalid height: {} - must be a multiple of 0.5 cm",
                 self.height
-            );
+            ),
         }
-
-        panic!("unrecognized InvalidHeightError cause")
     }
 }
 
 impl Error for InvalidHeightError {}
 
-lazy_static! {
-    static ref CURRENT_HEIGHT: RwLock<f32> = RwLock::new(0.0);
-    static ref TARGET_HEIGHT: RwLock<Option<f32>> = RwLock::new(None);
-    static ref CURRENT_PANEL_KEY: RwLock<Option<PanelToDeskMessage>> = RwLock::new(None);
-    static ref DESK_DROPPED_BYTE_COUNT: RwLock<usize> = RwLock::new(0);
-    static ref DESK_FOUND_FRAME_COUNT: RwLock<usize> = RwLock::new(0);
-    static ref PANEL_DROPPED_BYTE_COUNT: RwLock<usize> = RwLock::new(0);
-    static ref PANEL_FOUND_FRAME_COUNT: RwLock<usize> = RwLock::new(0);
-    static ref INTERRUPT_TX_RX: (
-        crossbeam_channel::Sender<()>,
-        crossbeam_channel::Receiver<()>,
-    ) = unbounded::<()>();
-}
-
-pub fn initialize() -> Result<(), Box<dyn Error>> {
-    os::initialize()
+// Heights must be within the desk's range, and a multiple of 0.5 cm
+fn validate_height(height_in_cm: f32) -> Result<(), InvalidHeightError> {
+    validate_height_within(height_in_cm, MIN_DESK_HEIGHT_CM, MAX_DESK_HEIGHT_CM)
 }
 
-// TODO: make private method, triggered by ctl_rx in run loop
-pub fn shutdown() -> Result<(), Box<dyn Error>> {
-    os::shutdown()
-}
-
-pub fn run(ctl_rx: crossbeam_channel::Receiver<bool>) -> Result<(), Box<dyn Error>> {
-    let (c1_tx, c1_rx) = unbounded::<bool>();
-    let (c2_tx, c2_rx) = unbounded::<bool>();
-    let (c3_tx, c3_rx) = unbounded::<bool>();
-    let (c4_tx, c4_rx) = unbounded::<bool>();
-
-    let (interrupt_tx, interrupt_rx) = INTERRUPT_TX_RX.clone();
-
-    spawn(move || {
-        loop {
-            // A frame takes about 7 ms to send and the desk sends one frame every 8 ms
-            // i.e. it pauses for about one ms between the end of one frame and the start of the next
-
-            select! {
-                recv(c3_rx) -> msg => {
-                    debug!("Run: received val on c3_rx: {:?}. Shutting down.", msg);
-                    return
-                    // TODO: shutdown
-                },
-                recv(interrupt_rx) -> _ =>{
-                    debug!("Run: received interrupt");
-                },
-                default(INTERRUPT_TIMEOUT_DURATION) => {
-                    debug!("Run: no interrupt received in {:?}", INTERRUPT_TIMEOUT_DURATION);
-                }
-            };
-
-            let current_height = current_height();
-            debug!("Run: current height: {:?}", current_height);
-
-            // TODO: handle situation(s) where desk isn't moving even though we're sending it a key
-            // - one situation is if we recently pressed another key
-            // - another situation is if we are too close to the target height
-            // - can resolve by sending a reset command
-
-            let panel_key = current_panel_key();
-            let target_height = target_height();
-
-            debug!(
-                "Run: Current height: {:?}. Target height: {:?}. Panel key: {:?}",
-                current_height, target_height, panel_key
-            );
-
-            let maybe_message_info =
-                calculate_panel_to_desk_message(panel_key, target_height, current_height)
-                    .expect("failed to calculate panel to desk message");
-
-            if let Some((message, times, reset_target_height)) = maybe_message_info {
-                debug!("Run: writing {:?} message {:?} times.", message, times);
-
-                for _ in 0..times {
-                    os::write_to_desk(message).expect("failed to write to desk");
-                }
-
-                if reset_target_height {
-                    info!("At target height of: {:?}.", target_height);
-                    debug!("Run: resetting target height to None");
-                    set_target_height(None);
-                } else if target_height.is_some() {
-                    debug!("Run: not yet at target_height - sending interrupt");
-                    interrupt_tx
-                        .send(())
-                        .expect("failed to send on INTERRUPT_TX_RX");
-                }
-            }
-        }
-    });
-
-    let (write_to_panel_tx, write_to_panel_rx) = unbounded::<DeskToPanelMessage>();
-
-    spawn(move || loop {
-        select! {
-        recv(c1_rx) -> _=> {
-            debug!("Received shutdown signal - exiting run (desk->panel) loop");
-            return
-        },
-        default => {
-            let (maybe_message,dropped_byte_count) = os::read_desk().expect("failed to read from desk");
-
-            increment_desk_dropped_byte_count(dropped_byte_count);
-
-            if let Some(message) = maybe_message {
-                increment_desk_found_frame_count(1);
-
-                match message {
-                    DeskToPanelMessage::Height(h) => {
-                        set_current_height(h);
-
-                        if !(MIN_DESK_HEIGHT_CM..=MAX_DESK_HEIGHT_CM).contains(&h){
-                            debug!(
-                                "received abnormal height from desk: {:?} - {:?}",
-                                h,
-                                message.as_frame()
-                            );
-                        }
-                    }
-                    _ => {
-                        debug!(
-                            "received other desk-to-panel message: {:?} - {:?}",
-                            message,
-                            message.as_frame()
-                        );
-                    }
-                }
-
-                write_to_panel_tx.send(message).expect("failed to send on write_to_panel_tx");
-            }
-        },
-        }
-    });
-
-    spawn(move || loop {
-        select! {
-            recv(c4_rx) -> _=> {
-                debug!("Received shutdown signal (c4_rx) - exiting run (desk->panel) loop");
-                return
-            },
-            recv(write_to_panel_rx) -> msg =>{
-                let message = msg.expect("failed to receive on write_to_panel_rx");
-                os::write_to_panel(message).expect("Failed to write to panel");
-            },
-        }
-    });
-
-    let (panel_to_desk_tx, panel_to_desk_rx) = unbounded::<(Option<PanelToDeskMessage>, usize)>();
-
-    // Keep this as a separate loop so that we can have a default timeout in the recv select loop
-    spawn(move || loop {
-        panel_to_desk_tx
-            .send(os::read_panel().expect("Failed to read from panel"))
-            .expect("Failed to send on panel_to_desk_tx");
-    });
-
-    spawn(move || loop {
-        select! {
-            recv(c2_rx) -> _=> {
-                debug!("Received shutdown signal - exiting run (panel->desk) loop");
-                return;
-            },
-            recv(panel_to_desk_rx) -> msg => {
-                let (maybe_message,dropped_byte_count) = msg.expect("failed to unpack panel->desk msg");
-
-                increment_panel_dropped_byte_count(dropped_byte_count);
-
-                if let Some(message) = maybe_message {
-                    increment_panel_found_frame_count(1);
-
-                    match message{
-                        PanelToDeskMessage::NoKey => {}
-                        _ => {
-                            debug!(
-                                "panel-to-desk message: {:?} - {:?}",
-                                message,
-                                message.as_frame()
-                            );
-                        },
-                    }
-
-                    set_current_panel_key(maybe_message);
-                }
-            },
-            default(PANEL_KEY_RESET_TIMEOUT) => {
-                let current_panel_key = current_panel_key();
-                if current_panel_key.is_some(){
-                    debug!("No panel key received in {:?} - resetting to None (from: {:?})",PANEL_KEY_RESET_TIMEOUT,current_panel_key);
-                    set_current_panel_key(None);
-                }
-            },
-        }
-    });
-
-    let x = ctl_rx.recv()?;
-    c1_tx.send(x)?;
-    c2_tx.send(x)?;
-    c3_tx.send(x)?;
-    c4_tx.send(x)?;
-
-    Ok(())
-}
-
-pub fn move_to_height(height_in_cm: f32) -> Result<(), InvalidHeightError> {
-    info!("Moving to height: {:?}", height_in_cm);
-
-    if !(MIN_DESK_HEIGHT_CM..=MAX_DESK_HEIGHT_CM).contains(&height_in_cm) {
-        return Err(InvalidHeightError::new_out_of_range(height_in_cm));
+fn validate_height_within(
+    height_in_cm: f32,
+    min_height: f32,
+    max_height: f32,
+) -> Result<(), InvalidHeightError> {
+    if !(min_height..=max_height).contains(&height_in_cm) {
+        return Err(InvalidHeightError::new(
+            height_in_cm,
+            min_height,
+            max_height,
+            InvalidHeightCause::OutOfRange,
+        ));
     }
 
     if (height_in_cm * 10.0) as usize % 5 != 0 {
-        return Err(InvalidHeightError::new_not_multiple_of_zero_point_five(
+        return Err(InvalidHeightError::new(
             height_in_cm,
+            min_height,
+            max_height,
+            InvalidHeightCause::NotMultipleOfZeroPointFive,
         ));
     }
 
-    set_target_height(Some(height_in_cm));
-
     Ok(())
 }
 
-pub fn clear_target_height() {
-    info!("Clearing target height");
-    set_target_height(None);
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct FrameCounts {
+    pub found_frames: usize,
+    pub dropped_bytes: usize,
+    pub checksum_failures: usize,
 }
 
-pub fn current_height() -> f32 {
-    *CURRENT_HEIGHT.read().unwrap()
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MotionState {
+    // Not driving the desk toward a target height
+    Idle,
+    // Driving the desk toward the target height
+    Moving,
+    // The desk stopped moving before reaching the target height and a recovery sequence has been sent
+    Recovering,
+    // The desk did not move despite recovery attempts. The target height has been cleared.
+    Stalled,
 }
 
-fn set_current_height(h: f32) {
-    *CURRENT_HEIGHT.write().unwrap() = h;
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    // Reads from or writes to the desk or panel are failing, but the controller is still running
+    Degraded,
+    // A worker thread has stopped. The controller has to be restarted.
+    Failed,
 }
 
-pub fn target_height() -> Option<f32> {
-    *TARGET_HEIGHT.read().unwrap()
+impl HealthStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HealthStatus::Ok => "ok",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Failed => "failed",
+        }
+    }
 }
 
-fn set_target_height(h: Option<f32>) {
-    *TARGET_HEIGHT.write().unwrap() = h;
-
-    let (tx, _) = INTERRUPT_TX_RX.clone();
-    tx.send(())
-        .expect("failed to send on INTERRUPT_TX_RX (target height)")
+// The latest frame from the desk that wasn't a height, e.g. a fault that the panel is showing.
+// Its opcode hasn't been identified, so it is kept as it was received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnknownDeskFrame {
+    pub message: DeskToPanelMessage,
+    pub received_at: SystemTime,
 }
 
-pub fn current_panel_key() -> Option<PanelToDeskMessage> {
-    *CURRENT_PANEL_KEY.read().unwrap()
+// How well the controller itself is working, as opposed to the desk
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Health {
+    pub status: HealthStatus,
+    // Workers whose last read or write failed
+    pub failing: Vec<&'static str>,
+    // Workers that stopped unexpectedly
+    pub stopped: Vec<&'static str>,
+    pub error_count: u64,
+    // Kept after recovering, to help work out what went wrong
+    pub last_error: Option<String>,
 }
 
-fn set_current_panel_key(key: Option<PanelToDeskMessage>) {
-    *CURRENT_PANEL_KEY.write().unwrap() = key;
+impl Health {
+    pub(crate) fn new() -> Health {
+        Health {
+            status: HealthStatus::Ok,
+            failing: Vec::new(),
+            stopped: Vec::new(),
+            error_count: 0,
+            last_error: None,
+        }
+    }
 
-    let (tx, _) = INTERRUPT_TX_RX.clone();
-    tx.send(())
-        .expect("failed to send on INTERRUPT_TX_RX (panel key)")
+    pub(crate) fn update_status(&mut self) {
+        self.status = if !self.stopped.is_empty() {
+            HealthStatus::Failed
+        } else if !self.failing.is_empty() {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Ok
+        };
+    }
 }
 
-pub fn desk_frame_counts() -> (usize, usize) {
-    (
-        *DESK_FOUND_FRAME_COUNT.read().unwrap(),
-        *DESK_DROPPED_BYTE_COUNT.read().unwrap(),
-    )
+lazy_static! {
+    static ref DEFAULT_CONTROLLER: RwLock<Option<DeskController>> = RwLock::new(None);
 }
 
-fn increment_desk_found_frame_count(u: usize) {
-    *DESK_FOUND_FRAME_COUNT.write().unwrap() += u;
-}
+// The free functions below are kept for backwards compatibility, with the signatures they had before
+// DeskController. They delegate to the controller created by `initialize`.
 
-fn increment_desk_dropped_byte_count(u: usize) {
-    *DESK_DROPPED_BYTE_COUNT.write().unwrap() += u;
+fn default_controller() -> DeskController {
+    DEFAULT_CONTROLLER
+        .read()
+        .unwrap()
+        .clone()
+        .expect("desk controller has not been initialized")
 }
 
-pub fn panel_frame_counts() -> (usize, usize) {
-    (
-        *PANEL_FOUND_FRAME_COUNT.read().unwrap(),
-        *PANEL_DROPPED_BYTE_COUNT.read().unwrap(),
-    )
-}
+// Opens the default transport (the UART on a Raspberry Pi, and the simulator elsewhere)
+pub fn initialize() -> Result<(), Box<dyn Error>> {
+    let controller = DeskController::new(TransportKind::default().open()?);
+    controller.initialize()?;
 
-fn increment_panel_found_frame_count(u: usize) {
-    *PANEL_FOUND_FRAME_COUNT.write().unwrap() += u;
-}
+    *DEFAULT_CONTROLLER.write().unwrap() = Some(controller);
 
-fn increment_panel_dropped_byte_count(u: usize) {
-    *PANEL_DROPPED_BYTE_COUNT.write().unwrap() += u;
+    Ok(())
 }
 
-fn calculate_panel_to_desk_message(
-    received_panel_key: Option<PanelToDeskMessage>,
-    target_height: Option<f32>,
-    current_height: f32,
-) -> Result<Option<(PanelToDeskMessage, usize, bool)>, Box<dyn Error>> {
-    match received_panel_key {
-        Some(PanelToDeskMessage::NoKey) => {
-            if target_height.is_none() {
-                return Ok(Some((PanelToDeskMessage::NoKey, 1, false)));
-            }
-        }
-        Some(key) => return Ok(Some((key, 1, false))),
-        None => {
-            // continue
-        }
-    }
-
-    let target_height = match target_height {
-        None => {
-            return Ok(None);
-        }
-        Some(t) => t,
-    };
-
-    // if target_height.is_none() {
-    //     return Ok(None);
-    // }
-    // let target_height = target_height.unwrap();
-
-    // TODO: test error windows. Also is there a better way to solve this problem?
-    let error_window = 0.5;
-
-    if current_height >= target_height - error_window
-        && current_height <= target_height + error_window
-    {
-        return Ok(Some((PanelToDeskMessage::NoKey, 200, true)));
-    }
-
-    if current_height < target_height - error_window {
-        return Ok(Some((PanelToDeskMessage::Up, 1, false)));
-    }
-
-    if current_height > target_height + error_window {
-        return Ok(Some((PanelToDeskMessage::Down, 1, false)));
-    }
+pub fn shutdown() -> Result<(), Box<dyn Error>> {
+    Ok(default_controller().shutdown()?)
+}
 
-    Ok(None)
+pub fn run(ctl_rx: crossbeam_channel::Receiver<bool>) -> Result<(), Box<dyn Error>> {
+    Ok(default_controller().run(ctl_rx)?)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_calculate_panel_to_desk_message_no_key_no_target_height() -> Result<(), Box<dyn Error>>
-    {
-        let current_height = 70.0;
-        assert_eq!(
-            calculate_panel_to_desk_message(None, None, current_height)?,
-            None,
-        );
-        Ok(())
-    }
+pub fn move_to_height(height_in_cm: f32) -> Result<(), InvalidHeightError> {
+    default_controller().move_to_height_from(height_in_cm, MovementSource::Api)
+}
 
-    #[test]
-    fn test_calculate_panel_to_desk_message_no_key_target_greater_than_current(
-    ) -> Result<(), Box<dyn Error>> {
-        let target_height = Some(100.0);
-        let current_height = 70.0;
-        assert_eq!(
-            calculate_panel_to_desk_message(None, target_height, current_height)?,
-            Some((PanelToDeskMessage::Up, 1, false))
-        );
-        Ok(())
-    }
+pub fn clear_target_height() {
+    default_controller().clear_target_height()
+}
 
-    #[test]
-    fn test_calculate_panel_to_desk_message_no_key_target_less_than_current(
-    ) -> Result<(), Box<dyn Error>> {
-        let target_height = Some(60.0);
-        let current_height = 70.0;
-        assert_eq!(
-            calculate_panel_to_desk_message(None, target_height, current_height)?,
-            Some((PanelToDeskMessage::Down, 1, false))
-        );
-        Ok(())
-    }
+pub fn current_height() -> f32 {
+    default_controller().current_height()
+}
 
-    #[test]
-    fn test_calculate_panel_to_desk_message_no_key_target_equal_to_current(
-    ) -> Result<(), Box<dyn Error>> {
-        let target_height = Some(70.0);
-        let current_height = 70.0;
-        assert_eq!(
-            calculate_panel_to_desk_message(None, target_height, current_height)?,
-            Some((PanelToDeskMessage::NoKey, 200, true))
-        );
-        Ok(())
-    }
+pub fn target_height() -> Option<f32> {
+    default_controller().target_height()
+}
 
-    #[test]
-    fn test_calculate_panel_to_desk_message_current_key_nokey_target_greater_than_current(
-    ) -> Result<(), Box<dyn Error>> {
-        let target_height = Some(100.0);
-        let current_height = 70.0;
-        let current_panel_key = Some(PanelToDeskMessage::NoKey);
-        assert_eq!(
-            calculate_panel_to_desk_message(current_panel_key, target_height, current_height)?,
-            Some((PanelToDeskMessage::Up, 1, false))
-        );
-        Ok(())
-    }
+pub fn current_panel_key() -> Option<PanelToDeskMessage> {
+    default_controller().current_panel_key()
+}
 
-    #[test]
-    fn test_calculate_panel_to_desk_message_current_key_nokey_no_target(
-    ) -> Result<(), Box<dyn Error>> {
-        let target_height = None;
-        let current_height = 70.0;
-        let current_panel_key = Some(PanelToDeskMessage::NoKey);
-        assert_eq!(
-            calculate_panel_to_desk_message(current_panel_key, target_height, current_height)?,
-            Some((PanelToDeskMessage::NoKey, 1, false))
-        );
-        Ok(())
-    }
+// (found frames, dropped bytes) - DeskController::desk_frame_counts also has the checksum failures
+pub fn desk_frame_counts() -> (usize, usize) {
+    let counts = default_controller().desk_frame_counts();
+    (counts.found_frames, counts.dropped_bytes)
+}
 
-    #[test]
-    fn test_calculate_panel_to_desk_message_current_key_other_target_greater_current(
-    ) -> Result<(), Box<dyn Error>> {
-        let target_height = Some(100.0);
-        let current_height = 70.0;
-        let current_panel_key = Some(PanelToDeskMessage::Two(120.0));
-        assert_eq!(
-            calculate_panel_to_desk_message(current_panel_key, target_height, current_height)?,
-            Some((PanelToDeskMessage::Two(120.0), 1, false))
-        );
-        Ok(())
-    }
+// (found frames, dropped bytes)
+pub fn panel_frame_counts() -> (usize, usize) {
+    let counts = default_controller().panel_frame_counts();
+    (counts.found_frames, counts.dropped_bytes)
 }
@@ -1,81 +1,74 @@
 #![feature(decl_macro)]
 
+mod config;
+mod mqtt;
+mod web;
+
+use crate::config::Config;
 use crossbeam_channel::unbounded;
-use rocket::*;
+use desk_controller::transport::{CapturingTransport, Transport};
+use desk_controller::DeskController;
+use std::env;
 use std::error::Error;
+use std::process;
+use std::sync::Arc;
 use std::thread::spawn;
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() {
     env_logger::init();
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", config::usage());
+        return;
+    }
+
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let config = Config::load(args, |name| env::var(name).ok())?;
+
+    let transport_kind = config.transport_kind();
+    println!("Using transport: {:?}", transport_kind);
+
+    let mut transport = transport_kind.open()?;
+    if let Some(path) = &config.files.capture {
+        println!("Capturing bytes to: {:?}", path);
+        transport = Arc::new(CapturingTransport::create(transport, path)?) as Arc<dyn Transport>;
+    }
+
+    let controller = DeskController::new(transport);
+    controller.set_controller_config(config.controller)?;
+
+    controller.set_coast_profile_path(&config.files.coast_profile)?;
+    controller.set_presets_path(&config.files.presets)?;
+    controller.set_panel_memory_path(&config.files.panel_memory)?;
+    controller.set_schedule_path(&config.files.schedule)?;
+    controller.set_history_path(&config.files.history)?;
+
+    // Built up front so that a bad address fails before the desk is touched
+    let rocket = web::rocket(controller.clone(), &config.web)?;
+
     let (ctl_tx, ctl_rx) = unbounded::<bool>();
 
+    // `run` returns once shutdown is complete, which ends the process
     ctrlc::set_handler(move || {
         println!("received kill signal");
-        desk_controller::shutdown().expect("Failed to shutdown");
-
         ctl_tx.send(true).expect("Failed to send shutdown signal");
-        // TODO: wait for acknowledgement from run loops
-
-        std::process::exit(0);
     })
     .expect("Error setting Ctrl-C handler");
 
-    desk_controller::initialize()?;
+    controller.initialize()?;
+
+    mqtt::start(controller.clone(), &config.mqtt)?;
 
     spawn(move || {
-        rocket::ignite()
-            .mount(
-                "/",
-                routes![
-                    web::index,
-                    web::current_height,
-                    web::move_desk,
-                    web::clear_target_height
-                ],
-            )
-            .launch();
+        rocket.launch();
     });
 
-    desk_controller::run(ctl_rx)
-}
-
-mod web {
-    use desk_controller::DATA_FRAME_SIZE;
-    use rocket::response::status::BadRequest;
-    use rocket::*;
-
-    #[get("/")]
-    pub fn index() -> String {
-        let (desk_found_frames, desk_dropped_bytes) = desk_controller::desk_frame_counts();
-        let (panel_found_frames, panel_dropped_bytes) = desk_controller::panel_frame_counts();
-
-        format!(
-            "Current Height: {:?} cm\nTarget Height: {:?} cm\nCurrent Panel Key: {:?}\nDesk - frames found: {:?}, bytes dropped: {:?} ({:?}%)\nPanel - frames found: {:?}, bytes dropped: {:?} ({:?}%)",
-            desk_controller::current_height(),
-            desk_controller::target_height(),
-            desk_controller::current_panel_key(),
-            desk_found_frames,
-            desk_dropped_bytes,
-            100.0*desk_dropped_bytes as f32 / (desk_found_frames*DATA_FRAME_SIZE + desk_dropped_bytes) as f32,
-            panel_found_frames,
-            panel_dropped_bytes,
-            100.0*panel_dropped_bytes as f32 / (panel_found_frames *DATA_FRAME_SIZE+ panel_dropped_bytes) as f32,
-        )
-    }
-
-    #[get("/move_desk/<target_height>")]
-    pub fn move_desk(target_height: f32) -> Result<(), BadRequest<String>> {
-        desk_controller::move_to_height(target_height).map_err(|e| BadRequest(Some(e.to_string())))
-    }
-
-    #[get("/clear_target_height")]
-    pub fn clear_target_height() {
-        desk_controller::clear_target_height()
-    }
-
-    #[get("/current_height")]
-    pub fn current_height() -> String {
-        format!("{}", desk_controller::current_height())
-    }
+    Ok(controller.run(ctl_rx)?)
 }
@@ -0,0 +1,353 @@
+// The panel's One/Two/Three memory slots.
+// Recalling a slot sends its height with every frame (e.g. One(110.0)), and storing the current height
+// in a slot sends ResetOne/Two/Three. The controller learns the slots from these frames as they pass through,
+// and can program a slot itself: it rewrites recalled heights, and moves the desk to the new height
+// before sending the store message. Programmed slots are saved, so that recalls are still rewritten
+// after a restart.
+
+use crate::protocol::PanelToDeskMessage;
+use crate::{validate_height_within, InvalidHeightError};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemorySlot {
+    One,
+    Two,
+    Three,
+}
+
+impl MemorySlot {
+    pub fn recall_message(self, height_in_cm: f32) -> PanelToDeskMessage {
+        match self {
+            MemorySlot::One => PanelToDeskMessage::One(height_in_cm),
+            MemorySlot::Two => PanelToDeskMessage::Two(height_in_cm),
+            MemorySlot::Three => PanelToDeskMessage::Three(height_in_cm),
+        }
+    }
+
+    pub fn store_message(self) -> PanelToDeskMessage {
+        match self {
+            MemorySlot::One => PanelToDeskMessage::ResetOne,
+            MemorySlot::Two => PanelToDeskMessage::ResetTwo,
+            MemorySlot::Three => PanelToDeskMessage::ResetThree,
+        }
+    }
+}
+
+impl FromStr for MemorySlot {
+    type Err = InvalidMemorySlotError;
+
+    fn from_str(s: &str) -> Result<MemorySlot, InvalidMemorySlotError> {
+        match s {
+            "one" | "1" => Ok(MemorySlot::One),
+            "two" | "2" => Ok(MemorySlot::Two),
+            "three" | "3" => Ok(MemorySlot::Three),
+            _ => Err(InvalidMemorySlotError(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidMemorySlotError(String);
+
+impl Display for InvalidMemorySlotError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid memory slot: {} - must be one, two or three",
+            self.0
+        )
+    }
+}
+
+impl Error for InvalidMemorySlotError {}
+
+// The height stored in each slot, if known
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PanelMemorySlots {
+    pub one: Option<f32>,
+    pub two: Option<f32>,
+    pub three: Option<f32>,
+}
+
+impl PanelMemorySlots {
+    pub fn get(&self, slot: MemorySlot) -> Option<f32> {
+        match slot {
+            MemorySlot::One => self.one,
+            MemorySlot::Two => self.two,
+            MemorySlot::Three => self.three,
+        }
+    }
+
+    pub(crate) fn set(&mut self, slot: MemorySlot, height_in_cm: Option<f32>) {
+        match slot {
+            MemorySlot::One => self.one = height_in_cm,
+            MemorySlot::Two => self.two = height_in_cm,
+            MemorySlot::Three => self.three = height_in_cm,
+        }
+    }
+
+    // Slots that aren't set are left out, e.g.
+    // one = 72.5
+    // three = 110.0
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        min_height: f32,
+        max_height: f32,
+    ) -> Result<PanelMemorySlots, PanelMemoryError> {
+        let contents = fs::read_to_string(path).map_err(PanelMemoryError::Storage)?;
+        PanelMemorySlots::from_toml(&contents, min_height, max_height)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PanelMemoryError> {
+        let contents =
+            toml::to_string(self).map_err(|e| PanelMemoryError::InvalidFile(e.to_string()))?;
+        fs::write(path, contents).map_err(PanelMemoryError::Storage)
+    }
+
+    fn from_toml(
+        contents: &str,
+        min_height: f32,
+        max_height: f32,
+    ) -> Result<PanelMemorySlots, PanelMemoryError> {
+        let slots = toml::from_str::<PanelMemorySlots>(contents)
+            .map_err(|e| PanelMemoryError::InvalidFile(e.to_string()))?;
+
+        for h in [slots.one, slots.two, slots.three].iter().flatten() {
+            validate_height_within(*h, min_height, max_height)
+                .map_err(PanelMemoryError::InvalidHeight)?;
+        }
+
+        Ok(slots)
+    }
+}
+
+#[derive(Debug)]
+pub enum PanelMemoryError {
+    InvalidHeight(InvalidHeightError),
+    InvalidFile(String),
+    Storage(io::Error),
+}
+
+impl Display for PanelMemoryError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PanelMemoryError::InvalidHeight(e) => write!(f, "Invalid panel memory slot: {}", e),
+            PanelMemoryError::InvalidFile(message) => {
+                write!(f, "Invalid panel memory file: {}", message)
+            }
+            PanelMemoryError::Storage(e) => {
+                write!(f, "Failed to read or write panel memory: {}", e)
+            }
+        }
+    }
+}
+
+impl Error for PanelMemoryError {}
+
+#[derive(Debug, Default)]
+pub(crate) struct PanelMemory {
+    // What we know (or have programmed) each slot to be
+    slots: PanelMemorySlots,
+    // Slots programmed by the controller, which may not match what the panel has stored
+    programmed: PanelMemorySlots,
+    // A slot to store once the desk reaches the given height
+    pending_store: Option<(MemorySlot, f32)>,
+}
+
+impl PanelMemory {
+    pub(crate) fn slots(&self) -> PanelMemorySlots {
+        self.slots
+    }
+
+    pub(crate) fn programmed(&self) -> PanelMemorySlots {
+        self.programmed
+    }
+
+    // Restores slots programmed before a restart (without storing them on the panel again)
+    pub(crate) fn restore_programmed(&mut self, programmed: PanelMemorySlots) {
+        for slot in [MemorySlot::One, MemorySlot::Two, MemorySlot::Three].iter() {
+            if let Some(h) = programmed.get(*slot) {
+                self.slots.set(*slot, Some(h));
+            }
+        }
+        self.programmed = programmed;
+    }
+
+    // Updates the slots from a message sent by the panel.
+    // Returns the message to forward to the desk, which is rewritten if the slot has been programmed.
+    pub(crate) fn observe(
+        &mut self,
+        message: PanelToDeskMessage,
+        current_height: f32,
+    ) -> PanelToDeskMessage {
+        let (slot, recalled_height) = match message {
+            PanelToDeskMessage::One(h) => (MemorySlot::One, Some(h)),
+            PanelToDeskMessage::Two(h) => (MemorySlot::Two, Some(h)),
+            PanelToDeskMessage::Three(h) => (MemorySlot::Three, Some(h)),
+            PanelToDeskMessage::ResetOne => (MemorySlot::One, None),
+            PanelToDeskMessage::ResetTwo => (MemorySlot::Two, None),
+            PanelToDeskMessage::ResetThree => (MemorySlot::Three, None),
+            _ => return message,
+        };
+
+        match (recalled_height, self.programmed.get(slot)) {
+            // The panel is recalling a slot that we've programmed
+            (Some(_), Some(programmed_height)) => slot.recall_message(programmed_height),
+            (Some(h), None) => {
+                self.slots.set(slot, Some(h));
+                message
+            }
+            // The panel has stored the current height, replacing anything we programmed
+            (None, _) => {
+                self.slots.set(slot, Some(current_height));
+                self.programmed.set(slot, None);
+                message
+            }
+        }
+    }
+
+    pub(crate) fn program(&mut self, slot: MemorySlot, height_in_cm: f32) {
+        self.slots.set(slot, Some(height_in_cm));
+        self.programmed.set(slot, Some(height_in_cm));
+        self.pending_store = Some((slot, height_in_cm));
+    }
+
+    pub(crate) fn has_pending_store(&self) -> bool {
+        self.pending_store.is_some()
+    }
+
+    // The slot to store once the desk is at rest, and the height that it should be at
+    pub(crate) fn take_pending_store(&mut self) -> Option<(MemorySlot, f32)> {
+        self.pending_store.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_slot_from_str() {
+        assert_eq!("one".parse::<MemorySlot>().unwrap(), MemorySlot::One);
+        assert_eq!("2".parse::<MemorySlot>().unwrap(), MemorySlot::Two);
+        assert_eq!("three".parse::<MemorySlot>().unwrap(), MemorySlot::Three);
+        assert!("four".parse::<MemorySlot>().is_err());
+    }
+
+    #[test]
+    fn test_panel_memory_learns_slots() {
+        let mut memory = PanelMemory::default();
+
+        assert_eq!(
+            memory.observe(PanelToDeskMessage::Two(110.0), 80.0),
+            PanelToDeskMessage::Two(110.0)
+        );
+        assert_eq!(
+            memory.observe(PanelToDeskMessage::ResetThree, 80.0),
+            PanelToDeskMessage::ResetThree
+        );
+        assert_eq!(
+            memory.observe(PanelToDeskMessage::Up, 80.0),
+            PanelToDeskMessage::Up
+        );
+
+        assert_eq!(
+            memory.slots(),
+            PanelMemorySlots {
+                one: None,
+                two: Some(110.0),
+                three: Some(80.0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_panel_memory_programmed_slots() {
+        let mut memory = PanelMemory::default();
+
+        memory.program(MemorySlot::One, 100.0);
+        assert_eq!(memory.slots().one, Some(100.0));
+
+        // Recalling a programmed slot moves to the programmed height, whatever the panel has stored
+        assert_eq!(
+            memory.observe(PanelToDeskMessage::One(90.0), 80.0),
+            PanelToDeskMessage::One(100.0)
+        );
+        assert_eq!(memory.slots().one, Some(100.0));
+
+        assert!(memory.has_pending_store());
+        assert_eq!(memory.take_pending_store(), Some((MemorySlot::One, 100.0)));
+        assert!(!memory.has_pending_store());
+
+        // Storing a height on the panel replaces the programmed height
+        memory.observe(PanelToDeskMessage::ResetOne, 85.0);
+        assert_eq!(
+            memory.observe(PanelToDeskMessage::One(85.0), 85.0),
+            PanelToDeskMessage::One(85.0)
+        );
+        assert_eq!(memory.slots().one, Some(85.0));
+        assert_eq!(memory.programmed(), PanelMemorySlots::default());
+    }
+
+    #[test]
+    fn test_panel_memory_restore_programmed() {
+        let mut memory = PanelMemory::default();
+        memory.observe(PanelToDeskMessage::Two(90.0), 80.0);
+
+        memory.restore_programmed(PanelMemorySlots {
+            one: Some(100.0),
+            two: None,
+            three: None,
+        });
+        assert_eq!(
+            memory.slots(),
+            PanelMemorySlots {
+                one: Some(100.0),
+                two: Some(90.0),
+                three: None,
+            }
+        );
+        assert!(!memory.has_pending_store());
+        assert_eq!(
+            memory.observe(PanelToDeskMessage::One(70.0), 80.0),
+            PanelToDeskMessage::One(100.0)
+        );
+    }
+
+    #[test]
+    fn test_panel_memory_slots_toml() {
+        let slots = PanelMemorySlots {
+            one: Some(72.5),
+            two: None,
+            three: Some(110.0),
+        };
+        let contents = toml::to_string(&slots).unwrap();
+        assert_eq!(contents, "one = 72.5\nthree = 110.0\n");
+        assert_eq!(
+            PanelMemorySlots::from_toml(&contents, 65.0, 129.0).unwrap(),
+            slots
+        );
+        assert_eq!(
+            PanelMemorySlots::from_toml("", 65.0, 129.0).unwrap(),
+            PanelMemorySlots::default()
+        );
+
+        assert!(matches!(
+            PanelMemorySlots::from_toml("one = 120.0", 65.0, 110.0),
+            Err(PanelMemoryError::InvalidHeight(_))
+        ));
+        assert!(matches!(
+            PanelMemorySlots::from_toml("one = \"high\"", 65.0, 129.0),
+            Err(PanelMemoryError::InvalidFile(_))
+        ));
+    }
+}
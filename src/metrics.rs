@@ -0,0 +1,280 @@
+// In-memory counters for monitoring, kept from when the controller starts.
+// Unlike the history, these don't need a database and are cheap enough to update with every frame.
+
+use crate::capture::Direction;
+use crate::history::{IncidentKind, Movement, MovementSource};
+use crate::{MAX_DESK_HEIGHT_CM, MIN_DESK_HEIGHT_CM};
+use std::time::{Duration, Instant};
+
+// Upper bounds of the movement duration histogram buckets, in seconds
+pub const MOVEMENT_DURATION_BUCKETS_S: [f64; 8] = [1.0, 2.0, 5.0, 10.0, 15.0, 20.0, 30.0, 60.0];
+
+// Upper bounds of the pass-through latency histogram buckets, in seconds.
+// At 9600 baud a frame takes about 8 ms to send, so anything much over that is delay we've added.
+pub const PASS_THROUGH_LATENCY_BUCKETS_S: [f64; 10] =
+    [0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+// Time is counted in height bands of this width, starting from the desk's lowest height
+pub const HEIGHT_BAND_CM: f32 = 5.0;
+
+const MOVEMENT_SOURCES: [MovementSource; 3] = [
+    MovementSource::Panel,
+    MovementSource::Api,
+    MovementSource::Schedule,
+];
+
+const DIRECTIONS: [Direction; 2] = [Direction::DeskToPanel, Direction::PanelToDesk];
+
+const INCIDENT_KINDS: [IncidentKind; 3] = [
+    IncidentKind::StallRecovery,
+    IncidentKind::Stall,
+    IncidentKind::Error,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    pub upper_bounds: &'static [f64],
+    // Cumulative counts, one per upper bound
+    pub buckets: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new(upper_bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            upper_bounds,
+            buckets: vec![0; upper_bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, &upper_bound) in self.buckets.iter_mut().zip(self.upper_bounds) {
+            if value <= upper_bound {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeightBand {
+    pub min_height_cm: f32,
+    pub max_height_cm: f32,
+    pub time: Duration,
+}
+
+// How long frames took to pass through the controller, from being read from one side
+// to being written to the other. All in seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassThroughLatency {
+    pub direction: Direction,
+    pub histogram: Histogram,
+    pub max: f64,
+    // The mean difference between the latencies of consecutive frames
+    pub jitter: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metrics {
+    pub movement_durations: Vec<(MovementSource, Histogram)>,
+    pub incidents: Vec<(IncidentKind, u64)>,
+    pub height_bands: Vec<HeightBand>,
+    pub pass_through_latencies: Vec<PassThroughLatency>,
+}
+
+#[derive(Debug)]
+pub(crate) struct MetricsRecorder {
+    metrics: Metrics,
+    last_height: Option<(f32, Instant)>,
+    // The last latency in each direction, and the sum of the differences between consecutive latencies
+    latency_differences: Vec<(Option<f64>, f64)>,
+}
+
+impl MetricsRecorder {
+    pub(crate) fn new() -> MetricsRecorder {
+        let band_count =
+            ((MAX_DESK_HEIGHT_CM - MIN_DESK_HEIGHT_CM) / HEIGHT_BAND_CM).ceil() as usize;
+
+        MetricsRecorder {
+            metrics: Metrics {
+                movement_durations: MOVEMENT_SOURCES
+                    .iter()
+                    .map(|&source| (source, Histogram::new(&MOVEMENT_DURATION_BUCKETS_S)))
+                    .collect(),
+                incidents: INCIDENT_KINDS.iter().map(|&kind| (kind, 0)).collect(),
+                height_bands: (0..band_count)
+                    .map(|i| {
+                        let min_height_cm = MIN_DESK_HEIGHT_CM + i as f32 * HEIGHT_BAND_CM;
+                        HeightBand {
+                            min_height_cm,
+                            max_height_cm: min_height_cm + HEIGHT_BAND_CM,
+                            time: Duration::from_secs(0),
+                        }
+                    })
+                    .collect(),
+                pass_through_latencies: DIRECTIONS
+                    .iter()
+                    .map(|&direction| PassThroughLatency {
+                        direction,
+                        histogram: Histogram::new(&PASS_THROUGH_LATENCY_BUCKETS_S),
+                        max: 0.0,
+                        jitter: 0.0,
+                    })
+                    .collect(),
+            },
+            last_height: None,
+            latency_differences: vec![(None, 0.0); DIRECTIONS.len()],
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    // The time since the last height is counted towards the band that height was in
+    pub(crate) fn observe_height(&mut self, height: f32, now: Instant) {
+        if let Some((last_height, last_at)) = self.last_height {
+            let bands = &mut self.metrics.height_bands;
+            let i = ((last_height - MIN_DESK_HEIGHT_CM) / HEIGHT_BAND_CM).floor() as isize;
+            // Heights outside of the desk's range are counted in the nearest band
+            let i = i.max(0).min(bands.len() as isize - 1) as usize;
+            bands[i].time += now.saturating_duration_since(last_at);
+        }
+
+        self.last_height = Some((height, now));
+    }
+
+    pub(crate) fn record_movement(&mut self, movement: &Movement) {
+        let duration = movement.duration().as_secs_f64();
+
+        if let Some((_, histogram)) = self
+            .metrics
+            .movement_durations
+            .iter_mut()
+            .find(|(source, _)| *source == movement.source)
+        {
+            histogram.observe(duration);
+        }
+    }
+
+    pub(crate) fn record_pass_through(&mut self, direction: Direction, latency: Duration) {
+        let latency = latency.as_secs_f64();
+
+        let i = DIRECTIONS.iter().position(|&d| d == direction).unwrap();
+        let stats = &mut self.metrics.pass_through_latencies[i];
+        let (last, difference_sum) = &mut self.latency_differences[i];
+
+        stats.histogram.observe(latency);
+        stats.max = stats.max.max(latency);
+
+        if let Some(last) = last.replace(latency) {
+            *difference_sum += (latency - last).abs();
+            stats.jitter = *difference_sum / (stats.histogram.count - 1) as f64;
+        }
+    }
+
+    pub(crate) fn record_incident(&mut self, kind: IncidentKind) {
+        if let Some((_, count)) = self.metrics.incidents.iter_mut().find(|(k, _)| *k == kind) {
+            *count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_metrics_movement_durations() {
+        let mut recorder = MetricsRecorder::new();
+
+        let started_at = SystemTime::now();
+        for &seconds in &[0.5, 4.0, 12.0, 90.0] {
+            recorder.record_movement(&Movement {
+                started_at,
+                ended_at: started_at + Duration::from_secs_f64(seconds),
+                start_height: 75.0,
+                end_height: 110.0,
+                source: MovementSource::Api,
+            });
+        }
+        recorder.record_incident(IncidentKind::Stall);
+
+        let metrics = recorder.metrics();
+        let (source, histogram) = &metrics.movement_durations[1];
+        assert_eq!(*source, MovementSource::Api);
+        assert_eq!(histogram.buckets, vec![1, 1, 2, 2, 3, 3, 3, 3]);
+        assert_eq!(histogram.count, 4);
+        assert!((histogram.sum - 106.5).abs() < 1e-6);
+
+        assert_eq!(metrics.movement_durations[0].1.count, 0);
+        assert_eq!(
+            metrics.incidents,
+            vec![
+                (IncidentKind::StallRecovery, 0),
+                (IncidentKind::Stall, 1),
+                (IncidentKind::Error, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metrics_pass_through_latencies() {
+        let mut recorder = MetricsRecorder::new();
+
+        for &ms in &[3, 9, 5, 400] {
+            recorder.record_pass_through(Direction::PanelToDesk, Duration::from_millis(ms));
+        }
+
+        let metrics = recorder.metrics();
+        let latency = &metrics.pass_through_latencies[1];
+        assert_eq!(latency.direction, Direction::PanelToDesk);
+        assert_eq!(
+            latency.histogram.buckets,
+            vec![0, 0, 2, 3, 3, 3, 3, 3, 4, 4]
+        );
+        assert_eq!(latency.histogram.count, 4);
+        assert!((latency.max - 0.4).abs() < 1e-9);
+        // (6 + 4 + 395) / 3 ms
+        assert!((latency.jitter - 0.135).abs() < 1e-9);
+
+        assert_eq!(metrics.pass_through_latencies[0].histogram.count, 0);
+        assert_eq!(
+            metrics.pass_through_latencies[0].jitter.to_bits(),
+            0f64.to_bits()
+        );
+    }
+
+    #[test]
+    fn test_metrics_height_bands() {
+        let mut recorder = MetricsRecorder::new();
+        let start = Instant::now();
+
+        recorder.observe_height(72.0, start);
+        recorder.observe_height(72.5, start + Duration::from_secs(10));
+        recorder.observe_height(110.0, start + Duration::from_secs(30));
+        recorder.observe_height(0.0, start + Duration::from_secs(35));
+        recorder.observe_height(75.0, start + Duration::from_secs(36));
+
+        let bands = recorder.metrics().height_bands;
+        assert_eq!(bands.len(), 13);
+        assert_eq!(bands[0].min_height_cm.to_bits(), 65f32.to_bits());
+        assert_eq!(bands[12].max_height_cm.to_bits(), 130f32.to_bits());
+
+        // 65-70 (the nearest band to 0), 70-75, 110-115
+        assert_eq!(bands[0].time, Duration::from_secs(1));
+        assert_eq!(bands[1].time, Duration::from_secs(30));
+        assert_eq!(bands[9].time, Duration::from_secs(5));
+        assert_eq!(
+            bands.iter().map(|band| band.time).sum::<Duration>(),
+            Duration::from_secs(36)
+        );
+    }
+}
@@ -0,0 +1,297 @@
+// Estimates how fast the desk is moving, and how far it keeps moving (coasts) after the key is released.
+// The controller uses these to release the key early so that the desk settles on the target height.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Velocity is estimated over readings received within this window
+const VELOCITY_WINDOW: Duration = Duration::from_millis(300);
+
+// The desk is considered to have stopped if the height hasn't changed for the whole window
+const SETTLED_TOLERANCE_CM: f32 = 0.05;
+
+// How much weight a new coast measurement has relative to what's already been learned
+const LEARNING_RATE: f32 = 0.5;
+
+// Roughly the speed of the desk when moving at full speed
+const DEFAULT_SPEED_CM_PER_S: f32 = 3.8;
+
+// Matches the fixed error window that was used before coast distances were learned
+const DEFAULT_COAST_DISTANCE_CM: f32 = 0.5;
+
+#[derive(Debug, Default)]
+pub struct VelocityEstimator {
+    readings: VecDeque<(Instant, f32)>,
+}
+
+impl VelocityEstimator {
+    pub fn new() -> VelocityEstimator {
+        VelocityEstimator {
+            readings: VecDeque::new(),
+        }
+    }
+
+    pub fn observe(&mut self, height: f32, now: Instant) {
+        self.readings.push_back((now, height));
+
+        while let Some(&(at, _)) = self.readings.front() {
+            if now.duration_since(at) > VELOCITY_WINDOW {
+                self.readings.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Velocity in cm/s. Positive is up.
+    pub fn velocity(&self) -> f32 {
+        match (self.readings.front(), self.readings.back()) {
+            (Some(&(first_at, first_height)), Some(&(last_at, last_height))) => {
+                let elapsed = last_at.duration_since(first_at).as_secs_f32();
+                if elapsed > 0.0 {
+                    (last_height - first_height) / elapsed
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        }
+    }
+
+    // Whether the height has stayed the same for (almost) the whole window
+    pub fn is_settled(&self) -> bool {
+        let (first_at, first_height) = match self.readings.front() {
+            Some(&reading) => reading,
+            None => return false,
+        };
+        let last_at = self.readings.back().unwrap().0;
+
+        last_at.duration_since(first_at) >= VELOCITY_WINDOW * 3 / 4
+            && self
+                .readings
+                .iter()
+                .all(|&(_, h)| (h - first_height).abs() < SETTLED_TOLERANCE_CM)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Coast {
+    // How far the desk moved after the key was released...
+    pub distance_cm: f32,
+    // ...when it was moving at this speed
+    pub speed_cm_per_s: f32,
+}
+
+impl Coast {
+    // Predicts how far the desk will coast if the key is released at `speed`.
+    // The desk decelerates at a roughly constant rate, so the distance grows with the square of the speed.
+    pub fn predict(&self, speed_cm_per_s: f32) -> f32 {
+        if self.speed_cm_per_s <= 0.0 {
+            return self.distance_cm;
+        }
+
+        let ratio = speed_cm_per_s.abs() / self.speed_cm_per_s;
+        self.distance_cm * ratio * ratio
+    }
+
+    fn learn(&mut self, measured: Coast) {
+        self.distance_cm += LEARNING_RATE * (measured.distance_cm - self.distance_cm);
+        self.speed_cm_per_s += LEARNING_RATE * (measured.speed_cm_per_s - self.speed_cm_per_s);
+    }
+}
+
+impl Default for Coast {
+    fn default() -> Coast {
+        Coast {
+            distance_cm: DEFAULT_COAST_DISTANCE_CM,
+            speed_cm_per_s: DEFAULT_SPEED_CM_PER_S,
+        }
+    }
+}
+
+// A direction missing from the file is left at the default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CoastProfile {
+    pub up: Coast,
+    pub down: Coast,
+}
+
+impl CoastProfile {
+    // Predicts how far the desk will coast if the key is released at `velocity` (positive is up)
+    pub fn predict(&self, velocity: f32) -> f32 {
+        if velocity >= 0.0 {
+            self.up.predict(velocity)
+        } else {
+            self.down.predict(velocity)
+        }
+    }
+
+    // Updates the profile after the desk was released at `released_velocity` and coasted `distance_cm`
+    pub fn learn(&mut self, released_velocity: f32, distance_cm: f32) {
+        let measured = Coast {
+            distance_cm: distance_cm.max(0.0),
+            speed_cm_per_s: released_velocity.abs(),
+        };
+
+        if released_velocity >= 0.0 {
+            self.up.learn(measured);
+        } else {
+            self.down.learn(measured);
+        }
+    }
+
+    // e.g.
+    // [up]
+    // distance_cm = 0.48
+    // speed_cm_per_s = 3.8
+    //
+    // [down]
+    // distance_cm = 0.52
+    // speed_cm_per_s = 3.75
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<CoastProfile, CoastProfileError> {
+        let contents = fs::read_to_string(path).map_err(CoastProfileError::Storage)?;
+        CoastProfile::from_toml(&contents)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CoastProfileError> {
+        let contents =
+            toml::to_string(self).map_err(|e| CoastProfileError::Invalid(e.to_string()))?;
+        fs::write(path, contents).map_err(CoastProfileError::Storage)
+    }
+
+    fn from_toml(contents: &str) -> Result<CoastProfile, CoastProfileError> {
+        let profile = toml::from_str::<CoastProfile>(contents)
+            .map_err(|e| CoastProfileError::Invalid(e.to_string()))?;
+
+        for coast in &[profile.up, profile.down] {
+            if !coast.distance_cm.is_finite()
+                || coast.distance_cm < 0.0
+                || !coast.speed_cm_per_s.is_finite()
+            {
+                return Err(CoastProfileError::Invalid(format!(
+                    "{:?} - distance must be at least 0, and both must be finite",
+                    coast
+                )));
+            }
+        }
+
+        Ok(profile)
+    }
+}
+
+#[derive(Debug)]
+pub enum CoastProfileError {
+    Invalid(String),
+    Storage(io::Error),
+}
+
+impl Display for CoastProfileError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CoastProfileError::Invalid(message) => write!(f, "Invalid coast profile: {}", message),
+            CoastProfileError::Storage(e) => {
+                write!(f, "Failed to read or write coast profile: {}", e)
+            }
+        }
+    }
+}
+
+impl Error for CoastProfileError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_velocity_estimator() {
+        let mut estimator = VelocityEstimator::new();
+        let start = Instant::now();
+        assert!(estimator.velocity().abs() < 0.001);
+        assert!(!estimator.is_settled());
+
+        // 4 cm/s up, with a reading every 8 ms
+        for i in 0..100 {
+            estimator.observe(
+                80.0 + 0.032 * i as f32,
+                start + Duration::from_millis(8 * i),
+            );
+        }
+        assert!((estimator.velocity() - 4.0).abs() < 0.01);
+        assert!(!estimator.is_settled());
+
+        // stopped
+        let stopped_at = start + Duration::from_millis(800);
+        for i in 0..100 {
+            estimator.observe(84.0, stopped_at + Duration::from_millis(8 * i));
+        }
+        assert!(estimator.velocity().abs() < 0.001);
+        assert!(estimator.is_settled());
+    }
+
+    #[test]
+    fn test_coast_predict() {
+        let coast = Coast {
+            distance_cm: 0.5,
+            speed_cm_per_s: 4.0,
+        };
+
+        assert!((coast.predict(4.0) - 0.5).abs() < 0.001);
+        assert!((coast.predict(-4.0) - 0.5).abs() < 0.001);
+        assert!((coast.predict(2.0) - 0.125).abs() < 0.001);
+        assert!(coast.predict(0.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_coast_profile_learn() {
+        let mut profile = CoastProfile::default();
+
+        profile.learn(4.0, 0.7);
+        assert_eq!(profile.down, Coast::default());
+        assert!((profile.up.distance_cm - 0.6).abs() < 0.001);
+        assert!((profile.up.speed_cm_per_s - 3.9).abs() < 0.001);
+
+        profile.learn(-3.8, 0.3);
+        assert!((profile.down.distance_cm - 0.4).abs() < 0.001);
+        assert!((profile.down.speed_cm_per_s - 3.8).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_coast_profile_from_toml() {
+        let profile = CoastProfile {
+            up: Coast {
+                distance_cm: 0.48,
+                speed_cm_per_s: 3.8,
+            },
+            down: Coast {
+                distance_cm: 0.52,
+                speed_cm_per_s: 3.75,
+            },
+        };
+
+        let contents = toml::to_string(&profile).unwrap();
+        assert_eq!(
+            contents,
+            "[up]\ndistance_cm = 0.48\nspeed_cm_per_s = 3.8\n\n[down]\ndistance_cm = 0.52\nspeed_cm_per_s = 3.75\n"
+        );
+        assert_eq!(CoastProfile::from_toml(&contents).unwrap(), profile);
+        assert_eq!(
+            CoastProfile::from_toml("").unwrap(),
+            CoastProfile::default()
+        );
+
+        assert!(CoastProfile::from_toml("[up]\ndistance_cm = 0.5").is_err());
+        assert!(
+            CoastProfile::from_toml("[up]\ndistance_cm = 0.5\nspeed_cm_per_s = \"four\"").is_err()
+        );
+        assert!(CoastProfile::from_toml("[up]\ndistance_cm = -0.5\nspeed_cm_per_s = 4.0").is_err());
+        assert!(CoastProfile::from_toml("up 0.5 4").is_err());
+    }
+}
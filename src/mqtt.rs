@@ -0,0 +1,609 @@
+// MQTT integration, with Home Assistant discovery.
+// State is published to retained topics under the topic prefix (e.g. desk_controller/state/height),
+// and commands are received on .../set topics. The desk appears in Home Assistant as a `number`
+// (the height) and a `cover` (open is standing, closed is sitting).
+
+use crate::config::MqttConfig;
+use crate::web::api::{parse_key, Key, UnknownDeskFrameResponse};
+use desk_controller::{
+    DeskController, Event, MotionState, PanelToDeskMessage, STANDING_MIN_HEIGHT_CM,
+};
+use log::{debug, info, warn};
+use rumqttc::{Client, Connection, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::json;
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Height changes are published at most this often
+const STATE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Frame counts change with every frame, so they're published on a timer rather than as they change
+const FRAME_COUNTS_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long to wait before reconnecting after the connection to the broker fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const KEEP_ALIVE_SECS: u16 = 30;
+
+// How long a key pressed over MQTT is held for, unless the command says otherwise
+const DEFAULT_KEY_HOLD: Duration = Duration::from_millis(500);
+const MAX_KEY_HOLD: Duration = Duration::from_secs(10);
+
+// The presets that the cover's open and close commands move to, if they exist
+const OPEN_PRESET: &str = "stand";
+const CLOSE_PRESET: &str = "sit";
+
+struct Topics {
+    prefix: String,
+}
+
+impl Topics {
+    fn availability(&self) -> String {
+        format!("{}/availability", self.prefix)
+    }
+
+    fn state(&self, name: &str) -> String {
+        format!("{}/state/{}", self.prefix, name)
+    }
+
+    fn command(&self, name: &str) -> String {
+        format!("{}/{}/set", self.prefix, name)
+    }
+
+    // e.g. "target" for desk_controller/target/set
+    fn command_name<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix('/')?
+            .strip_suffix("/set")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    MoveToHeight(f32),
+    ClearTargetHeight,
+    MoveToPreset(String),
+    Open,
+    Close,
+    PressKey(PanelToDeskMessage, Duration),
+}
+
+#[derive(Deserialize)]
+struct KeyCommand {
+    key: String,
+    height: Option<f32>,
+    hold_ms: Option<u64>,
+}
+
+// Parses the payload sent to a command topic, e.g. "110" to target/set
+fn parse_command(name: &str, payload: &str) -> Result<Command, String> {
+    let payload = payload.trim();
+
+    match name {
+        "target" => match payload {
+            "" | "none" => Ok(Command::ClearTargetHeight),
+            _ => {
+                let height = payload
+                    .parse::<f32>()
+                    .map_err(|e| format!("Invalid height: {:?} - {}", payload, e))?;
+
+                // Home Assistant scales cover positions between the closed and open heights,
+                // which won't usually land on a multiple of 0.5 cm
+                Ok(Command::MoveToHeight((height * 2.0).round() / 2.0))
+            }
+        },
+        "preset" => Ok(Command::MoveToPreset(payload.to_string())),
+        "stop" => Ok(Command::ClearTargetHeight),
+        "cover" => match payload {
+            "OPEN" => Ok(Command::Open),
+            "CLOSE" => Ok(Command::Close),
+            "STOP" => Ok(Command::ClearTargetHeight),
+            _ => Err(format!(
+                "Invalid cover command: {:?} - must be OPEN, CLOSE or STOP",
+                payload
+            )),
+        },
+        // Either a key name (e.g. "up") or {"key": "two", "height": 110, "hold_ms": 1000}
+        "key" => {
+            let command = if payload.starts_with('{') {
+                serde_json::from_str::<KeyCommand>(payload)
+                    .map_err(|e| format!("Invalid key command: {}", e))?
+            } else {
+                KeyCommand {
+                    key: payload.to_string(),
+                    height: None,
+                    hold_ms: None,
+                }
+            };
+
+            let key = parse_key(&command.key, command.height).map_err(|e| e.message())?;
+
+            let hold = command
+                .hold_ms
+                .map_or(DEFAULT_KEY_HOLD, Duration::from_millis);
+            if hold > MAX_KEY_HOLD {
+                return Err(format!(
+                    "Invalid hold: {} ms - must be at most {} ms",
+                    hold.as_millis(),
+                    MAX_KEY_HOLD.as_millis()
+                ));
+            }
+
+            Ok(Command::PressKey(key, hold))
+        }
+        _ => Err(format!("Unknown command: {}", name)),
+    }
+}
+
+fn run_command(controller: &DeskController, command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::MoveToHeight(height) => controller.move_to_height(height)?,
+        Command::ClearTargetHeight => controller.clear_target_height(),
+        Command::MoveToPreset(name) => controller.move_to_preset(&name)?,
+        Command::Open => match controller.presets().get(OPEN_PRESET) {
+            Some(_) => controller.move_to_preset(OPEN_PRESET)?,
+            None => controller.move_to_height(controller.controller_config().max_height_cm)?,
+        },
+        Command::Close => match controller.presets().get(CLOSE_PRESET) {
+            Some(_) => controller.move_to_preset(CLOSE_PRESET)?,
+            None => controller.move_to_height(controller.controller_config().min_height_cm)?,
+        },
+        Command::PressKey(key, hold) => controller.press_key(key, hold)?,
+    }
+
+    Ok(())
+}
+
+// The cover is open when standing and closed when sitting
+fn cover_state(
+    current_height: f32,
+    target_height: Option<f32>,
+    motion_state: MotionState,
+) -> &'static str {
+    match (motion_state, target_height) {
+        (MotionState::Moving, Some(target)) if target > current_height => "opening",
+        (MotionState::Moving, Some(_)) => "closing",
+        _ if current_height >= STANDING_MIN_HEIGHT_CM => "open",
+        _ => "closed",
+    }
+}
+
+fn motion_state_name(motion_state: MotionState) -> &'static str {
+    match motion_state {
+        MotionState::Idle => "idle",
+        MotionState::Moving => "moving",
+        MotionState::Recovering => "recovering",
+        MotionState::Stalled => "stalled",
+    }
+}
+
+// Home Assistant discovery topics and their config payloads
+fn discovery_messages(
+    config: &MqttConfig,
+    topics: &Topics,
+    min_height: f32,
+    max_height: f32,
+) -> Vec<(String, String)> {
+    if config.discovery_prefix.is_empty() {
+        return Vec::new();
+    }
+
+    let device = json!({
+        "identifiers": [config.client_id],
+        "name": "Desk",
+        "manufacturer": "Vari",
+        "model": "Desk Controller",
+    });
+
+    let number = json!({
+        "name": "Desk Height",
+        "unique_id": format!("{}_height", config.client_id),
+        "availability_topic": topics.availability(),
+        "state_topic": topics.state("height"),
+        "command_topic": topics.command("target"),
+        "min": min_height,
+        "max": max_height,
+        "step": 0.5,
+        "unit_of_measurement": "cm",
+        "icon": "mdi:desk",
+        "device": device,
+    });
+
+    let cover = json!({
+        "name": "Desk",
+        "unique_id": format!("{}_desk", config.client_id),
+        "availability_topic": topics.availability(),
+        "state_topic": topics.state("cover"),
+        "command_topic": topics.command("cover"),
+        "position_topic": topics.state("height"),
+        "set_position_topic": topics.command("target"),
+        "position_open": max_height,
+        "position_closed": min_height,
+        "device": device,
+    });
+
+    vec![
+        (
+            format!(
+                "{}/number/{}/height/config",
+                config.discovery_prefix, config.client_id
+            ),
+            number.to_string(),
+        ),
+        (
+            format!(
+                "{}/cover/{}/desk/config",
+                config.discovery_prefix, config.client_id
+            ),
+            cover.to_string(),
+        ),
+    ]
+}
+
+// Connects to the broker and starts publishing state and handling commands.
+// The connection is retried in the background, so this only fails if the threads can't be started.
+pub fn start(controller: DeskController, config: &MqttConfig) -> Result<(), Box<dyn Error>> {
+    let host = match &config.host {
+        Some(host) => host.clone(),
+        None => return Ok(()),
+    };
+
+    let topics = Topics {
+        prefix: config.topic_prefix.clone(),
+    };
+
+    let mut options = MqttOptions::new(config.client_id.clone(), host, config.port);
+    options.set_keep_alive(KEEP_ALIVE_SECS);
+    options.set_last_will(LastWill::new(
+        topics.availability(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username.clone(), password.clone());
+    }
+
+    info!(
+        "Connecting to MQTT broker at {}:{}",
+        options.broker_address().0,
+        options.broker_address().1
+    );
+
+    let (client, connection) = Client::new(options, 64);
+    let (connected_tx, connected_rx) = crossbeam_channel::unbounded::<()>();
+
+    let command_controller = controller.clone();
+    let command_topics = Topics {
+        prefix: config.topic_prefix.clone(),
+    };
+    thread::Builder::new()
+        .name("mqtt connection".to_string())
+        .spawn(move || {
+            run_connection(connection, command_controller, command_topics, connected_tx)
+        })?;
+
+    let config = config.clone();
+    thread::Builder::new()
+        .name("mqtt publisher".to_string())
+        .spawn(move || run_publisher(client, controller, config, topics, connected_rx))?;
+
+    Ok(())
+}
+
+// Drives the connection (reconnecting as needed) and runs the commands received on it
+fn run_connection(
+    mut connection: Connection,
+    controller: DeskController,
+    topics: Topics,
+    connected_tx: crossbeam_channel::Sender<()>,
+) {
+    for notification in connection.iter() {
+        match notification {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                if connected_tx.send(()).is_err() {
+                    return;
+                }
+            }
+            Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                debug!("MQTT command: {} {:?}", publish.topic, payload);
+
+                let result = match topics.command_name(&publish.topic) {
+                    Some(name) => parse_command(name, &payload)
+                        .map_err(|e| e.into())
+                        .and_then(|command| run_command(&controller, command)),
+                    None => Err(format!("Unexpected topic: {}", publish.topic).into()),
+                };
+
+                if let Err(e) = result {
+                    warn!("MQTT command on {} failed: {}", publish.topic, e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!(
+                    "MQTT connection failed: {} - reconnecting in {:?}",
+                    e, RECONNECT_DELAY
+                );
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+// Publishes state changes, and (re)announces the desk whenever the connection is made
+fn run_publisher(
+    mut client: Client,
+    controller: DeskController,
+    config: MqttConfig,
+    topics: Topics,
+    connected_rx: crossbeam_channel::Receiver<()>,
+) {
+    let mut subscription = controller.subscribe(STATE_INTERVAL);
+    let mut frame_counts_published_at = Instant::now();
+    let mut published_cover_state = None;
+
+    loop {
+        let mut messages = Vec::new();
+
+        if connected_rx.try_iter().count() > 0 {
+            let desk_config = controller.controller_config();
+            let discovery = discovery_messages(
+                &config,
+                &topics,
+                desk_config.min_height_cm,
+                desk_config.max_height_cm,
+            );
+
+            for (topic, payload) in discovery {
+                publish(&mut client, topic, QoS::AtLeastOnce, payload);
+            }
+
+            for name in &["target", "preset", "stop", "cover", "key"] {
+                if let Err(e) = client.subscribe(topics.command(name), QoS::AtLeastOnce) {
+                    warn!("Failed to subscribe to MQTT commands: {}", e);
+                }
+            }
+
+            publish(
+                &mut client,
+                topics.availability(),
+                QoS::AtLeastOnce,
+                "online".to_string(),
+            );
+
+            messages.extend(state_messages(&controller, &topics));
+            frame_counts_published_at = Instant::now();
+            published_cover_state = None;
+        }
+
+        let events = match subscription.next_batch(STATE_INTERVAL) {
+            Some(events) => events,
+            None => return,
+        };
+
+        for event in events {
+            match event {
+                Event::Height(h) => messages.push((topics.state("height"), h.to_string())),
+                Event::TargetHeight(h) => messages.push((
+                    topics.state("target_height"),
+                    h.map_or("none".to_string(), |h| h.to_string()),
+                )),
+                Event::PanelKey(key) => messages.push((
+                    topics.state("panel_key"),
+                    key.map_or("none", |key| Key::new(key).name()).to_string(),
+                )),
+                Event::MotionState(state) => messages.push((
+                    topics.state("motion_state"),
+                    motion_state_name(state).to_string(),
+                )),
+                Event::Health(status) => {
+                    messages.push((topics.state("health"), status.as_str().to_string()))
+                }
+                Event::DeskFrame(message) => messages.push((
+                    topics.state("desk_frame"),
+                    json!(UnknownDeskFrameResponse::from_message(message)).to_string(),
+                )),
+            }
+        }
+
+        // Any change can move the cover between states
+        let cover_state = cover_state(
+            controller.current_height(),
+            controller.target_height(),
+            controller.motion_state(),
+        );
+        if published_cover_state != Some(cover_state) {
+            messages.push((topics.state("cover"), cover_state.to_string()));
+            published_cover_state = Some(cover_state);
+        }
+
+        if frame_counts_published_at.elapsed() >= FRAME_COUNTS_INTERVAL {
+            messages.push(frame_counts_message(&controller, &topics));
+            frame_counts_published_at = Instant::now();
+        }
+
+        for (topic, payload) in messages {
+            publish(&mut client, topic, QoS::AtMostOnce, payload);
+        }
+    }
+}
+
+// Everything but the cover state, for when the connection has just been made
+fn state_messages(controller: &DeskController, topics: &Topics) -> Vec<(String, String)> {
+    vec![
+        (
+            topics.state("height"),
+            controller.current_height().to_string(),
+        ),
+        (
+            topics.state("target_height"),
+            controller
+                .target_height()
+                .map_or("none".to_string(), |h| h.to_string()),
+        ),
+        (
+            topics.state("panel_key"),
+            controller
+                .current_panel_key()
+                .map_or("none", |key| Key::new(key).name())
+                .to_string(),
+        ),
+        (
+            topics.state("motion_state"),
+            motion_state_name(controller.motion_state()).to_string(),
+        ),
+        (
+            topics.state("health"),
+            controller.health().status.as_str().to_string(),
+        ),
+        (
+            topics.state("desk_frame"),
+            controller
+                .last_unknown_desk_frame()
+                .map_or("none".to_string(), |frame| {
+                    json!(UnknownDeskFrameResponse::from_message(frame.message)).to_string()
+                }),
+        ),
+        frame_counts_message(controller, topics),
+    ]
+}
+
+fn frame_counts_message(controller: &DeskController, topics: &Topics) -> (String, String) {
+    let payload = json!({
+        "desk": controller.desk_frame_counts(),
+        "panel": controller.panel_frame_counts(),
+    });
+
+    (topics.state("frames"), payload.to_string())
+}
+
+// State is retained, so that anything subscribing later gets the latest values
+fn publish(client: &mut Client, topic: String, qos: QoS, payload: String) {
+    if let Err(e) = client.publish(topic.as_str(), qos, true, payload) {
+        warn!("Failed to publish to {}: {}", topic, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command("target", "110"),
+            Ok(Command::MoveToHeight(110.0))
+        );
+        assert_eq!(
+            parse_command("target", "97.3"),
+            Ok(Command::MoveToHeight(97.5))
+        );
+        assert_eq!(
+            parse_command("target", "none"),
+            Ok(Command::ClearTargetHeight)
+        );
+        assert!(parse_command("target", "high").is_err());
+
+        assert_eq!(
+            parse_command("preset", "stand"),
+            Ok(Command::MoveToPreset("stand".to_string()))
+        );
+        assert_eq!(parse_command("stop", ""), Ok(Command::ClearTargetHeight));
+
+        assert_eq!(parse_command("cover", "OPEN"), Ok(Command::Open));
+        assert_eq!(parse_command("cover", "CLOSE"), Ok(Command::Close));
+        assert_eq!(
+            parse_command("cover", "STOP"),
+            Ok(Command::ClearTargetHeight)
+        );
+        assert!(parse_command("cover", "open").is_err());
+
+        assert_eq!(
+            parse_command("key", "up"),
+            Ok(Command::PressKey(PanelToDeskMessage::Up, DEFAULT_KEY_HOLD))
+        );
+        assert_eq!(
+            parse_command("key", r#"{"key": "two", "height": 110, "hold_ms": 1000}"#),
+            Ok(Command::PressKey(
+                PanelToDeskMessage::Two(110.0),
+                Duration::from_secs(1)
+            ))
+        );
+        assert!(parse_command("key", "two").is_err());
+        assert!(parse_command("key", r#"{"key": "up", "hold_ms": 60000}"#).is_err());
+
+        assert!(parse_command("height", "110").is_err());
+    }
+
+    #[test]
+    fn test_topics() {
+        let topics = Topics {
+            prefix: "office/desk".to_string(),
+        };
+
+        assert_eq!(topics.availability(), "office/desk/availability");
+        assert_eq!(topics.state("height"), "office/desk/state/height");
+        assert_eq!(topics.command("target"), "office/desk/target/set");
+
+        assert_eq!(
+            topics.command_name("office/desk/target/set"),
+            Some("target")
+        );
+        assert_eq!(topics.command_name("office/desk/state/height"), None);
+        assert_eq!(topics.command_name("office/desks/target/set"), None);
+    }
+
+    #[test]
+    fn test_cover_state() {
+        assert_eq!(
+            cover_state(80.0, Some(110.0), MotionState::Moving),
+            "opening"
+        );
+        assert_eq!(
+            cover_state(110.0, Some(80.0), MotionState::Moving),
+            "closing"
+        );
+        assert_eq!(cover_state(110.0, None, MotionState::Idle), "open");
+        assert_eq!(
+            cover_state(80.0, Some(110.0), MotionState::Stalled),
+            "closed"
+        );
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let config = MqttConfig::default();
+        let topics = Topics {
+            prefix: config.topic_prefix.clone(),
+        };
+
+        let messages = discovery_messages(&config, &topics, 65.0, 129.5);
+        assert_eq!(
+            messages
+                .iter()
+                .map(|(topic, _)| topic.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "homeassistant/number/desk_controller/height/config",
+                "homeassistant/cover/desk_controller/desk/config",
+            ]
+        );
+
+        let cover: serde_json::Value = serde_json::from_str(&messages[1].1).unwrap();
+        assert_eq!(cover["set_position_topic"], "desk_controller/target/set");
+        assert_eq!(cover["position_open"], json!(129.5));
+        assert_eq!(cover["device"]["identifiers"][0], "desk_controller");
+
+        let config = MqttConfig {
+            discovery_prefix: String::new(),
+            ..MqttConfig::default()
+        };
+        assert!(discovery_messages(&config, &topics, 65.0, 129.5).is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+// Named heights, stored by the controller rather than in the panel's three memory slots.
+
+use crate::{validate_height, validate_height_within, InvalidHeightError};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MAX_PRESET_NAME_LENGTH: usize = 64;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Presets {
+    heights: BTreeMap<String, f32>,
+}
+
+impl Presets {
+    pub fn new() -> Presets {
+        Presets {
+            heights: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<f32> {
+        self.heights.get(name).copied()
+    }
+
+    // Presets in name order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f32)> {
+        self.heights.iter().map(|(name, h)| (name.as_str(), *h))
+    }
+
+    pub fn len(&self) -> usize {
+        self.heights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heights.is_empty()
+    }
+
+    // Adds a preset, or replaces the height of an existing one
+    pub fn insert(&mut self, name: &str, height_in_cm: f32) -> Result<(), PresetError> {
+        validate_name(name)?;
+        validate_height(height_in_cm).map_err(PresetError::InvalidHeight)?;
+
+        self.heights.insert(name.to_string(), height_in_cm);
+        Ok(())
+    }
+
+    // Heights are checked against the absolute limits when inserted, but the desk may be configured
+    // with a narrower range
+    pub fn validate_heights(&self, min_height: f32, max_height: f32) -> Result<(), PresetError> {
+        for (_, h) in self.iter() {
+            validate_height_within(h, min_height, max_height)
+                .map_err(PresetError::InvalidHeight)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<f32, PresetError> {
+        self.heights
+            .remove(name)
+            .ok_or_else(|| PresetError::NotFound(name.to_string()))
+    }
+
+    // e.g.
+    // sit = 72.5
+    // stand = 110.0
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Presets, PresetError> {
+        let contents = fs::read_to_string(path).map_err(PresetError::Storage)?;
+        Presets::from_toml(&contents)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PresetError> {
+        let contents =
+            toml::to_string(&self.heights).map_err(|e| PresetError::InvalidFile(e.to_string()))?;
+        fs::write(path, contents).map_err(PresetError::Storage)
+    }
+
+    // Each preset is checked as if it were being inserted
+    fn from_toml(contents: &str) -> Result<Presets, PresetError> {
+        let heights = toml::from_str::<BTreeMap<String, f32>>(contents)
+            .map_err(|e| PresetError::InvalidFile(e.to_string()))?;
+
+        let mut presets = Presets::new();
+        for (name, height) in heights {
+            presets.insert(&name, height)?;
+        }
+
+        Ok(presets)
+    }
+}
+
+// Names are used in URLs and in the presets file, so are limited to e.g. "standing-tall-monitor"
+fn validate_name(name: &str) -> Result<(), PresetError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_PRESET_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(PresetError::InvalidName(name.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    InvalidName(String),
+    InvalidHeight(InvalidHeightError),
+    InvalidFile(String),
+    NotFound(String),
+    Storage(io::Error),
+}
+
+impl Display for PresetError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PresetError::InvalidName(name) => write!(
+                f,
+                "Invalid preset name: {:?} - must be 1 to {} lowercase letters, digits, '-' or '_'",
+                name, MAX_PRESET_NAME_LENGTH
+            ),
+            PresetError::InvalidHeight(e) => write!(f, "Invalid preset: {}", e),
+            PresetError::InvalidFile(message) => write!(f, "Invalid presets file: {}", message),
+            PresetError::NotFound(name) => write!(f, "Preset not found: {}", name),
+            PresetError::Storage(e) => write!(f, "Failed to read or write presets: {}", e),
+        }
+    }
+}
+
+impl Error for PresetError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets_insert() {
+        let mut presets = Presets::new();
+
+        presets.insert("sit", 72.5).unwrap();
+        presets.insert("standing-tall-monitor", 115.0).unwrap();
+        presets.insert("sit", 73.0).unwrap();
+
+        assert_eq!(presets.get("sit"), Some(73.0));
+        assert_eq!(
+            presets.iter().collect::<Vec<_>>(),
+            vec![("sit", 73.0), ("standing-tall-monitor", 115.0)]
+        );
+
+        assert!(matches!(
+            presets.insert("", 100.0),
+            Err(PresetError::InvalidName(_))
+        ));
+        assert!(matches!(
+            presets.insert("Sit Down", 100.0),
+            Err(PresetError::InvalidName(_))
+        ));
+        assert!(matches!(
+            presets.insert("low", 50.0),
+            Err(PresetError::InvalidHeight(_))
+        ));
+        assert!(matches!(
+            presets.insert("odd", 100.2),
+            Err(PresetError::InvalidHeight(_))
+        ));
+        assert_eq!(presets.len(), 2);
+    }
+
+    #[test]
+    fn test_presets_remove() {
+        let mut presets = Presets::new();
+        presets.insert("sit", 72.5).unwrap();
+
+        assert!((presets.remove("sit").unwrap() - 72.5).abs() < 0.001);
+        assert!(matches!(
+            presets.remove("sit"),
+            Err(PresetError::NotFound(_))
+        ));
+        assert!(presets.is_empty());
+    }
+
+    #[test]
+    fn test_presets_from_toml() {
+        let mut presets = Presets::new();
+        presets.insert("sit", 72.5).unwrap();
+        presets.insert("stand", 110.0).unwrap();
+
+        let contents = toml::to_string(&presets.heights).unwrap();
+        assert_eq!(contents, "sit = 72.5\nstand = 110.0\n");
+        assert_eq!(Presets::from_toml(&contents).unwrap(), presets);
+        assert_eq!(
+            Presets::from_toml("stand = 110").unwrap().get("stand"),
+            Some(110.0)
+        );
+
+        assert!(matches!(
+            Presets::from_toml("sit"),
+            Err(PresetError::InvalidFile(_))
+        ));
+        assert!(matches!(
+            Presets::from_toml("sit = \"seventy\""),
+            Err(PresetError::InvalidFile(_))
+        ));
+        assert!(matches!(
+            Presets::from_toml("sit = 200"),
+            Err(PresetError::InvalidHeight(_))
+        ));
+        assert!(matches!(
+            Presets::from_toml("\"Sit Down\" = 72.5"),
+            Err(PresetError::InvalidName(_))
+        ));
+    }
+}
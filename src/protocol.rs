@@ -4,11 +4,19 @@
 // The penultimate byte is a checksum: summation of bytes 2 through 5 inclusive, modulo 256
 // [START,a,b,c,d,CHECKSUM,END]
 
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
 pub const DATA_FRAME_SIZE: usize = 7;
 
 const DATA_FRAME_START_BYTE: u8 = 104u8;
 const DATA_FRAME_END_BYTE: u8 = 22u8;
 
+// The only desk-to-panel opcode that has been identified from captures of the bus.
+// The desk also shows faults, the key lock and reset prompts on the panel, but until those frames
+// have been captured they are decoded as Unknown (desk-dissect groups them by opcode), and the
+// controller reports the latest one as it was received.
 const DESK_TO_PANEL_HEIGHT_BYTE: u8 = 0u8;
 
 const PANEL_TO_DESK_UP_BYTE: u8 = 1u8;
@@ -22,7 +30,62 @@ const PANEL_TO_DESK_RESET_ONE_BYTE: u8 = 10u8;
 const PANEL_TO_DESK_RESET_TWO_BYTE: u8 = 11u8;
 const PANEL_TO_DESK_RESET_THREE_BYTE: u8 = 12u8;
 
-pub type DataFrame = Vec<u8>;
+pub type DataFrame = [u8; DATA_FRAME_SIZE];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameError {
+    InvalidLength(usize),
+    InvalidStartByte(u8),
+    InvalidEndByte(u8),
+    InvalidChecksum { expected: u8, actual: u8 },
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            FrameError::InvalidLength(len) => write!(
+                f,
+                "Invalid frame: length {} - must be {}",
+                len, DATA_FRAME_SIZE
+            ),
+            FrameError::InvalidStartByte(b) => write!(
+                f,
+                "Invalid frame: start byte {} - must be {}",
+                b, DATA_FRAME_START_BYTE
+            ),
+            FrameError::InvalidEndByte(b) => write!(
+                f,
+                "Invalid frame: end byte {} - must be {}",
+                b, DATA_FRAME_END_BYTE
+            ),
+            FrameError::InvalidChecksum { expected, actual } => write!(
+                f,
+                "Invalid frame: checksum {} - expected {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+// What to do with a frame whose start and end bytes are valid but whose checksum is not
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumPolicy {
+    // Discard the frame
+    Drop,
+    // Pass the raw bytes on unchanged as an Unknown message, without interpreting them
+    Forward,
+    // Recalculate the checksum and decode the frame as if it were valid
+    Repair,
+}
+
+// Frames with a bad checksum are forwarded by default, as they always were before checksums were validated
+impl Default for ChecksumPolicy {
+    fn default() -> ChecksumPolicy {
+        ChecksumPolicy::Forward
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PanelToDeskMessage {
@@ -40,6 +103,7 @@ pub enum PanelToDeskMessage {
 }
 
 impl PanelToDeskMessage {
+    #[allow(clippy::many_single_char_names)]
     pub fn as_frame(&self) -> DataFrame {
         match *self {
             PanelToDeskMessage::Up => build_frame(PANEL_TO_DESK_UP_BYTE, 0u8, 0u8),
@@ -62,13 +126,31 @@ impl PanelToDeskMessage {
             PanelToDeskMessage::ResetTwo => build_frame(PANEL_TO_DESK_RESET_TWO_BYTE, 0u8, 0u8),
             PanelToDeskMessage::ResetThree => build_frame(PANEL_TO_DESK_RESET_THREE_BYTE, 0u8, 0u8),
             PanelToDeskMessage::Unknown(a, b, c, d, e) => {
-                vec![DATA_FRAME_START_BYTE, a, b, c, d, e, DATA_FRAME_END_BYTE]
+                [DATA_FRAME_START_BYTE, a, b, c, d, e, DATA_FRAME_END_BYTE]
             }
         }
     }
 
-    pub fn from_frame(buf: &DataFrame) -> PanelToDeskMessage {
-        // TODO: validate checksum somewhere. Or don't; just pass it on to desk?
+    pub fn from_frame(buf: &[u8]) -> Result<PanelToDeskMessage, FrameError> {
+        validate_frame(buf)?;
+        validate_checksum(buf)?;
+
+        Ok(PanelToDeskMessage::decode(buf))
+    }
+
+    // Handle a frame that failed checksum validation according to the given policy.
+    // Returns None if the frame should be dropped.
+    pub fn recover_frame(buf: &[u8], policy: ChecksumPolicy) -> Option<PanelToDeskMessage> {
+        match policy {
+            ChecksumPolicy::Drop => None,
+            ChecksumPolicy::Forward => Some(PanelToDeskMessage::Unknown(
+                buf[1], buf[2], buf[3], buf[4], buf[5],
+            )),
+            ChecksumPolicy::Repair => Some(PanelToDeskMessage::decode(&repair_checksum(buf))),
+        }
+    }
+
+    fn decode(buf: &[u8]) -> PanelToDeskMessage {
         match buf[2] {
             PANEL_TO_DESK_UP_BYTE => PanelToDeskMessage::Up,
             PANEL_TO_DESK_DOWN_BYTE => PanelToDeskMessage::Down,
@@ -95,24 +177,44 @@ pub fn is_start_byte(b: u8) -> bool {
     b == DATA_FRAME_START_BYTE
 }
 
-pub fn validate_frame(frame: &DataFrame) -> bool {
+// Checks the length, start byte and end byte of the frame.
+// The checksum is validated separately so that callers can choose how to handle a bad checksum.
+pub fn validate_frame(frame: &[u8]) -> Result<(), FrameError> {
     if frame.len() != DATA_FRAME_SIZE {
-        return false;
+        return Err(FrameError::InvalidLength(frame.len()));
     }
 
     if frame[0] != DATA_FRAME_START_BYTE {
-        return false;
+        return Err(FrameError::InvalidStartByte(frame[0]));
     }
 
     if frame[DATA_FRAME_SIZE - 1] != DATA_FRAME_END_BYTE {
-        return false;
+        return Err(FrameError::InvalidEndByte(frame[DATA_FRAME_SIZE - 1]));
     }
 
-    true
+    Ok(())
+}
+
+pub fn validate_checksum(frame: &[u8]) -> Result<(), FrameError> {
+    let expected = checksum(&frame[1..DATA_FRAME_SIZE - 2]);
+    let actual = frame[DATA_FRAME_SIZE - 2];
+
+    if expected != actual {
+        return Err(FrameError::InvalidChecksum { expected, actual });
+    }
+
+    Ok(())
+}
+
+fn repair_checksum(frame: &[u8]) -> DataFrame {
+    let mut repaired = [0u8; DATA_FRAME_SIZE];
+    repaired.copy_from_slice(frame);
+    repaired[DATA_FRAME_SIZE - 2] = checksum(&frame[1..DATA_FRAME_SIZE - 2]);
+    repaired
 }
 
 fn build_frame(b2: u8, b3: u8, b4: u8) -> DataFrame {
-    vec![
+    [
         DATA_FRAME_START_BYTE,
         1u8,
         b2,
@@ -130,6 +232,7 @@ pub enum DeskToPanelMessage {
 }
 
 impl DeskToPanelMessage {
+    #[allow(clippy::many_single_char_names)]
     pub fn as_frame(&self) -> DataFrame {
         match *self {
             DeskToPanelMessage::Height(h) => {
@@ -138,13 +241,31 @@ impl DeskToPanelMessage {
                 build_frame(DESK_TO_PANEL_HEIGHT_BYTE, height_msb, height_lsb)
             }
             DeskToPanelMessage::Unknown(a, b, c, d, e) => {
-                vec![DATA_FRAME_START_BYTE, a, b, c, d, e, DATA_FRAME_END_BYTE]
+                [DATA_FRAME_START_BYTE, a, b, c, d, e, DATA_FRAME_END_BYTE]
             }
         }
     }
 
-    pub fn from_frame(frame: &DataFrame) -> DeskToPanelMessage {
-        // TODO: validate checksum somewhere. Or don't; just pass it on to panel?
+    pub fn from_frame(frame: &[u8]) -> Result<DeskToPanelMessage, FrameError> {
+        validate_frame(frame)?;
+        validate_checksum(frame)?;
+
+        Ok(DeskToPanelMessage::decode(frame))
+    }
+
+    // Handle a frame that failed checksum validation according to the given policy.
+    // Returns None if the frame should be dropped.
+    pub fn recover_frame(frame: &[u8], policy: ChecksumPolicy) -> Option<DeskToPanelMessage> {
+        match policy {
+            ChecksumPolicy::Drop => None,
+            ChecksumPolicy::Forward => Some(DeskToPanelMessage::Unknown(
+                frame[1], frame[2], frame[3], frame[4], frame[5],
+            )),
+            ChecksumPolicy::Repair => Some(DeskToPanelMessage::decode(&repair_checksum(frame))),
+        }
+    }
+
+    fn decode(frame: &[u8]) -> DeskToPanelMessage {
         match frame[2] {
             DESK_TO_PANEL_HEIGHT_BYTE => {
                 DeskToPanelMessage::Height(bytes_to_height_cm(frame[3], frame[4], 65.0))
@@ -178,7 +299,7 @@ mod tests {
     #[test]
     fn test_panel_to_desk_message_from_frame() {
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_UP_BYTE,
@@ -187,11 +308,11 @@ mod tests {
                 2u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::Up,
+            Ok(PanelToDeskMessage::Up),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_DOWN_BYTE,
@@ -200,11 +321,11 @@ mod tests {
                 3u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::Down,
+            Ok(PanelToDeskMessage::Down),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_NO_KEY_BYTE,
@@ -213,11 +334,11 @@ mod tests {
                 4u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::NoKey,
+            Ok(PanelToDeskMessage::NoKey),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_DESK_RESET_BYTE,
@@ -226,11 +347,11 @@ mod tests {
                 5u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::DeskReset,
+            Ok(PanelToDeskMessage::DeskReset),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -239,11 +360,11 @@ mod tests {
                 7u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(0.0),
+            Ok(PanelToDeskMessage::One(0.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_TWO_BYTE,
@@ -252,11 +373,11 @@ mod tests {
                 8u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::Two(0.0),
+            Ok(PanelToDeskMessage::Two(0.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_THREE_BYTE,
@@ -265,11 +386,11 @@ mod tests {
                 9u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::Three(0.0),
+            Ok(PanelToDeskMessage::Three(0.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -278,11 +399,11 @@ mod tests {
                 147u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(65.0),
+            Ok(PanelToDeskMessage::One(65.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_TWO_BYTE,
@@ -291,11 +412,11 @@ mod tests {
                 148u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::Two(65.0),
+            Ok(PanelToDeskMessage::Two(65.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_THREE_BYTE,
@@ -304,11 +425,11 @@ mod tests {
                 149u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::Three(65.0),
+            Ok(PanelToDeskMessage::Three(65.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -317,11 +438,11 @@ mod tests {
                 152u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(65.5),
+            Ok(PanelToDeskMessage::One(65.5)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -330,11 +451,11 @@ mod tests {
                 242u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(100.0),
+            Ok(PanelToDeskMessage::One(100.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -343,11 +464,11 @@ mod tests {
                 6u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(76.5),
+            Ok(PanelToDeskMessage::One(76.5)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -356,11 +477,11 @@ mod tests {
                 12u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(77.0),
+            Ok(PanelToDeskMessage::One(77.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -369,11 +490,11 @@ mod tests {
                 6u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(102.0),
+            Ok(PanelToDeskMessage::One(102.0)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -382,11 +503,11 @@ mod tests {
                 12u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(102.5),
+            Ok(PanelToDeskMessage::One(102.5)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -395,11 +516,11 @@ mod tests {
                 27u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::One(129.5),
+            Ok(PanelToDeskMessage::One(129.5)),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_RESET_ONE_BYTE,
@@ -408,11 +529,11 @@ mod tests {
                 11u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::ResetOne,
+            Ok(PanelToDeskMessage::ResetOne),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_RESET_TWO_BYTE,
@@ -421,11 +542,11 @@ mod tests {
                 12u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::ResetTwo,
+            Ok(PanelToDeskMessage::ResetTwo),
         );
 
         assert_eq!(
-            PanelToDeskMessage::from_frame(&vec![
+            PanelToDeskMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_RESET_THREE_BYTE,
@@ -434,7 +555,7 @@ mod tests {
                 13u8,
                 DATA_FRAME_END_BYTE
             ]),
-            PanelToDeskMessage::ResetThree,
+            Ok(PanelToDeskMessage::ResetThree),
         );
     }
 
@@ -442,7 +563,7 @@ mod tests {
     fn test_panel_to_desk_message_as_frame() {
         assert_eq!(
             PanelToDeskMessage::Up.as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_UP_BYTE,
@@ -455,7 +576,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::Down.as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_DOWN_BYTE,
@@ -468,7 +589,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::NoKey.as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_NO_KEY_BYTE,
@@ -481,7 +602,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::DeskReset.as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_DESK_RESET_BYTE,
@@ -494,7 +615,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::Unknown(99u8, 64u8, 254u8, 1u8, 98u8).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 99u8,
                 64u8,
@@ -507,7 +628,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(0.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -520,7 +641,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::Two(0.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_TWO_BYTE,
@@ -533,7 +654,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::Three(0.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_THREE_BYTE,
@@ -546,7 +667,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(65.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -559,7 +680,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::Two(65.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_TWO_BYTE,
@@ -572,7 +693,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::Three(65.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_THREE_BYTE,
@@ -585,7 +706,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(65.5).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -598,7 +719,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(100.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -611,7 +732,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(76.5).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -624,7 +745,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(77.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -637,7 +758,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(102.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -650,7 +771,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(102.5).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -663,7 +784,7 @@ mod tests {
 
         assert_eq!(
             PanelToDeskMessage::One(129.5).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 PANEL_TO_DESK_ONE_BYTE,
@@ -683,7 +804,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Height(65.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -696,7 +817,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Height(65.5).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -709,7 +830,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Height(100.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -722,7 +843,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Height(90.5).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -735,7 +856,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Height(91.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -748,7 +869,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Height(116.0).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -761,7 +882,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Height(116.5).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -774,7 +895,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Height(129.5).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -787,7 +908,7 @@ mod tests {
 
         assert_eq!(
             DeskToPanelMessage::Unknown(99u8, 64u8, 254u8, 1u8, 98u8).as_frame(),
-            vec![
+            [
                 DATA_FRAME_START_BYTE,
                 99u8,
                 64u8,
@@ -802,7 +923,7 @@ mod tests {
     #[test]
     fn test_desk_to_panel_message_from_frame() {
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -811,11 +932,11 @@ mod tests {
                 1u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Height(65.0),
+            Ok(DeskToPanelMessage::Height(65.0)),
         );
 
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -824,11 +945,11 @@ mod tests {
                 6u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Height(65.5),
+            Ok(DeskToPanelMessage::Height(65.5)),
         );
 
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -837,11 +958,11 @@ mod tests {
                 96u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Height(100.0),
+            Ok(DeskToPanelMessage::Height(100.0)),
         );
 
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -850,11 +971,11 @@ mod tests {
                 0u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Height(90.5),
+            Ok(DeskToPanelMessage::Height(90.5)),
         );
 
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -863,11 +984,11 @@ mod tests {
                 6u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Height(91.0),
+            Ok(DeskToPanelMessage::Height(91.0)),
         );
 
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -876,11 +997,11 @@ mod tests {
                 0u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Height(116.0),
+            Ok(DeskToPanelMessage::Height(116.0)),
         );
 
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -889,11 +1010,11 @@ mod tests {
                 6u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Height(116.5),
+            Ok(DeskToPanelMessage::Height(116.5)),
         );
 
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 1u8,
                 DESK_TO_PANEL_HEIGHT_BYTE,
@@ -902,11 +1023,24 @@ mod tests {
                 136u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Height(129.5),
+            Ok(DeskToPanelMessage::Height(129.5)),
+        );
+
+        assert_eq!(
+            DeskToPanelMessage::from_frame(&[
+                DATA_FRAME_START_BYTE,
+                99u8,
+                64u8,
+                254u8,
+                1u8,
+                162u8,
+                DATA_FRAME_END_BYTE
+            ]),
+            Ok(DeskToPanelMessage::Unknown(99u8, 64u8, 254u8, 1u8, 162u8)),
         );
 
         assert_eq!(
-            DeskToPanelMessage::from_frame(&vec![
+            DeskToPanelMessage::from_frame(&[
                 DATA_FRAME_START_BYTE,
                 99u8,
                 64u8,
@@ -915,42 +1049,166 @@ mod tests {
                 98u8,
                 DATA_FRAME_END_BYTE
             ]),
-            DeskToPanelMessage::Unknown(99u8, 64u8, 254u8, 1u8, 98u8),
+            Err(FrameError::InvalidChecksum {
+                expected: 162u8,
+                actual: 98u8
+            }),
         );
     }
 
     #[test]
-    fn test_validate_frame() {
-        assert!(!validate_frame(&vec![]));
-        assert!(!validate_frame(&vec![0u8, 0u8]));
-        assert!(!validate_frame(&vec![0u8; DATA_FRAME_SIZE]));
-        assert!(!validate_frame(&vec![
+    fn test_desk_to_panel_message_recover_frame() {
+        let frame = vec![
             DATA_FRAME_START_BYTE,
+            1u8,
+            DESK_TO_PANEL_HEIGHT_BYTE,
+            1u8,
+            94u8,
             0u8,
-            0u8,
-            0u8,
-            0u8,
-            0u8,
-            0u8
-        ]));
-        assert!(!validate_frame(&vec![
-            0u8,
-            0u8,
-            0u8,
-            0u8,
-            0u8,
-            0u8,
-            DATA_FRAME_END_BYTE
-        ]));
+            DATA_FRAME_END_BYTE,
+        ];
 
-        assert!(validate_frame(&vec![
+        assert_eq!(
+            DeskToPanelMessage::recover_frame(&frame, ChecksumPolicy::Drop),
+            None,
+        );
+
+        assert_eq!(
+            DeskToPanelMessage::recover_frame(&frame, ChecksumPolicy::Forward),
+            Some(DeskToPanelMessage::Unknown(
+                1u8,
+                DESK_TO_PANEL_HEIGHT_BYTE,
+                1u8,
+                94u8,
+                0u8
+            )),
+        );
+
+        assert_eq!(
+            DeskToPanelMessage::recover_frame(&frame, ChecksumPolicy::Repair),
+            Some(DeskToPanelMessage::Height(100.0)),
+        );
+    }
+
+    #[test]
+    fn test_panel_to_desk_message_recover_frame() {
+        let frame = vec![
             DATA_FRAME_START_BYTE,
+            1u8,
+            PANEL_TO_DESK_UP_BYTE,
             0u8,
             0u8,
-            0u8,
-            0u8,
-            0u8,
-            DATA_FRAME_END_BYTE
-        ]));
+            99u8,
+            DATA_FRAME_END_BYTE,
+        ];
+
+        assert_eq!(
+            PanelToDeskMessage::from_frame(&frame),
+            Err(FrameError::InvalidChecksum {
+                expected: 2u8,
+                actual: 99u8
+            }),
+        );
+
+        assert_eq!(
+            PanelToDeskMessage::recover_frame(&frame, ChecksumPolicy::Drop),
+            None,
+        );
+
+        assert_eq!(
+            PanelToDeskMessage::recover_frame(&frame, ChecksumPolicy::Forward),
+            Some(PanelToDeskMessage::Unknown(
+                1u8,
+                PANEL_TO_DESK_UP_BYTE,
+                0u8,
+                0u8,
+                99u8
+            )),
+        );
+
+        assert_eq!(
+            PanelToDeskMessage::recover_frame(&frame, ChecksumPolicy::Repair),
+            Some(PanelToDeskMessage::Up),
+        );
+    }
+
+    #[test]
+    fn test_validate_frame() {
+        assert_eq!(validate_frame(&[]), Err(FrameError::InvalidLength(0)));
+        assert_eq!(
+            validate_frame(&[0u8, 0u8]),
+            Err(FrameError::InvalidLength(2))
+        );
+        assert_eq!(
+            validate_frame(&[0u8; DATA_FRAME_SIZE]),
+            Err(FrameError::InvalidStartByte(0u8))
+        );
+        assert_eq!(
+            validate_frame(&[DATA_FRAME_START_BYTE, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8]),
+            Err(FrameError::InvalidEndByte(0u8))
+        );
+        assert_eq!(
+            validate_frame(&[0u8, 0u8, 0u8, 0u8, 0u8, 0u8, DATA_FRAME_END_BYTE]),
+            Err(FrameError::InvalidStartByte(0u8))
+        );
+
+        assert_eq!(
+            validate_frame(&[
+                DATA_FRAME_START_BYTE,
+                0u8,
+                0u8,
+                0u8,
+                0u8,
+                0u8,
+                DATA_FRAME_END_BYTE
+            ]),
+            Ok(())
+        );
+
+        // Checksums are not checked by validate_frame
+        assert_eq!(
+            validate_frame(&[
+                DATA_FRAME_START_BYTE,
+                0u8,
+                0u8,
+                0u8,
+                0u8,
+                1u8,
+                DATA_FRAME_END_BYTE
+            ]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_validate_checksum() {
+        assert_eq!(
+            validate_checksum(&[
+                DATA_FRAME_START_BYTE,
+                1u8,
+                PANEL_TO_DESK_ONE_BYTE,
+                253u8,
+                2u8,
+                6u8,
+                DATA_FRAME_END_BYTE
+            ]),
+            Ok(())
+        );
+
+        assert_eq!(
+            validate_checksum(&[
+                DATA_FRAME_START_BYTE,
+                1u8,
+                PANEL_TO_DESK_ONE_BYTE,
+                253u8,
+                2u8,
+                7u8,
+                DATA_FRAME_END_BYTE
+            ]),
+            Err(FrameError::InvalidChecksum {
+                expected: 6u8,
+                actual: 7u8
+            })
+        );
     }
 }
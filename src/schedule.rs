@@ -0,0 +1,563 @@
+// Sit/stand schedule: rules that move the desk at set times of day.
+// A key pressed on the panel pauses the schedule for a cool-down, so that it never fights someone at the desk.
+
+use crate::{validate_height, validate_height_within, InvalidHeightError};
+use chrono::Weekday;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30 * 60);
+
+// A day is as long as anyone would want the schedule to leave them alone for
+const MAX_COOLDOWN_MINUTES: u64 = 24 * 60;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    rules: Vec<ScheduleRule>,
+    cooldown: Duration,
+}
+
+impl Default for Schedule {
+    fn default() -> Schedule {
+        Schedule {
+            rules: Vec::new(),
+            cooldown: DEFAULT_COOLDOWN,
+        }
+    }
+}
+
+impl Schedule {
+    pub fn new(rules: Vec<ScheduleRule>, cooldown: Duration) -> Result<Schedule, ScheduleError> {
+        if cooldown > Duration::from_secs(MAX_COOLDOWN_MINUTES * 60) {
+            return Err(ScheduleError::InvalidCooldown(cooldown.as_secs() / 60));
+        }
+
+        Ok(Schedule { rules, cooldown })
+    }
+
+    // Fails rather than overflowing, so that the cooldown can then be checked by `new`
+    pub fn cooldown_from_minutes(minutes: u64) -> Result<Duration, ScheduleError> {
+        minutes
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or(ScheduleError::InvalidCooldown(minutes))
+    }
+
+    pub fn rules(&self) -> &[ScheduleRule] {
+        &self.rules
+    }
+
+    // Heights are checked against the absolute limits as rules are parsed, but the desk may be
+    // configured with a narrower range
+    pub fn validate_heights(&self, min_height: f32, max_height: f32) -> Result<(), ScheduleError> {
+        for action in self.rules.iter().flat_map(ScheduleRule::actions) {
+            if let ScheduleAction::MoveToHeight(h) = action {
+                validate_height_within(*h, min_height, max_height)
+                    .map_err(ScheduleError::InvalidHeight)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // How long the schedule is paused for after a key is pressed on the panel
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    // What to do at `minute_of_day` (e.g. 10:30 is 630) on `weekday`, if anything.
+    // When several rules match, the last one wins.
+    pub fn action_at(&self, weekday: Weekday, minute_of_day: u32) -> Option<&ScheduleAction> {
+        self.rules
+            .iter()
+            .rev()
+            .find_map(|rule| rule.action_at(weekday, minute_of_day))
+    }
+
+    // e.g.
+    // cooldown_minutes = 30
+    // rules = [
+    //     'weekdays 10:00 stand',
+    //     'weekdays every 30m 09:00-17:00 sit stand',
+    // ]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Schedule, ScheduleError> {
+        let contents = fs::read_to_string(path).map_err(ScheduleError::Storage)?;
+        Schedule::from_toml(&contents)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ScheduleError> {
+        let file = ScheduleFile {
+            cooldown_minutes: self.cooldown.as_secs() / 60,
+            rules: self.rules.iter().map(ToString::to_string).collect(),
+        };
+        let contents =
+            toml::to_string_pretty(&file).map_err(|e| ScheduleError::InvalidFile(e.to_string()))?;
+        fs::write(path, contents).map_err(ScheduleError::Storage)
+    }
+
+    fn from_toml(contents: &str) -> Result<Schedule, ScheduleError> {
+        let file = toml::from_str::<ScheduleFile>(contents)
+            .map_err(|e| ScheduleError::InvalidFile(e.to_string()))?;
+
+        let rules = file
+            .rules
+            .iter()
+            .map(|rule| rule.parse::<ScheduleRule>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Schedule::new(
+            rules,
+            Schedule::cooldown_from_minutes(file.cooldown_minutes)?,
+        )
+    }
+}
+
+// Rules are kept in the same form as they are given through the API
+#[derive(Serialize, Deserialize)]
+struct ScheduleFile {
+    #[serde(default = "default_cooldown_minutes")]
+    cooldown_minutes: u64,
+    #[serde(default)]
+    rules: Vec<String>,
+}
+
+fn default_cooldown_minutes() -> u64 {
+    DEFAULT_COOLDOWN.as_secs() / 60
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScheduleRule {
+    // e.g. weekdays 10:00 stand
+    At {
+        days: Days,
+        minute_of_day: u32,
+        action: ScheduleAction,
+    },
+    // e.g. weekdays every 30m 09:00-17:00 sit stand
+    // Alternates between the two actions, starting with the first at the start of the range.
+    // The end of the range is exclusive.
+    Alternate {
+        days: Days,
+        every_minutes: u32,
+        from_minute: u32,
+        until_minute: u32,
+        actions: [ScheduleAction; 2],
+    },
+}
+
+impl ScheduleRule {
+    pub fn actions(&self) -> &[ScheduleAction] {
+        match self {
+            ScheduleRule::At { action, .. } => std::slice::from_ref(action),
+            ScheduleRule::Alternate { actions, .. } => actions,
+        }
+    }
+
+    pub fn action_at(&self, weekday: Weekday, minute_of_day: u32) -> Option<&ScheduleAction> {
+        match self {
+            ScheduleRule::At {
+                days,
+                minute_of_day: at,
+                action,
+            } => {
+                if days.contains(weekday) && *at == minute_of_day {
+                    Some(action)
+                } else {
+                    None
+                }
+            }
+            ScheduleRule::Alternate {
+                days,
+                every_minutes,
+                from_minute,
+                until_minute,
+                actions,
+            } => {
+                if !days.contains(weekday)
+                    || !(*from_minute..*until_minute).contains(&minute_of_day)
+                {
+                    return None;
+                }
+
+                let elapsed = minute_of_day - from_minute;
+                if elapsed % every_minutes == 0 {
+                    Some(&actions[(elapsed / every_minutes) as usize % 2])
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl Display for ScheduleRule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ScheduleRule::At {
+                days,
+                minute_of_day,
+                action,
+            } => write!(f, "{} {} {}", days, TimeOfDay(*minute_of_day), action),
+            ScheduleRule::Alternate {
+                days,
+                every_minutes,
+                from_minute,
+                until_minute,
+                actions,
+            } => write!(
+                f,
+                "{} every {}m {}-{} {} {}",
+                days,
+                every_minutes,
+                TimeOfDay(*from_minute),
+                TimeOfDay(*until_minute),
+                actions[0],
+                actions[1]
+            ),
+        }
+    }
+}
+
+impl FromStr for ScheduleRule {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<ScheduleRule, ScheduleError> {
+        let invalid_line = || ScheduleError::InvalidLine(s.to_string());
+
+        match s.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [days, time, action] => Ok(ScheduleRule::At {
+                days: days.parse().map_err(|_| invalid_line())?,
+                minute_of_day: parse_time(time).ok_or_else(invalid_line)?,
+                action: action.parse()?,
+            }),
+            [days, "every", every, range, first, second] => {
+                let every_minutes = parse_minutes(every).ok_or_else(invalid_line)?;
+                let (from_minute, until_minute) = match range.split('-').collect::<Vec<_>>()[..] {
+                    [from, until] => (
+                        parse_time(from).ok_or_else(invalid_line)?,
+                        parse_time(until).ok_or_else(invalid_line)?,
+                    ),
+                    _ => return Err(invalid_line()),
+                };
+                if every_minutes == 0 || from_minute >= until_minute {
+                    return Err(invalid_line());
+                }
+
+                Ok(ScheduleRule::Alternate {
+                    days: days.parse().map_err(|_| invalid_line())?,
+                    every_minutes,
+                    from_minute,
+                    until_minute,
+                    actions: [first.parse()?, second.parse()?],
+                })
+            }
+            _ => Err(invalid_line()),
+        }
+    }
+}
+
+// What a rule does when it fires
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScheduleAction {
+    MoveToHeight(f32),
+    MoveToPreset(String),
+}
+
+impl Display for ScheduleAction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ScheduleAction::MoveToHeight(h) => write!(f, "{}", h),
+            ScheduleAction::MoveToPreset(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// Anything that looks like a number is a height, and anything else is the name of a preset
+impl FromStr for ScheduleAction {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<ScheduleAction, ScheduleError> {
+        match s.parse::<f32>() {
+            Ok(h) => {
+                validate_height(h).map_err(ScheduleError::InvalidHeight)?;
+                Ok(ScheduleAction::MoveToHeight(h))
+            }
+            Err(_) => Ok(ScheduleAction::MoveToPreset(s.to_string())),
+        }
+    }
+}
+
+// The days of the week that a rule applies to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Days(u8);
+
+impl Days {
+    pub const DAILY: Days = Days(0x7f);
+    pub const WEEKDAYS: Days = Days(0x1f);
+    pub const WEEKENDS: Days = Days(0x60);
+
+    pub fn contains(self, weekday: Weekday) -> bool {
+        self.0 & day_bit(weekday) != 0
+    }
+}
+
+fn day_bit(weekday: Weekday) -> u8 {
+    1 << weekday.num_days_from_monday()
+}
+
+fn day_name(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "mon",
+        Weekday::Tue => "tue",
+        Weekday::Wed => "wed",
+        Weekday::Thu => "thu",
+        Weekday::Fri => "fri",
+        Weekday::Sat => "sat",
+        Weekday::Sun => "sun",
+    }
+}
+
+impl Display for Days {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Days::DAILY => write!(f, "daily"),
+            Days::WEEKDAYS => write!(f, "weekdays"),
+            Days::WEEKENDS => write!(f, "weekends"),
+            _ => {
+                let days = WEEKDAYS
+                    .iter()
+                    .filter(|&&weekday| self.contains(weekday))
+                    .map(|&weekday| day_name(weekday))
+                    .collect::<Vec<_>>();
+                write!(f, "{}", days.join(","))
+            }
+        }
+    }
+}
+
+// e.g. daily, weekdays, weekends or mon,wed,fri
+impl FromStr for Days {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Days, ScheduleError> {
+        match s {
+            "daily" => Ok(Days::DAILY),
+            "weekdays" => Ok(Days::WEEKDAYS),
+            "weekends" => Ok(Days::WEEKENDS),
+            _ => s
+                .split(',')
+                .map(|day| {
+                    day.parse::<Weekday>()
+                        .map(day_bit)
+                        .map_err(|_| ScheduleError::InvalidLine(s.to_string()))
+                })
+                .try_fold(0, |days, bit| Ok(days | bit?))
+                .map(Days),
+        }
+    }
+}
+
+// Formats minutes since midnight as e.g. 09:30
+struct TimeOfDay(u32);
+
+impl Display for TimeOfDay {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+// e.g. 09:30 -> 570. 24:00 is allowed, as the (exclusive) end of a range.
+fn parse_time(s: &str) -> Option<u32> {
+    let (hours, minutes) = match s.split(':').collect::<Vec<_>>()[..] {
+        [hours, minutes] if minutes.len() == 2 => {
+            (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?)
+        }
+        _ => return None,
+    };
+
+    let minute_of_day = hours * 60 + minutes;
+    if minutes < 60 && minute_of_day <= MINUTES_PER_DAY {
+        Some(minute_of_day)
+    } else {
+        None
+    }
+}
+
+// e.g. 30m -> 30
+fn parse_minutes(s: &str) -> Option<u32> {
+    s.strip_suffix('m')?.parse::<u32>().ok()
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    InvalidLine(String),
+    InvalidHeight(InvalidHeightError),
+    // In minutes
+    InvalidCooldown(u64),
+    InvalidFile(String),
+    Storage(io::Error),
+}
+
+impl Display for ScheduleError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ScheduleError::InvalidLine(line) => write!(
+                f,
+                "Invalid schedule rule: {:?} - must be e.g. \"weekdays 10:00 stand\", \"mon,wed 12:30 110\" or \"daily every 30m 09:00-17:00 sit stand\"",
+                line
+            ),
+            ScheduleError::InvalidHeight(e) => write!(f, "Invalid schedule: {}", e),
+            ScheduleError::InvalidCooldown(minutes) => write!(
+                f,
+                "Invalid schedule cooldown: {} minutes - must be at most {}",
+                minutes, MAX_COOLDOWN_MINUTES
+            ),
+            ScheduleError::InvalidFile(message) => write!(f, "Invalid schedule file: {}", message),
+            ScheduleError::Storage(e) => write!(f, "Failed to read or write schedule: {}", e),
+        }
+    }
+}
+
+impl Error for ScheduleError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> u32 {
+        parse_time(s).unwrap()
+    }
+
+    #[test]
+    fn test_rule_from_str() {
+        assert_eq!(
+            "weekdays 10:00 stand".parse::<ScheduleRule>().unwrap(),
+            ScheduleRule::At {
+                days: Days::WEEKDAYS,
+                minute_of_day: 600,
+                action: ScheduleAction::MoveToPreset("stand".to_string()),
+            }
+        );
+        assert_eq!(
+            "mon,wed 12:30 72.5".parse::<ScheduleRule>().unwrap(),
+            ScheduleRule::At {
+                days: Days(0x05),
+                minute_of_day: 750,
+                action: ScheduleAction::MoveToHeight(72.5),
+            }
+        );
+
+        assert!("weekdays 25:00 stand".parse::<ScheduleRule>().is_err());
+        assert!("weekdays 10:0 stand".parse::<ScheduleRule>().is_err());
+        assert!("someday 10:00 stand".parse::<ScheduleRule>().is_err());
+        assert!(matches!(
+            "daily 10:00 200".parse::<ScheduleRule>(),
+            Err(ScheduleError::InvalidHeight(_))
+        ));
+        assert!("daily every 0m 09:00-17:00 sit stand"
+            .parse::<ScheduleRule>()
+            .is_err());
+        assert!("daily every 30m 17:00-09:00 sit stand"
+            .parse::<ScheduleRule>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_schedule_from_toml() {
+        let contents = "cooldown_minutes = 45\nrules = [\n    'weekdays every 30m 09:00-17:00 sit 110',\n    'fri,sat 16:15 stand',\n]\n";
+        let schedule = Schedule::from_toml(contents).unwrap();
+
+        assert_eq!(schedule.cooldown(), Duration::from_secs(45 * 60));
+        assert_eq!(schedule.rules().len(), 2);
+
+        let file = ScheduleFile {
+            cooldown_minutes: 45,
+            rules: schedule.rules().iter().map(ToString::to_string).collect(),
+        };
+        assert_eq!(toml::to_string_pretty(&file).unwrap(), contents);
+
+        assert_eq!(Schedule::from_toml("").unwrap(), Schedule::default());
+        assert!(matches!(
+            Schedule::from_toml("cooldown_minutes = \"soon\""),
+            Err(ScheduleError::InvalidFile(_))
+        ));
+        assert!(matches!(
+            Schedule::from_toml("cooldown_minutes = -1"),
+            Err(ScheduleError::InvalidFile(_))
+        ));
+        assert!(matches!(
+            Schedule::from_toml("rules = ['someday 10:00 stand']"),
+            Err(ScheduleError::InvalidLine(_))
+        ));
+    }
+
+    #[test]
+    fn test_schedule_cooldown_is_bounded() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(Schedule::new(Vec::new(), day).unwrap().cooldown(), day);
+        assert!(matches!(
+            Schedule::new(Vec::new(), day + Duration::from_secs(60)),
+            Err(ScheduleError::InvalidCooldown(1441))
+        ));
+
+        assert!(matches!(
+            Schedule::cooldown_from_minutes(u64::MAX),
+            Err(ScheduleError::InvalidCooldown(u64::MAX))
+        ));
+        assert!(matches!(
+            Schedule::from_toml("cooldown_minutes = 1441"),
+            Err(ScheduleError::InvalidCooldown(1441))
+        ));
+        assert!(matches!(
+            Schedule::from_toml("cooldown_minutes = 9223372036854775807"),
+            Err(ScheduleError::InvalidCooldown(9223372036854775807))
+        ));
+    }
+
+    #[test]
+    fn test_schedule_action_at() {
+        let schedule = Schedule::from_toml(
+            "rules = ['weekdays every 30m 09:00-17:00 sit stand', 'weekdays 10:00 110']",
+        )
+        .unwrap();
+        let sit = ScheduleAction::MoveToPreset("sit".to_string());
+        let stand = ScheduleAction::MoveToPreset("stand".to_string());
+
+        assert_eq!(schedule.action_at(Weekday::Mon, time("08:30")), None);
+        assert_eq!(schedule.action_at(Weekday::Mon, time("09:00")), Some(&sit));
+        assert_eq!(schedule.action_at(Weekday::Mon, time("09:15")), None);
+        assert_eq!(
+            schedule.action_at(Weekday::Mon, time("09:30")),
+            Some(&stand)
+        );
+        assert_eq!(
+            schedule.action_at(Weekday::Mon, time("10:00")),
+            Some(&ScheduleAction::MoveToHeight(110.0))
+        );
+        assert_eq!(
+            schedule.action_at(Weekday::Mon, time("16:30")),
+            Some(&stand)
+        );
+        assert_eq!(schedule.action_at(Weekday::Mon, time("17:00")), None);
+        assert_eq!(schedule.action_at(Weekday::Sat, time("09:00")), None);
+    }
+}
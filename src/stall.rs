@@ -0,0 +1,216 @@
+// Detects when the desk has stopped moving even though we're sending it a key.
+// This happens if another key was pressed recently, or if the desk is too close to the target height.
+
+use std::time::{Duration, Instant};
+
+// Height changes smaller than this are treated as noise rather than progress
+const MIN_PROGRESS_CM: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StallConfig {
+    // How long the height may stay the same before the desk is considered stalled
+    pub window: Duration,
+    // How many recovery attempts to make before giving up
+    pub max_retries: usize,
+    // How many NoKey frames to send at the start of each recovery attempt
+    pub no_key_burst: usize,
+}
+
+impl Default for StallConfig {
+    fn default() -> StallConfig {
+        StallConfig {
+            window: Duration::from_millis(1500),
+            max_retries: 3,
+            no_key_burst: 25,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StallAction {
+    // The desk is moving (or hasn't had time to start moving yet)
+    None,
+    // The desk has stalled: send a NoKey burst, followed by a DeskReset if `reset_desk` is set
+    Recover { attempt: usize, reset_desk: bool },
+    // The desk is still stalled after `max_retries` recovery attempts
+    GiveUp,
+}
+
+#[derive(Debug)]
+pub struct StallDetector {
+    last_height: Option<f32>,
+    last_progress_at: Option<Instant>,
+    attempts: usize,
+}
+
+impl StallDetector {
+    pub fn new() -> StallDetector {
+        StallDetector {
+            last_height: None,
+            last_progress_at: None,
+            attempts: 0,
+        }
+    }
+
+    // The number of recovery attempts made since the desk last made progress
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    // Call whenever the controller stops driving the desk toward a target height
+    pub fn reset(&mut self) {
+        *self = StallDetector::new();
+    }
+
+    // Call each time the controller is about to drive the desk toward a target height
+    pub fn observe(&mut self, config: &StallConfig, height: f32, now: Instant) -> StallAction {
+        let progressed = match self.last_height {
+            Some(last_height) => (height - last_height).abs() >= MIN_PROGRESS_CM,
+            None => true,
+        };
+
+        if progressed {
+            self.last_height = Some(height);
+            self.last_progress_at = Some(now);
+            self.attempts = 0;
+            return StallAction::None;
+        }
+
+        let last_progress_at = self.last_progress_at.unwrap_or(now);
+        if now.duration_since(last_progress_at) < config.window {
+            return StallAction::None;
+        }
+
+        if self.attempts >= config.max_retries {
+            return StallAction::GiveUp;
+        }
+
+        self.attempts += 1;
+        // Give the recovery attempt a full window to take effect
+        self.last_progress_at = Some(now);
+
+        StallAction::Recover {
+            attempt: self.attempts,
+            // Only escalate to a desk reset if a NoKey burst on its own hasn't worked
+            reset_desk: self.attempts > 1,
+        }
+    }
+}
+
+impl Default for StallDetector {
+    fn default() -> StallDetector {
+        StallDetector::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stall_detector_progress() {
+        let config = StallConfig::default();
+        let mut detector = StallDetector::new();
+        let start = Instant::now();
+
+        for i in 0..100 {
+            assert_eq!(
+                detector.observe(
+                    &config,
+                    80.0 + i as f32 * 0.1,
+                    start + Duration::from_millis(i * 100)
+                ),
+                StallAction::None
+            );
+        }
+    }
+
+    #[test]
+    fn test_stall_detector_recovers_then_gives_up() {
+        let config = StallConfig::default();
+        let mut detector = StallDetector::new();
+        let start = Instant::now();
+
+        assert_eq!(detector.observe(&config, 80.0, start), StallAction::None);
+        assert_eq!(
+            detector.observe(&config, 80.0, start + config.window / 2),
+            StallAction::None
+        );
+
+        let mut now = start + config.window;
+        assert_eq!(
+            detector.observe(&config, 80.0, now),
+            StallAction::Recover {
+                attempt: 1,
+                reset_desk: false
+            }
+        );
+
+        // Each recovery attempt gets a full window before the next one
+        assert_eq!(
+            detector.observe(&config, 80.0, now + config.window / 2),
+            StallAction::None
+        );
+
+        now += config.window;
+        assert_eq!(
+            detector.observe(&config, 80.0, now),
+            StallAction::Recover {
+                attempt: 2,
+                reset_desk: true
+            }
+        );
+
+        now += config.window;
+        assert_eq!(
+            detector.observe(&config, 80.0, now),
+            StallAction::Recover {
+                attempt: 3,
+                reset_desk: true
+            }
+        );
+
+        now += config.window;
+        assert_eq!(detector.observe(&config, 80.0, now), StallAction::GiveUp);
+    }
+
+    #[test]
+    fn test_stall_detector_progress_resets_attempts() {
+        let config = StallConfig::default();
+        let mut detector = StallDetector::new();
+        let start = Instant::now();
+
+        detector.observe(&config, 80.0, start);
+        assert_eq!(
+            detector.observe(&config, 80.0, start + config.window),
+            StallAction::Recover {
+                attempt: 1,
+                reset_desk: false
+            }
+        );
+
+        let now = start + config.window * 2;
+        assert_eq!(detector.observe(&config, 81.0, now), StallAction::None);
+        assert_eq!(
+            detector.observe(&config, 81.0, now + config.window),
+            StallAction::Recover {
+                attempt: 1,
+                reset_desk: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_stall_detector_reset() {
+        let config = StallConfig::default();
+        let mut detector = StallDetector::new();
+        let start = Instant::now();
+
+        detector.observe(&config, 80.0, start);
+        detector.reset();
+        assert_eq!(
+            detector.observe(&config, 80.0, start + config.window),
+            StallAction::None
+        );
+    }
+}
@@ -0,0 +1,449 @@
+// A transport connects the controller to the desk and to the panel.
+// Frames read from one side are decoded by the run loop and written (possibly modified) to the other.
+
+mod capture;
+mod mock;
+mod reconnect;
+mod replay;
+#[cfg(unix)]
+mod serial;
+mod simulator;
+#[cfg(target_arch = "arm")]
+mod uart;
+
+pub(crate) use self::capture::ByteCapture;
+pub use self::capture::{CaptureReplayTransport, CapturingTransport};
+pub use self::mock::MockTransport;
+pub use self::reconnect::{Backoff, Reconnecting};
+pub use self::replay::ReplayTransport;
+#[cfg(unix)]
+pub use self::serial::{SerialTransport, UnsupportedBaudRateError};
+pub use self::simulator::{DeskSimulation, SimulatorTransport};
+#[cfg(target_arch = "arm")]
+pub use self::uart::UartTransport;
+
+use crate::capture::CaptureWriter;
+use crate::decoder::FrameDecoder;
+use crate::protocol::{DataFrame, DeskToPanelMessage, PanelToDeskMessage, DATA_FRAME_SIZE};
+use crate::{DeskError, InvalidConfigError};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub trait Transport: Send + Sync {
+    fn initialize(&self) -> Result<(), DeskError> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<(), DeskError> {
+        Ok(())
+    }
+
+    // Returns the next frame from the desk (if any) and the number of bytes dropped while looking for it
+    fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError>;
+
+    // Returns the next frame from the panel (if any) and the number of bytes dropped while looking for it
+    fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError>;
+
+    fn write_to_desk(&self, message: PanelToDeskMessage) -> Result<(), DeskError>;
+
+    fn write_to_panel(&self, message: DeskToPanelMessage) -> Result<(), DeskError>;
+
+    // Starts recording the bytes read from the desk and panel to `capture`, before they are decoded.
+    // Returns false if the transport doesn't read bytes (e.g. the simulator), so that its frames are recorded instead.
+    fn capture_bytes(&self, _capture: Arc<CaptureWriter>) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UartConfig {
+    pub desk_path: PathBuf,
+    pub panel_path: PathBuf,
+    pub baud_rate: u32,
+    // Uses BCM pin numbering. BCM GPIO 22 is tied to physical pin 15.
+    pub led_gpio_pin: u8,
+}
+
+impl Default for UartConfig {
+    fn default() -> UartConfig {
+        // The USB adapters show up as /dev/ttyUSB1 (desk) and /dev/ttyUSB0 (panel)
+        UartConfig {
+            desk_path: PathBuf::from("/dev/ttyAMA3"),
+            panel_path: PathBuf::from("/dev/ttyAMA2"),
+            baud_rate: 9600,
+            led_gpio_pin: 22,
+        }
+    }
+}
+
+impl UartConfig {
+    pub fn validate(&self) -> Result<(), InvalidConfigError> {
+        if self.desk_path.as_os_str().is_empty() {
+            return Err(InvalidConfigError::new("desk_path", "must not be empty"));
+        }
+
+        if self.panel_path.as_os_str().is_empty() {
+            return Err(InvalidConfigError::new("panel_path", "must not be empty"));
+        }
+
+        if self.desk_path == self.panel_path {
+            return Err(InvalidConfigError::new(
+                "panel_path",
+                format!("{:?} is also the desk path", self.panel_path),
+            ));
+        }
+
+        if self.baud_rate == 0 {
+            return Err(InvalidConfigError::new("baud_rate", "must not be zero"));
+        }
+
+        // The Raspberry Pi's header exposes BCM GPIO 0 to 27
+        if self.led_gpio_pin > 27 {
+            return Err(InvalidConfigError::new(
+                "led_gpio_pin",
+                format!("{} must be between 0 and 27", self.led_gpio_pin),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransportKind {
+    Uart(UartConfig),
+    // Any pair of ttys, using the same config as the UARTs (apart from the LED)
+    Serial(UartConfig),
+    Mock,
+    Simulator,
+    Replay {
+        desk_path: PathBuf,
+        panel_path: PathBuf,
+    },
+    Capture {
+        path: PathBuf,
+        speed: f64,
+    },
+}
+
+impl TransportKind {
+    pub fn open(&self) -> Result<Arc<dyn Transport>, DeskError> {
+        match self {
+            #[cfg(target_arch = "arm")]
+            TransportKind::Uart(config) => Ok(Arc::new(UartTransport::new(config)?)),
+            #[cfg(not(target_arch = "arm"))]
+            TransportKind::Uart(_) => Err(DeskError::transport(UnsupportedTransportError(
+                self.clone(),
+            ))),
+            #[cfg(unix)]
+            TransportKind::Serial(config) => Ok(Arc::new(SerialTransport::new(config)?)),
+            #[cfg(not(unix))]
+            TransportKind::Serial(_) => Err(DeskError::transport(UnsupportedTransportError(
+                self.clone(),
+            ))),
+            TransportKind::Mock => Ok(Arc::new(MockTransport::new())),
+            TransportKind::Simulator => Ok(Arc::new(SimulatorTransport::new())),
+            TransportKind::Replay {
+                desk_path,
+                panel_path,
+            } => Ok(Arc::new(ReplayTransport::new(desk_path, panel_path)?)),
+            TransportKind::Capture { path, speed } => {
+                Ok(Arc::new(CaptureReplayTransport::open(path, *speed)?))
+            }
+        }
+    }
+}
+
+impl Default for TransportKind {
+    #[cfg(target_arch = "arm")]
+    fn default() -> TransportKind {
+        TransportKind::Uart(UartConfig::default())
+    }
+
+    #[cfg(not(target_arch = "arm"))]
+    fn default() -> TransportKind {
+        TransportKind::Simulator
+    }
+}
+
+// Parses "uart" or "serial" (with the default UART config), "mock", "simulator", "replay:<desk path>,<panel path>"
+// or "capture:<path>[,<speed>]" (the speed defaults to 1, i.e. as captured, and may be "inf")
+impl FromStr for TransportKind {
+    type Err = InvalidTransportError;
+
+    fn from_str(s: &str) -> Result<TransportKind, InvalidTransportError> {
+        match s {
+            "uart" => Ok(TransportKind::Uart(UartConfig::default())),
+            "serial" => Ok(TransportKind::Serial(UartConfig::default())),
+            "mock" => Ok(TransportKind::Mock),
+            "simulator" => Ok(TransportKind::Simulator),
+            _ if s.starts_with("capture:") => parse_capture_kind(&s["capture:".len()..])
+                .ok_or_else(|| InvalidTransportError(s.to_string())),
+            _ => {
+                let paths = s
+                    .strip_prefix("replay:")
+                    .ok_or_else(|| InvalidTransportError(s.to_string()))?;
+
+                match paths.split(',').collect::<Vec<_>>().as_slice() {
+                    [desk_path, panel_path] if !desk_path.is_empty() && !panel_path.is_empty() => {
+                        Ok(TransportKind::Replay {
+                            desk_path: PathBuf::from(desk_path),
+                            panel_path: PathBuf::from(panel_path),
+                        })
+                    }
+                    _ => Err(InvalidTransportError(s.to_string())),
+                }
+            }
+        }
+    }
+}
+
+fn parse_capture_kind(s: &str) -> Option<TransportKind> {
+    let (path, speed) = match s.rsplit_once(',') {
+        Some((path, speed)) => (path, speed.parse::<f64>().ok()?),
+        None => (s, 1.0),
+    };
+
+    if path.is_empty() || speed.is_nan() || speed <= 0.0 {
+        return None;
+    }
+
+    Some(TransportKind::Capture {
+        path: PathBuf::from(path),
+        speed,
+    })
+}
+
+#[derive(Debug)]
+pub struct InvalidTransportError(String);
+
+impl Display for InvalidTransportError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid transport: {} - must be one of uart, serial, mock, simulator, replay:<desk path>,<panel path> or capture:<path>[,<speed>]",
+            self.0
+        )
+    }
+}
+
+impl Error for InvalidTransportError {}
+
+#[derive(Debug)]
+pub struct UnsupportedTransportError(TransportKind);
+
+impl Display for UnsupportedTransportError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unsupported transport: {:?} - not available on this platform",
+            self.0
+        )
+    }
+}
+
+impl Error for UnsupportedTransportError {}
+
+// A start bit, 8 data bits and a stop bit
+const BITS_PER_BYTE: u64 = 10;
+
+// How long it takes to send a frame (plus one byte of buffer)
+pub(crate) fn frame_duration(baud_rate: u32) -> Duration {
+    let frame_bits = (DATA_FRAME_SIZE as u64 + 1) * BITS_PER_BYTE;
+    Duration::from_micros(frame_bits * 1_000_000 / u64::from(baud_rate))
+}
+
+// Passes on the number of bytes read from a serial port. Once a USB adapter has been unplugged
+// its port reads nothing rather than failing, so a read of nothing checks that the port is still there.
+pub(crate) fn check_port_present(count: usize, path: &Path) -> Result<usize, DeskError> {
+    if count == 0 && !path.exists() {
+        return Err(DeskError::transport(format!(
+            "{} has been removed",
+            path.display()
+        )));
+    }
+
+    Ok(count)
+}
+
+// How many bytes a transport reads at once. A few frames' worth, so that a backlog is drained quickly.
+const READ_BUFFER_SIZE: usize = 4 * DATA_FRAME_SIZE;
+
+// Reads bytes in chunks and decodes frames from them.
+// Bytes after the end of a frame are kept for the next read, so no bytes are lost between frames.
+pub struct FrameReader {
+    decoder: FrameDecoder,
+    buffer: [u8; READ_BUFFER_SIZE],
+    start: usize,
+    end: usize,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader {
+            decoder: FrameDecoder::new(),
+            buffer: [0u8; READ_BUFFER_SIZE],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    // Reads until a complete frame is found. `read` fills the start of the buffer it is given and
+    // returns the number of bytes read, or 0 if none are available (e.g. after a read timeout or at end of file).
+    pub fn read_frame<F>(&mut self, mut read: F) -> Result<(Option<DataFrame>, usize), DeskError>
+    where
+        F: FnMut(&mut [u8]) -> Result<usize, DeskError>,
+    {
+        loop {
+            if self.start == self.end {
+                self.start = 0;
+                self.end = read(&mut self.buffer)?;
+
+                if self.end == 0 {
+                    return Ok((None, self.decoder.take_dropped_byte_count()));
+                }
+            }
+
+            let mut bytes = &self.buffer[self.start..self.end];
+            let frame = self.decoder.decode(&mut bytes);
+            self.start = self.end - bytes.len();
+
+            if let Some(frame) = frame {
+                return Ok((Some(frame), self.decoder.take_dropped_byte_count()));
+            }
+        }
+    }
+
+    // Bytes that have been read but not yet decoded, e.g. the frames after the first in a read
+    pub(crate) fn has_unread_bytes(&self) -> bool {
+        self.start < self.end
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> FrameReader {
+        FrameReader::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_kind_from_str() {
+        assert_eq!(
+            "uart".parse::<TransportKind>().unwrap(),
+            TransportKind::Uart(UartConfig::default())
+        );
+        assert_eq!(
+            "mock".parse::<TransportKind>().unwrap(),
+            TransportKind::Mock
+        );
+        assert_eq!(
+            "simulator".parse::<TransportKind>().unwrap(),
+            TransportKind::Simulator
+        );
+        assert_eq!(
+            "replay:desk.bin,panel.bin"
+                .parse::<TransportKind>()
+                .unwrap(),
+            TransportKind::Replay {
+                desk_path: PathBuf::from("desk.bin"),
+                panel_path: PathBuf::from("panel.bin"),
+            }
+        );
+
+        assert_eq!(
+            "capture:bus.jsonl".parse::<TransportKind>().unwrap(),
+            TransportKind::Capture {
+                path: PathBuf::from("bus.jsonl"),
+                speed: 1.0,
+            }
+        );
+        assert_eq!(
+            "capture:bus.jsonl,10".parse::<TransportKind>().unwrap(),
+            TransportKind::Capture {
+                path: PathBuf::from("bus.jsonl"),
+                speed: 10.0,
+            }
+        );
+        assert_eq!(
+            "capture:bus.jsonl,inf".parse::<TransportKind>().unwrap(),
+            TransportKind::Capture {
+                path: PathBuf::from("bus.jsonl"),
+                speed: f64::INFINITY,
+            }
+        );
+
+        assert!("".parse::<TransportKind>().is_err());
+        assert_eq!(
+            "serial".parse::<TransportKind>().unwrap(),
+            TransportKind::Serial(UartConfig::default())
+        );
+        assert!("rs232".parse::<TransportKind>().is_err());
+        assert!("replay:".parse::<TransportKind>().is_err());
+        assert!("replay:desk.bin".parse::<TransportKind>().is_err());
+        assert!("replay:desk.bin,".parse::<TransportKind>().is_err());
+        assert!("capture:".parse::<TransportKind>().is_err());
+        assert!("capture:bus.jsonl,0".parse::<TransportKind>().is_err());
+        assert!("capture:bus.jsonl,-1".parse::<TransportKind>().is_err());
+        assert!("capture:bus.jsonl,NaN".parse::<TransportKind>().is_err());
+        assert!("capture:bus.jsonl,fast".parse::<TransportKind>().is_err());
+    }
+
+    #[test]
+    fn test_uart_config_validate() {
+        assert!(UartConfig::default().validate().is_ok());
+
+        let config = UartConfig {
+            panel_path: PathBuf::from("/dev/ttyAMA3"),
+            ..UartConfig::default()
+        };
+        assert_eq!(config.validate().unwrap_err().field(), "panel_path");
+
+        let config = UartConfig {
+            baud_rate: 0,
+            ..UartConfig::default()
+        };
+        assert_eq!(config.validate().unwrap_err().field(), "baud_rate");
+
+        let config = UartConfig {
+            led_gpio_pin: 40,
+            ..UartConfig::default()
+        };
+        assert_eq!(config.validate().unwrap_err().field(), "led_gpio_pin");
+    }
+
+    #[test]
+    fn test_frame_reader() {
+        let mut bytes = vec![0u8, 1u8];
+        bytes.extend(&PanelToDeskMessage::Up.as_frame());
+        bytes.extend(&PanelToDeskMessage::Down.as_frame());
+        bytes.extend(&[104u8, 1u8, 0u8]);
+        let mut chunks = bytes.chunks(5);
+
+        let mut reader = FrameReader::new();
+        let mut read = |buffer: &mut [u8]| -> Result<usize, DeskError> {
+            Ok(chunks.next().map_or(0, |chunk| {
+                buffer[..chunk.len()].copy_from_slice(chunk);
+                chunk.len()
+            }))
+        };
+
+        assert_eq!(
+            reader.read_frame(&mut read).unwrap(),
+            (Some(PanelToDeskMessage::Up.as_frame()), 2)
+        );
+        assert_eq!(
+            reader.read_frame(&mut read).unwrap(),
+            (Some(PanelToDeskMessage::Down.as_frame()), 0)
+        );
+        assert_eq!(reader.read_frame(&mut read).unwrap(), (None, 0));
+    }
+}
@@ -0,0 +1,463 @@
+// Records the bytes read through another transport, and replays captures back through
+// `read_desk`/`read_panel` so that what was seen on the bus can be reproduced without the desk.
+
+use crate::capture::{read_capture, CaptureError, CaptureWriter, CapturedBytes, Direction};
+use crate::protocol::{DataFrame, DeskToPanelMessage, PanelToDeskMessage};
+use crate::transport::{FrameReader, Transport};
+use crate::DeskError;
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a replayed read waits for the next bytes before returning nothing,
+// so that the run loop can still notice a shutdown during a long gap in the capture
+const MAX_READ_WAIT: Duration = Duration::from_millis(100);
+
+// Avoids spinning once a direction's bytes have all been replayed
+const FINISHED_READ_WAIT: Duration = Duration::from_millis(8);
+
+// Transports that read bytes record them through this, once capturing has been started
+#[derive(Default)]
+pub(crate) struct ByteCapture {
+    capture: RwLock<Option<Arc<CaptureWriter>>>,
+}
+
+impl ByteCapture {
+    pub(crate) fn start(&self, capture: Arc<CaptureWriter>) {
+        *self.capture.write().unwrap() = Some(capture);
+    }
+
+    // Passes on the result of `read` (which reads into the start of `buffer`), recording the bytes read
+    pub(crate) fn read<F>(
+        &self,
+        direction: Direction,
+        buffer: &mut [u8],
+        read: F,
+    ) -> Result<usize, DeskError>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, DeskError>,
+    {
+        let count = read(buffer)?;
+
+        if count > 0 {
+            if let Some(capture) = self.capture.read().unwrap().as_ref() {
+                record(capture, direction, &buffer[..count]);
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+fn record(capture: &CaptureWriter, direction: Direction, bytes: &[u8]) {
+    // Losing part of the capture shouldn't stop the desk from working
+    if let Err(e) = capture.record(direction, bytes) {
+        warn!("Failed to capture bytes: {}", e);
+    }
+}
+
+pub struct CapturingTransport {
+    transport: Arc<dyn Transport>,
+    capture: Arc<CaptureWriter>,
+    // False if the transport doesn't read bytes, in which case each frame it returns is recorded
+    captures_bytes: bool,
+}
+
+impl CapturingTransport {
+    pub fn new(transport: Arc<dyn Transport>, capture: CaptureWriter) -> CapturingTransport {
+        let capture = Arc::new(capture);
+        let captures_bytes = transport.capture_bytes(capture.clone());
+
+        CapturingTransport {
+            transport,
+            capture,
+            captures_bytes,
+        }
+    }
+
+    pub fn create<P: AsRef<Path>>(
+        transport: Arc<dyn Transport>,
+        path: P,
+    ) -> Result<CapturingTransport, CaptureError> {
+        Ok(CapturingTransport::new(
+            transport,
+            CaptureWriter::create(path)?,
+        ))
+    }
+
+    fn record_frame(&self, direction: Direction, frame: Option<DataFrame>) {
+        if let Some(frame) = frame.filter(|_| !self.captures_bytes) {
+            record(&self.capture, direction, &frame);
+        }
+    }
+}
+
+impl Transport for CapturingTransport {
+    fn initialize(&self) -> Result<(), DeskError> {
+        self.transport.initialize()
+    }
+
+    fn shutdown(&self) -> Result<(), DeskError> {
+        self.capture.flush()?;
+        self.transport.shutdown()
+    }
+
+    fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        let (frame, dropped_byte_count) = self.transport.read_desk()?;
+        self.record_frame(Direction::DeskToPanel, frame);
+        Ok((frame, dropped_byte_count))
+    }
+
+    fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        let (frame, dropped_byte_count) = self.transport.read_panel()?;
+        self.record_frame(Direction::PanelToDesk, frame);
+        Ok((frame, dropped_byte_count))
+    }
+
+    fn write_to_desk(&self, message: PanelToDeskMessage) -> Result<(), DeskError> {
+        self.transport.write_to_desk(message)
+    }
+
+    fn write_to_panel(&self, message: DeskToPanelMessage) -> Result<(), DeskError> {
+        self.transport.write_to_panel(message)
+    }
+}
+
+// The bytes still to be replayed in one direction, and the frame reader they are decoded with
+struct Replay {
+    captured: VecDeque<CapturedBytes>,
+    reader: FrameReader,
+}
+
+impl Replay {
+    fn new(captured: VecDeque<CapturedBytes>) -> Replay {
+        Replay {
+            captured,
+            reader: FrameReader::new(),
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.captured.is_empty() && !self.reader.has_unread_bytes()
+    }
+}
+
+// Bytes are returned when they are due, at `speed` times the speed they were captured at
+// (an infinite speed returns them as fast as they are read), and decoded as the transports decode them,
+// so that the controller sees the same frames, invalid frames and dropped bytes. Writes are discarded.
+pub struct CaptureReplayTransport {
+    desk: Mutex<Replay>,
+    panel: Mutex<Replay>,
+    speed: f64,
+    // Set by the first read in either direction, so that both directions stay in step
+    started_at: Mutex<Option<Instant>>,
+    capture: ByteCapture,
+}
+
+impl CaptureReplayTransport {
+    pub fn new(captured: Vec<CapturedBytes>, speed: f64) -> CaptureReplayTransport {
+        let (desk, panel) = captured
+            .into_iter()
+            .partition(|captured| captured.direction == Direction::DeskToPanel);
+
+        CaptureReplayTransport {
+            desk: Mutex::new(Replay::new(desk)),
+            panel: Mutex::new(Replay::new(panel)),
+            speed,
+            started_at: Mutex::new(None),
+            capture: ByteCapture::default(),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        speed: f64,
+    ) -> Result<CaptureReplayTransport, CaptureError> {
+        Ok(CaptureReplayTransport::new(read_capture(path)?, speed))
+    }
+
+    // True once every byte has been read
+    pub fn is_finished(&self) -> bool {
+        self.desk.lock().unwrap().is_finished() && self.panel.lock().unwrap().is_finished()
+    }
+
+    fn replay_frame(
+        &self,
+        direction: Direction,
+        replay: &Mutex<Replay>,
+    ) -> Result<(Option<DataFrame>, usize), DeskError> {
+        let started_at = *self
+            .started_at
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+
+        let Replay { captured, reader } = &mut *replay.lock().unwrap();
+        reader.read_frame(|buffer| {
+            self.capture.read(direction, buffer, |buffer| {
+                Ok(self.replay_bytes(started_at, captured, buffer))
+            })
+        })
+    }
+
+    // Copies the next bytes into `buffer` once they are due, returning how many were copied
+    fn replay_bytes(
+        &self,
+        started_at: Instant,
+        captured: &mut VecDeque<CapturedBytes>,
+        buffer: &mut [u8],
+    ) -> usize {
+        let next = match captured.front_mut() {
+            Some(next) => next,
+            None => {
+                thread::sleep(FINISHED_READ_WAIT);
+                return 0;
+            }
+        };
+
+        let due_at = started_at + next.elapsed.div_f64(self.speed);
+        let wait = due_at.saturating_duration_since(Instant::now());
+        if wait > MAX_READ_WAIT {
+            thread::sleep(MAX_READ_WAIT);
+            return 0;
+        }
+        thread::sleep(wait);
+
+        // A read may have been bigger than the buffer, in which case the rest is left for the next one
+        let count = next.bytes.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&next.bytes[..count]);
+        next.bytes.drain(..count);
+        if next.bytes.is_empty() {
+            captured.pop_front();
+        }
+
+        count
+    }
+}
+
+impl Transport for CaptureReplayTransport {
+    fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        self.replay_frame(Direction::DeskToPanel, &self.desk)
+    }
+
+    fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        self.replay_frame(Direction::PanelToDesk, &self.panel)
+    }
+
+    fn write_to_desk(&self, message: PanelToDeskMessage) -> Result<(), DeskError> {
+        debug!("Capture replay: discarding write to desk: {:?}", message);
+        Ok(())
+    }
+
+    fn write_to_panel(&self, message: DeskToPanelMessage) -> Result<(), DeskError> {
+        debug!("Capture replay: discarding write to panel: {:?}", message);
+        Ok(())
+    }
+
+    fn capture_bytes(&self, capture: Arc<CaptureWriter>) -> bool {
+        self.capture.start(capture);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::parse_capture;
+    use crate::transport::SimulatorTransport;
+    use crate::{DeskController, FrameCounts};
+    use crossbeam_channel::unbounded;
+
+    fn bytes(elapsed_ms: u64, direction: Direction, bytes: &[u8]) -> CapturedBytes {
+        CapturedBytes::new(Duration::from_millis(elapsed_ms), direction, bytes)
+    }
+
+    // Reads until the transport has nothing more to return
+    fn read_all<F>(mut read: F) -> Vec<(Option<DataFrame>, usize)>
+    where
+        F: FnMut() -> Result<(Option<DataFrame>, usize), DeskError>,
+    {
+        let mut reads = Vec::new();
+        loop {
+            let read = read().unwrap();
+            reads.push(read);
+            if read.0.is_none() {
+                return reads;
+            }
+        }
+    }
+
+    #[test]
+    fn test_capture_replay_transport_paces_reads() {
+        let height = DeskToPanelMessage::Height(75.5).as_frame();
+        let transport = CaptureReplayTransport::new(
+            vec![
+                bytes(
+                    0,
+                    Direction::DeskToPanel,
+                    &DeskToPanelMessage::Height(75.0).as_frame(),
+                ),
+                bytes(
+                    0,
+                    Direction::PanelToDesk,
+                    &PanelToDeskMessage::Up.as_frame(),
+                ),
+                bytes(0, Direction::DeskToPanel, &height[..3]),
+                bytes(400, Direction::DeskToPanel, &height[3..]),
+            ],
+            4.0,
+        );
+
+        let started_at = Instant::now();
+        assert_eq!(
+            transport.read_desk().unwrap(),
+            (Some(DeskToPanelMessage::Height(75.0).as_frame()), 0)
+        );
+        assert_eq!(
+            transport.read_panel().unwrap(),
+            (Some(PanelToDeskMessage::Up.as_frame()), 0)
+        );
+        assert!(!transport.is_finished());
+
+        // The rest of the frame is due 100 ms in at 4x speed
+        let mut read = transport.read_desk().unwrap();
+        while read.0.is_none() && started_at.elapsed() < Duration::from_secs(1) {
+            read = transport.read_desk().unwrap();
+        }
+        assert_eq!(read, (Some(height), 0));
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+        assert!(transport.is_finished());
+
+        assert_eq!(transport.read_desk().unwrap(), (None, 0));
+        assert_eq!(transport.read_panel().unwrap(), (None, 0));
+    }
+
+    #[test]
+    fn test_capture_and_replay_through_controller() {
+        let mut bad_checksum = DeskToPanelMessage::Height(110.0).as_frame();
+        bad_checksum[5] += 1;
+        let mut bad_end_byte = DeskToPanelMessage::Height(105.0).as_frame();
+        bad_end_byte[6] = 0;
+        let height = DeskToPanelMessage::Height(100.0).as_frame();
+
+        // Noise, a frame split across reads, an invalid frame, a bad checksum and part of a frame
+        let mut desk_bytes = vec![0u8, 1u8];
+        desk_bytes.extend(&height[..2]);
+        let mut rest = height[2..].to_vec();
+        rest.extend(&bad_end_byte);
+        rest.extend(&bad_checksum);
+        rest.extend(&height[..3]);
+
+        // Stands in for the UARTs
+        let bus = CaptureReplayTransport::new(
+            vec![
+                bytes(0, Direction::DeskToPanel, &desk_bytes),
+                bytes(8, Direction::DeskToPanel, &rest),
+                bytes(
+                    8,
+                    Direction::PanelToDesk,
+                    &PanelToDeskMessage::NoKey.as_frame(),
+                ),
+            ],
+            f64::INFINITY,
+        );
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let capturing = CapturingTransport::new(
+            Arc::new(bus),
+            CaptureWriter::new(SharedBuffer(buffer.clone())),
+        );
+        let desk_reads = read_all(|| capturing.read_desk());
+        let panel_reads = read_all(|| capturing.read_panel());
+        assert_eq!(
+            desk_reads,
+            vec![(Some(height), 2), (Some(bad_checksum), 7), (None, 0)]
+        );
+
+        // Every byte is captured as read, including those that didn't make up a valid frame
+        let contents = buffer.lock().unwrap().clone();
+        let captured = parse_capture(&contents[..]).unwrap();
+        let captured_bytes = |direction| -> Vec<u8> {
+            captured
+                .iter()
+                .filter(|captured| captured.direction == direction)
+                .flat_map(|captured| captured.bytes.clone())
+                .collect()
+        };
+        assert_eq!(
+            captured_bytes(Direction::DeskToPanel),
+            [desk_bytes, rest].concat()
+        );
+        assert_eq!(
+            captured_bytes(Direction::PanelToDesk),
+            PanelToDeskMessage::NoKey.as_frame().to_vec()
+        );
+
+        // The capture replays exactly as it was read
+        let replay = CaptureReplayTransport::new(captured.clone(), f64::INFINITY);
+        assert_eq!(read_all(|| replay.read_desk()), desk_reads);
+        assert_eq!(read_all(|| replay.read_panel()), panel_reads);
+        assert!(replay.is_finished());
+
+        // And leaves the controller as the original bytes would have
+        let transport = Arc::new(CaptureReplayTransport::new(captured, f64::INFINITY));
+        let controller = DeskController::new(transport.clone());
+
+        let (_ctl_tx, ctl_rx) = unbounded::<bool>();
+        let run_controller = controller.clone();
+        let run = thread::spawn(move || run_controller.run(ctl_rx).is_ok());
+
+        let started_at = Instant::now();
+        while !transport.is_finished() && started_at.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        controller.shutdown().unwrap();
+        assert!(run.join().unwrap());
+
+        assert_eq!(controller.current_height().to_bits(), 100f32.to_bits());
+        // The frame with the bad checksum is forwarded, so it is found as well
+        assert_eq!(
+            controller.desk_frame_counts(),
+            FrameCounts {
+                found_frames: 2,
+                dropped_bytes: 9,
+                checksum_failures: 1,
+            }
+        );
+        assert_eq!(controller.panel_frame_counts().found_frames, 1);
+    }
+
+    #[test]
+    fn test_capturing_transport_records_frames_without_bytes() {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let capturing = CapturingTransport::new(
+            Arc::new(SimulatorTransport::new()),
+            CaptureWriter::new(SharedBuffer(buffer.clone())),
+        );
+
+        let (frame, _) = capturing.read_desk().unwrap();
+
+        let contents = buffer.lock().unwrap().clone();
+        let captured = parse_capture(&contents[..]).unwrap();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].direction, Direction::DeskToPanel);
+        assert_eq!(captured[0].bytes, frame.unwrap().to_vec());
+    }
+
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
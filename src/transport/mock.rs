@@ -0,0 +1,38 @@
+use crate::protocol::{DataFrame, DeskToPanelMessage, PanelToDeskMessage};
+use crate::transport::Transport;
+use crate::DeskError;
+use rand::Rng;
+use std::time;
+
+#[derive(Default)]
+pub struct MockTransport {}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport {}
+    }
+}
+
+impl Transport for MockTransport {
+    fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        std::thread::sleep(time::Duration::from_secs(3));
+
+        let mut rng = rand::thread_rng();
+        let height = rng.gen::<f32>() * 64.0 + 65.0;
+        Ok((Some(DeskToPanelMessage::Height(height).as_frame()), 0))
+    }
+
+    fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        std::thread::sleep(time::Duration::from_secs(3));
+        Ok((Some(PanelToDeskMessage::Three(121.0).as_frame()), 0))
+        // Ok((None, 0))
+    }
+
+    fn write_to_panel(&self, _: DeskToPanelMessage) -> Result<(), DeskError> {
+        Ok(())
+    }
+
+    fn write_to_desk(&self, _: PanelToDeskMessage) -> Result<(), DeskError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+// Reopens a connection (e.g. a UART) after it fails, so that unplugging and replugging
+// a USB-serial adapter doesn't need the controller to be restarted.
+// While the connection is down every use of it fails, which marks the controller degraded
+// until it has been reopened.
+
+use crate::DeskError;
+use log::{info, warn};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    // How long to wait after the first failed attempt to reopen the connection
+    pub initial: Duration,
+    // The wait doubles after each failed attempt, up to this
+    pub max: Duration,
+}
+
+impl Backoff {
+    // How long to wait after `attempts` failed attempts
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+
+        self.initial
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+type Open<T> = Box<dyn Fn() -> Result<T, DeskError> + Send + Sync>;
+
+pub struct Reconnecting<T> {
+    name: String,
+    open: Open<T>,
+    backoff: Backoff,
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    // None while disconnected
+    connection: Option<T>,
+    failed_attempts: u32,
+    retry_at: Instant,
+}
+
+impl<T> Reconnecting<T> {
+    // Opens the connection straight away. If that fails it is retried when the connection is first used,
+    // so the controller can start before the adapter is plugged in.
+    pub fn new<F>(name: String, backoff: Backoff, open: F) -> Reconnecting<T>
+    where
+        F: Fn() -> Result<T, DeskError> + Send + Sync + 'static,
+    {
+        let mut state = State {
+            connection: None,
+            failed_attempts: 0,
+            retry_at: Instant::now(),
+        };
+
+        match open() {
+            Ok(connection) => state.connection = Some(connection),
+            Err(e) => {
+                state.failed_attempts = 1;
+                state.retry_at += backoff.delay(1);
+                warn!(
+                    "Failed to open {}: {} - retrying in {:?}",
+                    name,
+                    e,
+                    backoff.delay(1)
+                );
+            }
+        }
+
+        Reconnecting {
+            name,
+            open: Box::new(open),
+            backoff,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connection.is_some()
+    }
+
+    // Runs `f` with the connection, reopening it first if it was lost and the backoff has passed.
+    // If `f` fails the connection is dropped, and reopened on the next call.
+    pub fn with<R, F>(&self, f: F) -> Result<R, DeskError>
+    where
+        F: FnOnce(&mut T) -> Result<R, DeskError>,
+    {
+        let mut state = self.state.lock().unwrap();
+
+        let mut connection = match state.connection.take() {
+            Some(connection) => connection,
+            None => self.reopen(&mut state)?,
+        };
+
+        match f(&mut connection) {
+            Ok(result) => {
+                state.connection = Some(connection);
+                Ok(result)
+            }
+            Err(e) => {
+                warn!("Lost connection to {}: {} - reconnecting", self.name, e);
+                state.failed_attempts = 0;
+                state.retry_at = Instant::now();
+                Err(e)
+            }
+        }
+    }
+
+    fn reopen(&self, state: &mut State<T>) -> Result<T, DeskError> {
+        let now = Instant::now();
+        if now < state.retry_at {
+            return Err(DeskError::transport(format!(
+                "{} is disconnected - retrying in {:?}",
+                self.name,
+                state.retry_at - now
+            )));
+        }
+
+        match (self.open)() {
+            Ok(connection) => {
+                info!(
+                    "Reconnected to {} after {} failed attempt(s)",
+                    self.name, state.failed_attempts
+                );
+                state.failed_attempts = 0;
+                Ok(connection)
+            }
+            Err(e) => {
+                state.failed_attempts += 1;
+                let delay = self.backoff.delay(state.failed_attempts);
+                state.retry_at = now + delay;
+                Err(DeskError::transport(format!(
+                    "Failed to reconnect to {}: {} - retrying in {:?}",
+                    self.name, e, delay
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(4), Duration::from_millis(800));
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+    }
+
+    // Stands in for a USB-serial adapter that can be unplugged
+    #[derive(Clone, Default)]
+    struct Adapter {
+        unplugged: Arc<AtomicBool>,
+        open_count: Arc<AtomicUsize>,
+    }
+
+    impl Adapter {
+        fn open(&self) -> Result<Adapter, DeskError> {
+            self.open_count.fetch_add(1, Ordering::SeqCst);
+            self.check(io::ErrorKind::NotFound)?;
+            Ok(self.clone())
+        }
+
+        fn read(&self) -> Result<(), DeskError> {
+            Ok(self.check(io::ErrorKind::BrokenPipe)?)
+        }
+
+        fn check(&self, kind: io::ErrorKind) -> io::Result<()> {
+            if self.unplugged.load(Ordering::SeqCst) {
+                return Err(io::Error::new(kind, "unplugged"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reconnecting() {
+        let adapter = Adapter::default();
+        let backoff = Backoff {
+            initial: Duration::from_millis(50),
+            max: Duration::from_millis(100),
+        };
+        let opener = adapter.clone();
+        let reconnecting = Reconnecting::new("adapter".to_string(), backoff, move || opener.open());
+
+        assert!(reconnecting.with(|a| a.read()).is_ok());
+        assert!(reconnecting.is_connected());
+
+        // The failed read drops the connection, and it is reopened straight away on the next use
+        adapter.unplugged.store(true, Ordering::SeqCst);
+        assert!(reconnecting.with(|a| a.read()).is_err());
+        assert!(!reconnecting.is_connected());
+        assert!(reconnecting.with(|a| a.read()).is_err());
+        assert_eq!(adapter.open_count.load(Ordering::SeqCst), 2);
+
+        // Until the backoff has passed, it isn't reopened even once the adapter is back
+        adapter.unplugged.store(false, Ordering::SeqCst);
+        assert!(reconnecting.with(|a| a.read()).is_err());
+        assert_eq!(adapter.open_count.load(Ordering::SeqCst), 2);
+
+        thread::sleep(backoff.delay(1));
+        assert!(reconnecting.with(|a| a.read()).is_ok());
+        assert!(reconnecting.is_connected());
+        assert_eq!(adapter.open_count.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_reconnecting_opens_late() {
+        let adapter = Adapter::default();
+        adapter.unplugged.store(true, Ordering::SeqCst);
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(1),
+        };
+        let opener = adapter.clone();
+        let reconnecting = Reconnecting::new("adapter".to_string(), backoff, move || opener.open());
+        assert!(!reconnecting.is_connected());
+
+        adapter.unplugged.store(false, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(2));
+        assert!(reconnecting.with(|a| a.read()).is_ok());
+    }
+}
@@ -0,0 +1,72 @@
+// Replays raw bytes previously recorded from the desk and panel UARTs.
+// Frames are returned at the rate the desk sends them; writes are discarded.
+
+use crate::capture::{CaptureWriter, Direction};
+use crate::protocol::{DataFrame, DeskToPanelMessage, PanelToDeskMessage};
+use crate::transport::{ByteCapture, FrameReader, Transport};
+use crate::DeskError;
+use log::debug;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// The desk sends one frame every 8 ms
+const FRAME_INTERVAL: Duration = Duration::from_millis(8);
+
+pub struct ReplayTransport {
+    desk: Mutex<(File, FrameReader)>,
+    panel: Mutex<(File, FrameReader)>,
+    capture: ByteCapture,
+}
+
+impl ReplayTransport {
+    pub fn new<P: AsRef<Path>>(desk_path: P, panel_path: P) -> Result<ReplayTransport, DeskError> {
+        Ok(ReplayTransport {
+            desk: Mutex::new((File::open(desk_path)?, FrameReader::new())),
+            panel: Mutex::new((File::open(panel_path)?, FrameReader::new())),
+            capture: ByteCapture::default(),
+        })
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        let (file, reader) = &mut *self.desk.lock().unwrap();
+        replay_frame(file, reader, &self.capture, Direction::DeskToPanel)
+    }
+
+    fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        let (file, reader) = &mut *self.panel.lock().unwrap();
+        replay_frame(file, reader, &self.capture, Direction::PanelToDesk)
+    }
+
+    fn write_to_desk(&self, message: PanelToDeskMessage) -> Result<(), DeskError> {
+        debug!("Replay: discarding write to desk: {:?}", message);
+        Ok(())
+    }
+
+    fn write_to_panel(&self, message: DeskToPanelMessage) -> Result<(), DeskError> {
+        debug!("Replay: discarding write to panel: {:?}", message);
+        Ok(())
+    }
+
+    fn capture_bytes(&self, capture: Arc<CaptureWriter>) -> bool {
+        self.capture.start(capture);
+        true
+    }
+}
+
+fn replay_frame<R: Read>(
+    file: &mut R,
+    reader: &mut FrameReader,
+    capture: &ByteCapture,
+    direction: Direction,
+) -> Result<(Option<DataFrame>, usize), DeskError> {
+    // Either pace the frames or (at end of file) avoid spinning
+    thread::sleep(FRAME_INTERVAL);
+
+    reader.read_frame(|buffer| capture.read(direction, buffer, |buffer| Ok(file.read(buffer)?)))
+}
@@ -0,0 +1,278 @@
+// Talks to the desk and panel through any pair of ttys (e.g. USB-TTL adapters on a PC, or pseudo-terminals
+// in tests), configured with termios rather than through the Raspberry Pi's UART driver.
+// Like the UART transport, each port is reopened if it fails.
+
+use crate::capture::{CaptureWriter, Direction};
+use crate::protocol::{DataFrame, DeskToPanelMessage, PanelToDeskMessage};
+use crate::transport::{
+    check_port_present, frame_duration, Backoff, ByteCapture, FrameReader, Reconnecting, Transport,
+    UartConfig,
+};
+use crate::DeskError;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::termios;
+use nix::sys::termios::{BaudRate, ControlFlags, FlushArg, SetArg, SpecialCharacterIndices};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub struct SerialTransport {
+    panel_read: Reconnecting<(File, FrameReader)>,
+    panel_write: Reconnecting<File>,
+    desk_read: Reconnecting<(File, FrameReader)>,
+    desk_write: Reconnecting<File>,
+    panel_path: PathBuf,
+    desk_path: PathBuf,
+    frame_duration: Duration,
+    capture: ByteCapture,
+}
+
+impl SerialTransport {
+    // The LED GPIO pin is ignored
+    pub fn new(config: &UartConfig) -> Result<SerialTransport, DeskError> {
+        config.validate()?;
+        let baud_rate = baud_rate(config.baud_rate).map_err(DeskError::transport)?;
+
+        Ok(SerialTransport {
+            panel_read: reconnecting_read_port("panel", &config.panel_path, baud_rate),
+            panel_write: reconnecting_port("panel", &config.panel_path, baud_rate),
+            desk_read: reconnecting_read_port("desk", &config.desk_path, baud_rate),
+            desk_write: reconnecting_port("desk", &config.desk_path, baud_rate),
+            panel_path: config.panel_path.clone(),
+            desk_path: config.desk_path.clone(),
+            frame_duration: frame_duration(config.baud_rate),
+            capture: ByteCapture::default(),
+        })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        self.desk_read.with(|(port, reader)| {
+            reader.read_frame(|buffer| {
+                self.capture.read(Direction::DeskToPanel, buffer, |buffer| {
+                    read_port(port, &self.desk_path, buffer)
+                })
+            })
+        })
+    }
+
+    fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        self.panel_read.with(|(port, reader)| {
+            reader.read_frame(|buffer| {
+                self.capture.read(Direction::PanelToDesk, buffer, |buffer| {
+                    read_port(port, &self.panel_path, buffer)
+                })
+            })
+        })
+    }
+
+    fn write_to_desk(&self, message: PanelToDeskMessage) -> Result<(), DeskError> {
+        self.desk_write
+            .with(|port| write_to_port(port, &message.as_frame(), self.frame_duration))
+    }
+
+    fn write_to_panel(&self, message: DeskToPanelMessage) -> Result<(), DeskError> {
+        self.panel_write
+            .with(|port| write_to_port(port, &message.as_frame(), self.frame_duration))
+    }
+
+    fn capture_bytes(&self, capture: Arc<CaptureWriter>) -> bool {
+        self.capture.start(capture);
+        true
+    }
+}
+
+fn baud_rate(baud_rate: u32) -> Result<BaudRate, UnsupportedBaudRateError> {
+    match baud_rate {
+        1200 => Ok(BaudRate::B1200),
+        2400 => Ok(BaudRate::B2400),
+        4800 => Ok(BaudRate::B4800),
+        9600 => Ok(BaudRate::B9600),
+        19200 => Ok(BaudRate::B19200),
+        38400 => Ok(BaudRate::B38400),
+        57600 => Ok(BaudRate::B57600),
+        115200 => Ok(BaudRate::B115200),
+        _ => Err(UnsupportedBaudRateError(baud_rate)),
+    }
+}
+
+// Opens the port as 8N1 in raw mode, so that bytes are passed through untouched.
+// Reads return whatever has arrived as soon as there is at least one byte,
+// or nothing after 100 ms so that the run loop can notice a shutdown.
+fn open_port(path: &Path, baud_rate: BaudRate) -> Result<File, DeskError> {
+    // Without O_NOCTTY the port could become the controlling terminal, and a hangup would kill the process.
+    // Without O_NONBLOCK the open could wait for carrier detect on a port with modem control, before
+    // CLOCAL has been set.
+    let port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags((OFlag::O_NOCTTY | OFlag::O_NONBLOCK).bits())
+        .open(path)?;
+    let fd = port.as_raw_fd();
+
+    let mut settings = termios::tcgetattr(fd).map_err(DeskError::transport)?;
+    termios::cfmakeraw(&mut settings);
+    termios::cfsetspeed(&mut settings, baud_rate).map_err(DeskError::transport)?;
+    settings.control_flags &= !(ControlFlags::PARENB | ControlFlags::CSTOPB | ControlFlags::CSIZE);
+    settings.control_flags |= ControlFlags::CS8 | ControlFlags::CLOCAL | ControlFlags::CREAD;
+    // VTIME is in tenths of a second
+    settings.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+    settings.control_chars[SpecialCharacterIndices::VTIME as usize] = 1;
+    termios::tcsetattr(fd, SetArg::TCSANOW, &settings).map_err(DeskError::transport)?;
+
+    // Reads block (for up to VTIME) from here on
+    let flags =
+        OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL).map_err(DeskError::transport)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags - OFlag::O_NONBLOCK)).map_err(DeskError::transport)?;
+
+    Ok(port)
+}
+
+// Each side is opened twice (for reading and for writing), so only the read handle drops what
+// arrived before it was set up - reopening the write handle mustn't discard bytes still to be read
+fn open_read_port(path: &Path, baud_rate: BaudRate) -> Result<File, DeskError> {
+    let port = open_port(path, baud_rate)?;
+    termios::tcflush(port.as_raw_fd(), FlushArg::TCIFLUSH).map_err(DeskError::transport)?;
+
+    Ok(port)
+}
+
+fn reconnecting_port(side: &str, path: &Path, baud_rate: BaudRate) -> Reconnecting<File> {
+    let name = format!("{} serial port {}", side, path.display());
+    let path = path.to_path_buf();
+    Reconnecting::new(name, Backoff::default(), move || {
+        open_port(&path, baud_rate)
+    })
+}
+
+// The frame reader is replaced too, as a partial frame won't be finished after reconnecting
+fn reconnecting_read_port(
+    side: &str,
+    path: &Path,
+    baud_rate: BaudRate,
+) -> Reconnecting<(File, FrameReader)> {
+    let name = format!("{} serial port {} (reading)", side, path.display());
+    let path = path.to_path_buf();
+    Reconnecting::new(name, Backoff::default(), move || {
+        Ok((open_read_port(&path, baud_rate)?, FrameReader::new()))
+    })
+}
+
+fn read_port(port: &mut File, path: &Path, buffer: &mut [u8]) -> Result<usize, DeskError> {
+    let count = port.read(buffer)?;
+    check_port_present(count, path)
+}
+
+fn write_to_port(port: &mut File, frame: &[u8], frame_duration: Duration) -> Result<(), DeskError> {
+    port.write_all(frame)?;
+
+    // Wait for the frame to be sent, so that frames don't run into each other
+    thread::sleep(frame_duration);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct UnsupportedBaudRateError(u32);
+
+impl Display for UnsupportedBaudRateError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unsupported baud rate: {} - must be one of 1200, 2400, 4800, 9600, 19200, 38400, 57600 or 115200",
+            self.0
+        )
+    }
+}
+
+impl Error for UnsupportedBaudRateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::openpty;
+    use nix::unistd::{close, ttyname};
+    use std::os::unix::io::FromRawFd;
+
+    // Returns the master end of a pseudo-terminal, and the path of its slave end
+    fn pty() -> (File, PathBuf) {
+        let pty = openpty(None, None).unwrap();
+        let path = ttyname(pty.slave).unwrap();
+        close(pty.slave).unwrap();
+        (unsafe { File::from_raw_fd(pty.master) }, path)
+    }
+
+    #[test]
+    fn test_serial_transport() {
+        let (mut desk, desk_path) = pty();
+        let (mut panel, panel_path) = pty();
+
+        let transport = SerialTransport::new(&UartConfig {
+            desk_path,
+            panel_path,
+            ..UartConfig::default()
+        })
+        .unwrap();
+
+        // Bytes split across writes still make a frame
+        let height = DeskToPanelMessage::Height(100.0).as_frame();
+        desk.write_all(&[0u8]).unwrap();
+        desk.write_all(&height[..3]).unwrap();
+        let reader = thread::spawn(move || transport.read_desk().map(|read| (read, transport)));
+        thread::sleep(Duration::from_millis(20));
+        desk.write_all(&height[3..]).unwrap();
+        let (read, transport) = reader.join().unwrap().unwrap();
+        assert_eq!(read, (Some(height), 1));
+
+        // Nothing to read
+        assert_eq!(transport.read_panel().unwrap(), (None, 0));
+
+        transport.write_to_desk(PanelToDeskMessage::Up).unwrap();
+        let mut frame = [0u8; 7];
+        desk.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, PanelToDeskMessage::Up.as_frame());
+
+        panel
+            .write_all(&PanelToDeskMessage::Down.as_frame())
+            .unwrap();
+        assert_eq!(
+            transport.read_panel().unwrap(),
+            (Some(PanelToDeskMessage::Down.as_frame()), 0)
+        );
+    }
+
+    #[test]
+    fn test_serial_transport_reopening_write_port_keeps_input() {
+        let (mut desk, desk_path) = pty();
+        let mut read_port = open_read_port(&desk_path, BaudRate::B9600).unwrap();
+
+        let height = DeskToPanelMessage::Height(100.0).as_frame();
+        desk.write_all(&height).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        // As when the write handle reconnects
+        let _write_port = open_port(&desk_path, BaudRate::B9600).unwrap();
+
+        let mut frame = [0u8; 7];
+        read_port.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, height);
+    }
+
+    #[test]
+    fn test_serial_transport_unsupported_baud_rate() {
+        let config = UartConfig {
+            baud_rate: 9601,
+            ..UartConfig::default()
+        };
+        assert!(SerialTransport::new(&config).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+// Simulates a desk that moves in response to the keys written to it.
+// The simulated desk accelerates up to its maximum speed while a key is held,
+// and coasts to a stop (overshooting slightly) once the key is released.
+// There is no simulated panel: reading from the panel never returns a frame.
+
+use crate::protocol::{DataFrame, DeskToPanelMessage, PanelToDeskMessage};
+use crate::transport::Transport;
+use crate::{DeskError, MAX_DESK_HEIGHT_CM, MIN_DESK_HEIGHT_CM};
+use log::debug;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INITIAL_HEIGHT_CM: f32 = 75.0;
+
+const MAX_SPEED_CM_PER_S: f32 = 3.8;
+const ACCELERATION_CM_PER_S2: f32 = 10.0;
+const DECELERATION_CM_PER_S2: f32 = 15.0;
+
+// Preset keys stop driving the desk once it is this close to the preset height
+const PRESET_TOLERANCE_CM: f32 = 0.1;
+
+// The desk sends one frame every 8 ms
+const FRAME_INTERVAL: Duration = Duration::from_millis(8);
+
+// How long a key is considered held after the last frame containing it
+const KEY_HOLD_DURATION: Duration = Duration::from_millis(100);
+
+// How long a read from the (non-existent) panel blocks before returning nothing
+const PANEL_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeskSimulation {
+    height: f32,
+    velocity: f32,
+}
+
+impl DeskSimulation {
+    pub fn new(height: f32) -> DeskSimulation {
+        DeskSimulation {
+            height,
+            velocity: 0.0,
+        }
+    }
+
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    // Advances the simulation by `dt` with `key` held (or no key held if None)
+    pub fn step(&mut self, key: Option<PanelToDeskMessage>, dt: Duration) {
+        let dt = dt.as_secs_f32();
+
+        let direction = match key {
+            Some(PanelToDeskMessage::Up) => 1.0,
+            Some(PanelToDeskMessage::Down) => -1.0,
+            Some(PanelToDeskMessage::One(h))
+            | Some(PanelToDeskMessage::Two(h))
+            | Some(PanelToDeskMessage::Three(h)) => {
+                if (h - self.height).abs() <= PRESET_TOLERANCE_CM {
+                    0.0
+                } else {
+                    (h - self.height).signum()
+                }
+            }
+            _ => 0.0,
+        };
+
+        let target_velocity = direction * MAX_SPEED_CM_PER_S;
+        let max_change = if direction == 0.0 {
+            DECELERATION_CM_PER_S2 * dt
+        } else {
+            ACCELERATION_CM_PER_S2 * dt
+        };
+
+        let change = (target_velocity - self.velocity)
+            .max(-max_change)
+            .min(max_change);
+        self.velocity += change;
+        self.height += self.velocity * dt;
+
+        if self.height <= MIN_DESK_HEIGHT_CM || self.height >= MAX_DESK_HEIGHT_CM {
+            self.height = self.height.max(MIN_DESK_HEIGHT_CM).min(MAX_DESK_HEIGHT_CM);
+            self.velocity = 0.0;
+        }
+    }
+
+    // The height reported by the desk, to the nearest mm
+    pub fn reported_height(&self) -> f32 {
+        (self.height * 10.0).round() / 10.0
+    }
+}
+
+struct SimulatorState {
+    simulation: DeskSimulation,
+    key: Option<(PanelToDeskMessage, Instant)>,
+    last_step: Instant,
+}
+
+pub struct SimulatorTransport {
+    state: Mutex<SimulatorState>,
+}
+
+impl SimulatorTransport {
+    pub fn new() -> SimulatorTransport {
+        SimulatorTransport::with_height(INITIAL_HEIGHT_CM)
+    }
+
+    pub fn with_height(height: f32) -> SimulatorTransport {
+        SimulatorTransport {
+            state: Mutex::new(SimulatorState {
+                simulation: DeskSimulation::new(height),
+                key: None,
+                last_step: Instant::now(),
+            }),
+        }
+    }
+
+    // The state of the simulated desk, as of the last frame read from it
+    pub fn simulation(&self) -> DeskSimulation {
+        self.state.lock().unwrap().simulation
+    }
+}
+
+impl Default for SimulatorTransport {
+    fn default() -> SimulatorTransport {
+        SimulatorTransport::new()
+    }
+}
+
+impl Transport for SimulatorTransport {
+    fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        thread::sleep(FRAME_INTERVAL);
+
+        let mut state = self.state.lock().unwrap();
+
+        let now = Instant::now();
+        let key = match state.key {
+            Some((key, pressed_at)) if now.duration_since(pressed_at) < KEY_HOLD_DURATION => {
+                Some(key)
+            }
+            _ => None,
+        };
+        let dt = now.duration_since(state.last_step);
+
+        state.simulation.step(key, dt);
+        state.last_step = now;
+
+        let height = state.simulation.reported_height();
+        Ok((Some(DeskToPanelMessage::Height(height).as_frame()), 0))
+    }
+
+    fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        thread::sleep(PANEL_READ_TIMEOUT);
+        Ok((None, 0))
+    }
+
+    fn write_to_desk(&self, message: PanelToDeskMessage) -> Result<(), DeskError> {
+        self.state.lock().unwrap().key = Some((message, Instant::now()));
+
+        // Writing a frame to the real desk takes about as long as the desk takes to send one
+        thread::sleep(FRAME_INTERVAL);
+
+        Ok(())
+    }
+
+    fn write_to_panel(&self, message: DeskToPanelMessage) -> Result<(), DeskError> {
+        debug!("Simulator: discarding write to panel: {:?}", message);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_for(
+        simulation: &mut DeskSimulation,
+        key: Option<PanelToDeskMessage>,
+        duration: Duration,
+    ) {
+        let steps = duration.as_millis() / FRAME_INTERVAL.as_millis();
+        for _ in 0..steps {
+            simulation.step(key, FRAME_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn test_desk_simulation_no_key() {
+        let mut simulation = DeskSimulation::new(100.0);
+        run_for(&mut simulation, None, Duration::from_secs(1));
+
+        assert_eq!(simulation, DeskSimulation::new(100.0));
+    }
+
+    #[test]
+    fn test_desk_simulation_up_accelerates_to_max_speed() {
+        let mut simulation = DeskSimulation::new(100.0);
+
+        simulation.step(Some(PanelToDeskMessage::Up), FRAME_INTERVAL);
+        assert!(simulation.velocity() > 0.0);
+        assert!(simulation.velocity() < MAX_SPEED_CM_PER_S);
+
+        run_for(
+            &mut simulation,
+            Some(PanelToDeskMessage::Up),
+            Duration::from_secs(1),
+        );
+        assert!(simulation.velocity() >= MAX_SPEED_CM_PER_S);
+        assert!(simulation.height() > 103.0);
+        assert!(simulation.height() < 104.0);
+    }
+
+    #[test]
+    fn test_desk_simulation_down() {
+        let mut simulation = DeskSimulation::new(100.0);
+        run_for(
+            &mut simulation,
+            Some(PanelToDeskMessage::Down),
+            Duration::from_secs(1),
+        );
+
+        assert!(simulation.velocity() < 0.0);
+        assert!(simulation.height() < 97.0);
+    }
+
+    #[test]
+    fn test_desk_simulation_overshoots_after_release() {
+        let mut simulation = DeskSimulation::new(100.0);
+        run_for(
+            &mut simulation,
+            Some(PanelToDeskMessage::Up),
+            Duration::from_secs(2),
+        );
+
+        let released_at = simulation.height();
+        run_for(
+            &mut simulation,
+            Some(PanelToDeskMessage::NoKey),
+            Duration::from_secs(1),
+        );
+
+        assert!(simulation.velocity() <= 0.0);
+        assert!(simulation.height() > released_at + 0.2);
+        assert!(simulation.height() < released_at + 1.0);
+    }
+
+    #[test]
+    fn test_desk_simulation_stops_at_limits() {
+        let mut simulation = DeskSimulation::new(128.0);
+        run_for(
+            &mut simulation,
+            Some(PanelToDeskMessage::Up),
+            Duration::from_secs(2),
+        );
+
+        assert_eq!(simulation, DeskSimulation::new(MAX_DESK_HEIGHT_CM));
+
+        let mut simulation = DeskSimulation::new(66.0);
+        run_for(
+            &mut simulation,
+            Some(PanelToDeskMessage::Down),
+            Duration::from_secs(2),
+        );
+
+        assert_eq!(simulation, DeskSimulation::new(MIN_DESK_HEIGHT_CM));
+    }
+
+    #[test]
+    fn test_desk_simulation_moves_to_preset() {
+        let mut simulation = DeskSimulation::new(100.0);
+        run_for(
+            &mut simulation,
+            Some(PanelToDeskMessage::Two(90.0)),
+            Duration::from_secs(5),
+        );
+
+        assert!((simulation.height() - 90.0).abs() < 1.0);
+
+        run_for(
+            &mut simulation,
+            Some(PanelToDeskMessage::Two(90.0)),
+            Duration::from_secs(5),
+        );
+
+        assert!((simulation.height() - 90.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_desk_simulation_reported_height() {
+        let simulation = DeskSimulation::new(100.04);
+        assert!((simulation.reported_height() - 100.0).abs() < 0.001);
+
+        let simulation = DeskSimulation::new(100.06);
+        assert!((simulation.reported_height() - 100.1).abs() < 0.001);
+    }
+}
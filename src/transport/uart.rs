@@ -0,0 +1,177 @@
+use crate::capture::{CaptureWriter, Direction};
+use crate::protocol::{DataFrame, DeskToPanelMessage, PanelToDeskMessage, DATA_FRAME_SIZE};
+use crate::transport::{
+    check_port_present, frame_duration, Backoff, ByteCapture, FrameReader, Reconnecting, Transport,
+    UartConfig,
+};
+use crate::DeskError;
+use rppal::gpio::{Gpio, Pin};
+use rppal::uart::{Parity, Uart};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Each UART is reopened if it fails (e.g. when a USB adapter is unplugged), so that the controller
+// carries on once it is plugged back in
+pub struct UartTransport {
+    panel_read: Reconnecting<(Uart, FrameReader)>,
+    panel_write: Reconnecting<Uart>,
+    desk_read: Reconnecting<(Uart, FrameReader)>,
+    desk_write: Reconnecting<Uart>,
+    panel_path: PathBuf,
+    desk_path: PathBuf,
+    led_gpio_pin: u8,
+    // How long it takes to send a frame (plus one byte of buffer)
+    frame_duration: Duration,
+    capture: ByteCapture,
+}
+
+impl UartTransport {
+    pub fn new(config: &UartConfig) -> Result<UartTransport, DeskError> {
+        config.validate()?;
+
+        Ok(UartTransport {
+            panel_read: reconnecting_read_uart("panel", &config.panel_path, config.baud_rate),
+            panel_write: reconnecting_uart("panel", &config.panel_path, config.baud_rate),
+            desk_read: reconnecting_read_uart("desk", &config.desk_path, config.baud_rate),
+            desk_write: reconnecting_uart("desk", &config.desk_path, config.baud_rate),
+            panel_path: config.panel_path.clone(),
+            desk_path: config.desk_path.clone(),
+            led_gpio_pin: config.led_gpio_pin,
+            frame_duration: frame_duration(config.baud_rate),
+            capture: ByteCapture::default(),
+        })
+    }
+}
+
+impl Transport for UartTransport {
+    fn initialize(&self) -> Result<(), DeskError> {
+        println!("Turning on LED at GPIO {}.", self.led_gpio_pin,);
+
+        // TODO: Figure out why the GPIO needs to be set to output mode
+        // by some process external to this application (e.g. wiring-pi)
+        let mut pin = led_pin(self.led_gpio_pin)?.into_output();
+
+        pin.set_high();
+
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<(), DeskError> {
+        println!("Turning off LED at GPIO {}.", self.led_gpio_pin,);
+
+        let mut pin = led_pin(self.led_gpio_pin)?.into_output();
+
+        pin.set_low();
+        drop(pin);
+
+        let current_state = led_pin(self.led_gpio_pin)?.read();
+        println!(
+            "New state of LED at GPIO {}: {}.",
+            self.led_gpio_pin, current_state
+        );
+
+        Ok(())
+    }
+
+    fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        self.desk_read.with(|(uart, reader)| {
+            reader.read_frame(|buffer| {
+                self.capture.read(Direction::DeskToPanel, buffer, |buffer| {
+                    read_uart(uart, &self.desk_path, buffer)
+                })
+            })
+        })
+    }
+
+    fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+        self.panel_read.with(|(uart, reader)| {
+            reader.read_frame(|buffer| {
+                self.capture.read(Direction::PanelToDesk, buffer, |buffer| {
+                    read_uart(uart, &self.panel_path, buffer)
+                })
+            })
+        })
+    }
+
+    fn write_to_desk(&self, message: PanelToDeskMessage) -> Result<(), DeskError> {
+        self.desk_write
+            .with(|uart| write_to_uart(uart, &message.as_frame(), self.frame_duration))
+    }
+
+    fn write_to_panel(&self, message: DeskToPanelMessage) -> Result<(), DeskError> {
+        self.panel_write
+            .with(|uart| write_to_uart(uart, &message.as_frame(), self.frame_duration))
+    }
+
+    fn capture_bytes(&self, capture: Arc<CaptureWriter>) -> bool {
+        self.capture.start(capture);
+        true
+    }
+}
+
+fn led_pin(pin: u8) -> Result<Pin, DeskError> {
+    Gpio::new()
+        .and_then(|gpio| gpio.get(pin))
+        .map_err(DeskError::transport)
+}
+
+fn open_uart(path: &Path, baud_rate: u32) -> Result<Uart, DeskError> {
+    Uart::with_path(path, baud_rate, Parity::None, 8, 1).map_err(DeskError::transport)
+}
+
+// Reads return whatever has arrived as soon as there is at least one byte,
+// or nothing after 100 ms so that the run loop can notice a shutdown
+fn open_read_uart(path: &Path, baud_rate: u32) -> Result<Uart, DeskError> {
+    let mut uart = open_uart(path, baud_rate)?;
+    uart.set_read_mode(1, Duration::from_millis(100))
+        .map_err(DeskError::transport)?;
+    Ok(uart)
+}
+
+fn reconnecting_uart(side: &str, path: &Path, baud_rate: u32) -> Reconnecting<Uart> {
+    let name = format!("{} UART {}", side, path.display());
+    let path = path.to_path_buf();
+    Reconnecting::new(name, Backoff::default(), move || {
+        open_uart(&path, baud_rate)
+    })
+}
+
+// The frame reader is replaced too, as a partial frame won't be finished after reconnecting
+fn reconnecting_read_uart(
+    side: &str,
+    path: &Path,
+    baud_rate: u32,
+) -> Reconnecting<(Uart, FrameReader)> {
+    let name = format!("{} UART {} (reading)", side, path.display());
+    let path = path.to_path_buf();
+    Reconnecting::new(name, Backoff::default(), move || {
+        Ok((open_read_uart(&path, baud_rate)?, FrameReader::new()))
+    })
+}
+
+fn read_uart(uart: &mut Uart, path: &Path, buffer: &mut [u8]) -> Result<usize, DeskError> {
+    let count = uart.read(buffer).map_err(DeskError::transport)?;
+    check_port_present(count, path)
+}
+
+fn write_to_uart(uart: &mut Uart, frame: &[u8], frame_duration: Duration) -> Result<(), DeskError> {
+    let bytes_written_count = uart.write(frame).map_err(DeskError::transport)?;
+
+    if bytes_written_count != DATA_FRAME_SIZE {
+        println!(
+            "Wrote {:?} bytes - Expected to write: {:?}",
+            bytes_written_count, DATA_FRAME_SIZE
+        );
+    }
+
+    // It takes a bit over one millisecond to transfer each byte at 9600 baud
+    // (Blocking doesn't seem to work)
+    // So we have to sleep for at least the length of the data frame (plus some buffer)
+    // to avoid sending overlapping frames.
+    // TODO: can we get blocking writes to work?
+    thread::sleep(frame_duration);
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+// The original plain text routes are kept (alongside /api/v1) while clients move over to the JSON API
+
+pub mod api;
+mod metrics;
+mod stream;
+
+use crate::config::WebConfig;
+use crate::web::stream::StreamLimit;
+use desk_controller::{DeskController, FrameCounts, DATA_FRAME_SIZE};
+use rocket::config::{ConfigError, Environment};
+use rocket::response::status::BadRequest;
+use rocket::*;
+
+// The address and port come from our config rather than ROCKET_ADDRESS and ROCKET_PORT
+pub fn rocket(controller: DeskController, config: &WebConfig) -> Result<Rocket, ConfigError> {
+    let mut builder = rocket::Config::build(Environment::active()?).port(config.port);
+    if let Some(address) = &config.address {
+        builder = builder.address(address.as_str());
+    }
+
+    let rocket_config = builder.finalize()?;
+    // Event streams may use up to half of the workers
+    let stream_limit = StreamLimit::new((rocket_config.workers / 2).max(1) as usize);
+
+    Ok(rocket::custom(rocket_config)
+        .manage(controller)
+        .manage(stream_limit)
+        .mount(
+            "/",
+            routes![
+                index,
+                current_height,
+                move_desk,
+                clear_target_height,
+                metrics::metrics
+            ],
+        )
+        .mount("/api/v1", api::routes())
+        .register(api::catchers()))
+}
+
+#[get("/")]
+pub fn index(controller: State<DeskController>) -> String {
+    let desk_counts = controller.desk_frame_counts();
+    let panel_counts = controller.panel_frame_counts();
+
+    format!(
+        "Current Height: {:?} cm\nTarget Height: {:?} cm\nVelocity: {:.1} cm/s\nMotion State: {:?}\nCurrent Panel Key: {:?}\nDesk - frames found: {:?}, bytes dropped: {:?} ({:?}%), checksum failures: {:?}\nPanel - frames found: {:?}, bytes dropped: {:?} ({:?}%), checksum failures: {:?}\nCoast Profile: {:?}",
+        controller.current_height(),
+        controller.target_height(),
+        controller.velocity(),
+        controller.motion_state(),
+        controller.current_panel_key(),
+        desk_counts.found_frames,
+        desk_counts.dropped_bytes,
+        dropped_byte_percentage(desk_counts),
+        desk_counts.checksum_failures,
+        panel_counts.found_frames,
+        panel_counts.dropped_bytes,
+        dropped_byte_percentage(panel_counts),
+        panel_counts.checksum_failures,
+        controller.coast_profile(),
+    )
+}
+
+pub fn dropped_byte_percentage(counts: FrameCounts) -> f32 {
+    100.0 * counts.dropped_bytes as f32
+        / (counts.found_frames * DATA_FRAME_SIZE + counts.dropped_bytes) as f32
+}
+
+#[get("/move_desk/<target_height>")]
+pub fn move_desk(
+    controller: State<DeskController>,
+    target_height: f32,
+) -> Result<(), BadRequest<String>> {
+    controller
+        .move_to_height(target_height)
+        .map_err(|e| BadRequest(Some(e.to_string())))
+}
+
+#[get("/clear_target_height")]
+pub fn clear_target_height(controller: State<DeskController>) {
+    controller.clear_target_height()
+}
+
+#[get("/current_height")]
+pub fn current_height(controller: State<DeskController>) -> String {
+    format!("{}", controller.current_height())
+}
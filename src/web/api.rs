@@ -0,0 +1,1111 @@
+// JSON API, mounted at /api/v1
+
+use crate::web::dropped_byte_percentage;
+use crate::web::stream::{EventStream, StreamLimit};
+use chrono::{DateTime, Local, NaiveDate};
+use desk_controller::{
+    DeskController, DeskError, DeskToPanelMessage, Direction, FrameCounts, Health, HealthStatus,
+    History, HistoryError, Incident, IncidentKind, InvalidHeightError, MemorySlot, MotionState,
+    Movement, MovementSource, PanelMemoryError, PanelMemorySlots, PanelToDeskMessage,
+    PassThroughLatency, PresetError, Schedule, ScheduleError, ScheduleRule, SitStand,
+    UnknownDeskFrame,
+};
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::*;
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+// How long a key pressed through the API is held for, unless the request says otherwise
+const DEFAULT_KEY_HOLD: Duration = Duration::from_millis(500);
+const MAX_KEY_HOLD: Duration = Duration::from_secs(10);
+
+// Limits on how much history is returned at once
+const DEFAULT_HISTORY_DAYS: u32 = 7;
+const MAX_HISTORY_DAYS: u32 = 366;
+const DEFAULT_HISTORY_WEEKS: u32 = 4;
+const MAX_HISTORY_WEEKS: u32 = 53;
+const DEFAULT_HISTORY_LIMIT: u32 = 50;
+const MAX_HISTORY_LIMIT: u32 = 1000;
+
+type ApiResult<T> = Result<Json<T>, Custom<Json<ApiError>>>;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        state,
+        health,
+        events,
+        put_target,
+        delete_target,
+        post_key,
+        get_presets,
+        get_preset,
+        put_preset,
+        delete_preset,
+        move_to_preset,
+        get_panel_memory,
+        put_panel_memory_slot,
+        get_schedule,
+        put_schedule,
+        resume_schedule,
+        history_days,
+        history_weeks,
+        history_movements,
+        history_incidents,
+        latency
+    ]
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![bad_request, not_found, unprocessable_entity]
+}
+
+#[derive(Serialize)]
+pub struct StateResponse {
+    current_height: f32,
+    target_height: Option<f32>,
+    velocity: f32,
+    motion_state: MotionState,
+    panel_key: Option<Key>,
+    pressed_key: Option<Key>,
+    health: Health,
+    last_unknown_desk_frame: Option<UnknownDeskFrameResponse>,
+    desk_frames: FrameStats,
+    panel_frames: FrameStats,
+}
+
+impl StateResponse {
+    fn new(controller: &DeskController) -> StateResponse {
+        StateResponse {
+            current_height: controller.current_height(),
+            target_height: controller.target_height(),
+            velocity: controller.velocity(),
+            motion_state: controller.motion_state(),
+            panel_key: controller.current_panel_key().map(Key::new),
+            pressed_key: controller.pressed_key().map(Key::new),
+            health: controller.health(),
+            last_unknown_desk_frame: controller
+                .last_unknown_desk_frame()
+                .map(UnknownDeskFrameResponse::new),
+            desk_frames: FrameStats::new(controller.desk_frame_counts()),
+            panel_frames: FrameStats::new(controller.panel_frame_counts()),
+        }
+    }
+}
+
+// e.g. {"opcode":2,"bytes":[1,2,0,0,3],"received_at":"..."}
+// The bytes are everything between the start byte and the end byte, checksum included
+#[derive(Serialize)]
+pub struct UnknownDeskFrameResponse {
+    opcode: u8,
+    bytes: [u8; 5],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received_at: Option<String>,
+}
+
+impl UnknownDeskFrameResponse {
+    fn new(frame: UnknownDeskFrame) -> UnknownDeskFrameResponse {
+        UnknownDeskFrameResponse {
+            received_at: Some(timestamp(frame.received_at)),
+            ..UnknownDeskFrameResponse::from_message(frame.message)
+        }
+    }
+
+    pub fn from_message(message: DeskToPanelMessage) -> UnknownDeskFrameResponse {
+        let frame = message.as_frame();
+
+        UnknownDeskFrameResponse {
+            opcode: frame[2],
+            bytes: [frame[1], frame[2], frame[3], frame[4], frame[5]],
+            received_at: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FrameStats {
+    #[serde(flatten)]
+    counts: FrameCounts,
+    dropped_byte_percentage: Option<f32>,
+}
+
+impl FrameStats {
+    fn new(counts: FrameCounts) -> FrameStats {
+        let percentage = dropped_byte_percentage(counts);
+
+        FrameStats {
+            counts,
+            // NaN until any bytes have been received
+            dropped_byte_percentage: if percentage.is_finite() {
+                Some(percentage)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Key {
+    name: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<f32>,
+}
+
+impl Key {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn new(key: PanelToDeskMessage) -> Key {
+        let (name, height) = match key {
+            PanelToDeskMessage::Up => ("up", None),
+            PanelToDeskMessage::Down => ("down", None),
+            PanelToDeskMessage::NoKey => ("no_key", None),
+            PanelToDeskMessage::DeskReset => ("desk_reset", None),
+            PanelToDeskMessage::One(h) => ("one", Some(h)),
+            PanelToDeskMessage::Two(h) => ("two", Some(h)),
+            PanelToDeskMessage::Three(h) => ("three", Some(h)),
+            PanelToDeskMessage::ResetOne => ("reset_one", None),
+            PanelToDeskMessage::ResetTwo => ("reset_two", None),
+            PanelToDeskMessage::ResetThree => ("reset_three", None),
+            PanelToDeskMessage::Unknown(..) => ("unknown", None),
+        };
+
+        Key { name, height }
+    }
+}
+
+// Memory keys (one, two, three) need the height that the panel would send with them
+pub fn parse_key(name: &str, height: Option<f32>) -> Result<PanelToDeskMessage, ApiError> {
+    let memory_key_height = || {
+        height.ok_or_else(|| {
+            ApiError::new(
+                "missing_height",
+                format!("Key {} requires a height query parameter", name),
+            )
+        })
+    };
+
+    match name {
+        "up" => Ok(PanelToDeskMessage::Up),
+        "down" => Ok(PanelToDeskMessage::Down),
+        "no_key" => Ok(PanelToDeskMessage::NoKey),
+        "desk_reset" => Ok(PanelToDeskMessage::DeskReset),
+        "one" => Ok(PanelToDeskMessage::One(memory_key_height()?)),
+        "two" => Ok(PanelToDeskMessage::Two(memory_key_height()?)),
+        "three" => Ok(PanelToDeskMessage::Three(memory_key_height()?)),
+        "reset_one" => Ok(PanelToDeskMessage::ResetOne),
+        "reset_two" => Ok(PanelToDeskMessage::ResetTwo),
+        "reset_three" => Ok(PanelToDeskMessage::ResetThree),
+        _ => Err(ApiError::new(
+            "unknown_key",
+            format!(
+                "Unknown key: {} - must be one of up, down, no_key, desk_reset, one, two, three, reset_one, reset_two or reset_three",
+                name
+            ),
+        )),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    error: ErrorBody,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_height: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_height: Option<f32>,
+}
+
+impl ApiError {
+    fn new(code: &'static str, message: String) -> ApiError {
+        ApiError {
+            error: ErrorBody {
+                code,
+                message,
+                height: None,
+                min_height: None,
+                max_height: None,
+            },
+        }
+    }
+
+    pub fn message(&self) -> String {
+        self.error.message.clone()
+    }
+
+    fn with_status(self, status: Status) -> Custom<Json<ApiError>> {
+        Custom(status, Json(self))
+    }
+
+    fn bad_request(self) -> Custom<Json<ApiError>> {
+        self.with_status(Status::BadRequest)
+    }
+}
+
+impl From<InvalidHeightError> for ApiError {
+    fn from(e: InvalidHeightError) -> ApiError {
+        let code = if e.is_out_of_range() {
+            "height_out_of_range"
+        } else {
+            "height_not_multiple_of_zero_point_five"
+        };
+
+        ApiError {
+            error: ErrorBody {
+                code,
+                message: e.to_string(),
+                height: Some(e.height()),
+                min_height: Some(e.min_height()),
+                max_height: Some(e.max_height()),
+            },
+        }
+    }
+}
+
+fn desk_error(e: DeskError) -> Custom<Json<ApiError>> {
+    match e {
+        DeskError::InvalidHeight(e) => ApiError::from(e).bad_request(),
+        _ => ApiError::new("desk_error", e.to_string()).with_status(Status::ServiceUnavailable),
+    }
+}
+
+fn preset_error(e: PresetError) -> Custom<Json<ApiError>> {
+    match e {
+        PresetError::InvalidHeight(e) => ApiError::from(e).bad_request(),
+        PresetError::InvalidName(_) => {
+            ApiError::new("invalid_preset_name", e.to_string()).bad_request()
+        }
+        PresetError::NotFound(_) => {
+            ApiError::new("preset_not_found", e.to_string()).with_status(Status::NotFound)
+        }
+        PresetError::InvalidFile(_) | PresetError::Storage(_) => {
+            ApiError::new("preset_storage_failed", e.to_string())
+                .with_status(Status::InternalServerError)
+        }
+    }
+}
+
+fn panel_memory_error(e: PanelMemoryError) -> Custom<Json<ApiError>> {
+    match e {
+        PanelMemoryError::InvalidHeight(e) => ApiError::from(e).bad_request(),
+        PanelMemoryError::InvalidFile(_) | PanelMemoryError::Storage(_) => {
+            ApiError::new("panel_memory_storage_failed", e.to_string())
+                .with_status(Status::InternalServerError)
+        }
+    }
+}
+
+fn schedule_error(e: ScheduleError) -> Custom<Json<ApiError>> {
+    match e {
+        ScheduleError::InvalidHeight(e) => ApiError::from(e).bad_request(),
+        ScheduleError::InvalidLine(_) => {
+            ApiError::new("invalid_schedule_rule", e.to_string()).bad_request()
+        }
+        ScheduleError::InvalidCooldown(_) => {
+            ApiError::new("invalid_schedule_cooldown", e.to_string()).bad_request()
+        }
+        ScheduleError::InvalidFile(_) | ScheduleError::Storage(_) => {
+            ApiError::new("schedule_storage_failed", e.to_string())
+                .with_status(Status::InternalServerError)
+        }
+    }
+}
+
+fn history_error(e: HistoryError) -> Custom<Json<ApiError>> {
+    ApiError::new("history_query_failed", e.to_string()).with_status(Status::InternalServerError)
+}
+
+fn history(controller: &DeskController) -> Result<Arc<History>, Custom<Json<ApiError>>> {
+    controller.history().ok_or_else(|| {
+        ApiError::new(
+            "history_disabled",
+            "History is not being recorded".to_string(),
+        )
+        .with_status(Status::NotFound)
+    })
+}
+
+// e.g. ?days=400 -> 400 days is too many
+fn history_count(
+    name: &str,
+    count: Option<u32>,
+    default: u32,
+    max: u32,
+) -> Result<u32, Custom<Json<ApiError>>> {
+    match count.unwrap_or(default) {
+        count if (1..=max).contains(&count) => Ok(count),
+        count => Err(ApiError::new(
+            "invalid_history_range",
+            format!("Invalid {}: {} - must be 1 to {}", name, count, max),
+        )
+        .bad_request()),
+    }
+}
+
+fn timestamp(t: SystemTime) -> String {
+    DateTime::<Local>::from(t).to_rfc3339()
+}
+
+#[derive(Serialize)]
+pub struct PresetResponse {
+    name: String,
+    height: f32,
+}
+
+#[derive(Serialize)]
+pub struct PresetsResponse {
+    presets: Vec<PresetResponse>,
+}
+
+#[derive(Deserialize)]
+pub struct PresetRequest {
+    height: f32,
+}
+
+#[derive(Deserialize)]
+pub struct PanelMemorySlotRequest {
+    height: f32,
+}
+
+// Rules are in the same form as in the schedule file, e.g. "weekdays 10:00 stand"
+#[derive(Serialize)]
+pub struct ScheduleResponse {
+    cooldown_minutes: u64,
+    rules: Vec<String>,
+    paused_for_s: Option<u64>,
+}
+
+impl ScheduleResponse {
+    fn new(controller: &DeskController) -> ScheduleResponse {
+        let schedule = controller.schedule();
+
+        ScheduleResponse {
+            cooldown_minutes: schedule.cooldown().as_secs() / 60,
+            rules: schedule.rules().iter().map(ToString::to_string).collect(),
+            paused_for_s: controller.schedule_paused_for().map(|d| d.as_secs()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleRequest {
+    cooldown_minutes: u64,
+    rules: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct SitStandResponse {
+    start: String,
+    sitting_s: u64,
+    standing_s: u64,
+}
+
+impl SitStandResponse {
+    fn new((start, sit_stand): (NaiveDate, SitStand)) -> SitStandResponse {
+        SitStandResponse {
+            start: start.to_string(),
+            sitting_s: sit_stand.sitting.as_secs(),
+            standing_s: sit_stand.standing.as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SitStandHistoryResponse {
+    periods: Vec<SitStandResponse>,
+}
+
+#[derive(Serialize)]
+pub struct MovementResponse {
+    started_at: String,
+    ended_at: String,
+    duration_s: f32,
+    start_height: f32,
+    end_height: f32,
+    source: MovementSource,
+}
+
+impl MovementResponse {
+    fn new(movement: Movement) -> MovementResponse {
+        MovementResponse {
+            started_at: timestamp(movement.started_at),
+            ended_at: timestamp(movement.ended_at),
+            duration_s: movement.duration().as_secs_f32(),
+            start_height: movement.start_height,
+            end_height: movement.end_height,
+            source: movement.source,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MovementsResponse {
+    movements: Vec<MovementResponse>,
+}
+
+#[derive(Serialize)]
+pub struct IncidentResponse {
+    at: String,
+    kind: IncidentKind,
+    height: f32,
+    message: String,
+}
+
+impl IncidentResponse {
+    fn new(incident: Incident) -> IncidentResponse {
+        IncidentResponse {
+            at: timestamp(incident.at),
+            kind: incident.kind,
+            height: incident.height,
+            message: incident.message,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct IncidentsResponse {
+    incidents: Vec<IncidentResponse>,
+}
+
+#[derive(Serialize)]
+pub struct LatencyBucketResponse {
+    le_ms: f64,
+    count: u64,
+}
+
+// Counted from when each frame was read to when it was written to the other side
+#[derive(Serialize)]
+pub struct LatencyResponse {
+    direction: Direction,
+    count: u64,
+    mean_ms: Option<f64>,
+    max_ms: f64,
+    jitter_ms: f64,
+    // Cumulative, like Prometheus buckets
+    buckets: Vec<LatencyBucketResponse>,
+}
+
+impl LatencyResponse {
+    fn new(latency: &PassThroughLatency) -> LatencyResponse {
+        let histogram = &latency.histogram;
+
+        LatencyResponse {
+            direction: latency.direction,
+            count: histogram.count,
+            mean_ms: if histogram.count > 0 {
+                Some(histogram.sum / histogram.count as f64 * 1000.0)
+            } else {
+                None
+            },
+            max_ms: latency.max * 1000.0,
+            jitter_ms: latency.jitter * 1000.0,
+            buckets: histogram
+                .upper_bounds
+                .iter()
+                .zip(&histogram.buckets)
+                .map(|(&upper_bound, &count)| LatencyBucketResponse {
+                    le_ms: upper_bound * 1000.0,
+                    count,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LatenciesResponse {
+    latencies: Vec<LatencyResponse>,
+}
+
+#[derive(Deserialize)]
+pub struct TargetRequest {
+    height: f32,
+}
+
+#[get("/state")]
+pub fn state(controller: State<DeskController>) -> Json<StateResponse> {
+    Json(StateResponse::new(&controller))
+}
+
+// Responds with 503 once a worker has stopped, so that a health check can restart the controller
+#[get("/health")]
+pub fn health(controller: State<DeskController>) -> Custom<Json<Health>> {
+    let health = controller.health();
+    let status = match health.status {
+        HealthStatus::Ok | HealthStatus::Degraded => Status::Ok,
+        HealthStatus::Failed => Status::ServiceUnavailable,
+    };
+
+    Custom(status, Json(health))
+}
+
+// Streams changes to the state as Server-Sent Events
+#[get("/events")]
+pub fn events(
+    controller: State<DeskController>,
+    limit: State<StreamLimit>,
+) -> Result<EventStream, Custom<Json<ApiError>>> {
+    EventStream::new(&controller, &limit).ok_or_else(|| {
+        ApiError::new(
+            "too_many_streams",
+            format!("At most {} event streams can be open at once", limit.max()),
+        )
+        .with_status(Status::ServiceUnavailable)
+    })
+}
+
+#[put("/target", format = "json", data = "<request>")]
+pub fn put_target(
+    controller: State<DeskController>,
+    request: Json<TargetRequest>,
+) -> ApiResult<StateResponse> {
+    controller
+        .move_to_height(request.height)
+        .map_err(desk_error)?;
+
+    Ok(Json(StateResponse::new(&controller)))
+}
+
+#[delete("/target")]
+pub fn delete_target(controller: State<DeskController>) -> Json<StateResponse> {
+    controller.clear_target_height();
+
+    Json(StateResponse::new(&controller))
+}
+
+// e.g. POST /api/v1/keys/up?hold_ms=1000 or POST /api/v1/keys/two?height=110
+#[post("/keys/<name>?<height>&<hold_ms>")]
+pub fn post_key(
+    controller: State<DeskController>,
+    name: String,
+    height: Option<f32>,
+    hold_ms: Option<u64>,
+) -> ApiResult<StateResponse> {
+    let key = parse_key(&name, height).map_err(ApiError::bad_request)?;
+
+    let hold = hold_ms.map_or(DEFAULT_KEY_HOLD, Duration::from_millis);
+    if hold > MAX_KEY_HOLD {
+        return Err(ApiError::new(
+            "hold_too_long",
+            format!(
+                "Invalid hold: {} ms - must be at most {} ms",
+                hold.as_millis(),
+                MAX_KEY_HOLD.as_millis()
+            ),
+        )
+        .bad_request());
+    }
+
+    controller.press_key(key, hold).map_err(desk_error)?;
+
+    Ok(Json(StateResponse::new(&controller)))
+}
+
+#[get("/presets")]
+pub fn get_presets(controller: State<DeskController>) -> Json<PresetsResponse> {
+    let presets = controller
+        .presets()
+        .iter()
+        .map(|(name, height)| PresetResponse {
+            name: name.to_string(),
+            height,
+        })
+        .collect();
+
+    Json(PresetsResponse { presets })
+}
+
+#[get("/presets/<name>")]
+pub fn get_preset(controller: State<DeskController>, name: String) -> ApiResult<PresetResponse> {
+    let height = controller
+        .preset(&name)
+        .ok_or_else(|| preset_error(PresetError::NotFound(name.clone())))?;
+
+    Ok(Json(PresetResponse { name, height }))
+}
+
+#[put("/presets/<name>", format = "json", data = "<request>")]
+pub fn put_preset(
+    controller: State<DeskController>,
+    name: String,
+    request: Json<PresetRequest>,
+) -> ApiResult<PresetResponse> {
+    controller
+        .save_preset(&name, request.height)
+        .map_err(preset_error)?;
+
+    Ok(Json(PresetResponse {
+        name,
+        height: request.height,
+    }))
+}
+
+#[delete("/presets/<name>")]
+pub fn delete_preset(controller: State<DeskController>, name: String) -> ApiResult<PresetResponse> {
+    let height = controller.delete_preset(&name).map_err(preset_error)?;
+
+    Ok(Json(PresetResponse { name, height }))
+}
+
+#[post("/presets/<name>/move")]
+pub fn move_to_preset(controller: State<DeskController>, name: String) -> ApiResult<StateResponse> {
+    controller.move_to_preset(&name).map_err(preset_error)?;
+
+    Ok(Json(StateResponse::new(&controller)))
+}
+
+// The heights stored in the panel's One/Two/Three memory slots, as far as the controller knows
+#[get("/panel/memory")]
+pub fn get_panel_memory(controller: State<DeskController>) -> Json<PanelMemorySlots> {
+    Json(controller.panel_memory_slots())
+}
+
+// Moves to the height, then stores it in the slot
+#[put("/panel/memory/<slot>", format = "json", data = "<request>")]
+pub fn put_panel_memory_slot(
+    controller: State<DeskController>,
+    slot: String,
+    request: Json<PanelMemorySlotRequest>,
+) -> ApiResult<PanelMemorySlots> {
+    let slot = slot
+        .parse::<MemorySlot>()
+        .map_err(|e| ApiError::new("invalid_memory_slot", e.to_string()).bad_request())?;
+
+    controller
+        .program_panel_memory_slot(slot, request.height)
+        .map_err(panel_memory_error)?;
+
+    Ok(Json(controller.panel_memory_slots()))
+}
+
+#[get("/schedule")]
+pub fn get_schedule(controller: State<DeskController>) -> Json<ScheduleResponse> {
+    Json(ScheduleResponse::new(&controller))
+}
+
+// Replaces the whole schedule
+#[put("/schedule", format = "json", data = "<request>")]
+pub fn put_schedule(
+    controller: State<DeskController>,
+    request: Json<ScheduleRequest>,
+) -> ApiResult<ScheduleResponse> {
+    let rules = request
+        .rules
+        .iter()
+        .map(|rule| rule.parse::<ScheduleRule>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(schedule_error)?;
+
+    let cooldown =
+        Schedule::cooldown_from_minutes(request.cooldown_minutes).map_err(schedule_error)?;
+    let schedule = Schedule::new(rules, cooldown).map_err(schedule_error)?;
+    controller.set_schedule(schedule).map_err(schedule_error)?;
+
+    Ok(Json(ScheduleResponse::new(&controller)))
+}
+
+// Ends a pause caused by a key being pressed on the panel
+#[post("/schedule/resume")]
+pub fn resume_schedule(controller: State<DeskController>) -> Json<ScheduleResponse> {
+    controller.resume_schedule();
+
+    Json(ScheduleResponse::new(&controller))
+}
+
+// Time spent sitting and standing on each of the last `days` days, oldest first
+#[get("/history/days?<days>")]
+pub fn history_days(
+    controller: State<DeskController>,
+    days: Option<u32>,
+) -> ApiResult<SitStandHistoryResponse> {
+    let days = history_count("days", days, DEFAULT_HISTORY_DAYS, MAX_HISTORY_DAYS)?;
+    let periods = history(&controller)?
+        .sit_stand_by_day(days, Local::now())
+        .map_err(history_error)?;
+
+    Ok(Json(SitStandHistoryResponse {
+        periods: periods.into_iter().map(SitStandResponse::new).collect(),
+    }))
+}
+
+// Time spent sitting and standing in each of the last `weeks` weeks (starting on Monday), oldest first
+#[get("/history/weeks?<weeks>")]
+pub fn history_weeks(
+    controller: State<DeskController>,
+    weeks: Option<u32>,
+) -> ApiResult<SitStandHistoryResponse> {
+    let weeks = history_count("weeks", weeks, DEFAULT_HISTORY_WEEKS, MAX_HISTORY_WEEKS)?;
+    let periods = history(&controller)?
+        .sit_stand_by_week(weeks, Local::now())
+        .map_err(history_error)?;
+
+    Ok(Json(SitStandHistoryResponse {
+        periods: periods.into_iter().map(SitStandResponse::new).collect(),
+    }))
+}
+
+#[get("/history/movements?<limit>")]
+pub fn history_movements(
+    controller: State<DeskController>,
+    limit: Option<u32>,
+) -> ApiResult<MovementsResponse> {
+    let limit = history_count("limit", limit, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT)?;
+    let movements = history(&controller)?
+        .movements(limit)
+        .map_err(history_error)?;
+
+    Ok(Json(MovementsResponse {
+        movements: movements.into_iter().map(MovementResponse::new).collect(),
+    }))
+}
+
+#[get("/history/incidents?<limit>")]
+pub fn history_incidents(
+    controller: State<DeskController>,
+    limit: Option<u32>,
+) -> ApiResult<IncidentsResponse> {
+    let limit = history_count("limit", limit, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT)?;
+    let incidents = history(&controller)?
+        .incidents(limit)
+        .map_err(history_error)?;
+
+    Ok(Json(IncidentsResponse {
+        incidents: incidents.into_iter().map(IncidentResponse::new).collect(),
+    }))
+}
+
+// How long frames take to pass through the controller in each direction
+#[get("/latency")]
+pub fn latency(controller: State<DeskController>) -> Json<LatenciesResponse> {
+    Json(LatenciesResponse {
+        latencies: controller
+            .metrics()
+            .pass_through_latencies
+            .iter()
+            .map(LatencyResponse::new)
+            .collect(),
+    })
+}
+
+#[catch(400)]
+pub fn bad_request(req: &Request) -> Json<ApiError> {
+    Json(ApiError::new(
+        "bad_request",
+        format!("Bad request: {} {}", req.method(), req.uri()),
+    ))
+}
+
+#[catch(404)]
+pub fn not_found(req: &Request) -> Json<ApiError> {
+    Json(ApiError::new(
+        "not_found",
+        format!("Not found: {} {}", req.method(), req.uri()),
+    ))
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(req: &Request) -> Json<ApiError> {
+    Json(ApiError::new(
+        "unprocessable_entity",
+        format!(
+            "Could not parse request body: {} {} - expected e.g. {{\"height\": 100.0}}",
+            req.method(),
+            req.uri()
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WebConfig;
+    use desk_controller::transport::{MockTransport, Transport};
+    use desk_controller::{DataFrame, DeskToPanelMessage};
+    use rocket::http::ContentType;
+    use rocket::local::{Client, LocalResponse};
+    use serde_json::{json, Value};
+    use std::thread;
+
+    fn api_client(controller: DeskController) -> Client {
+        Client::new(crate::web::rocket(controller, &WebConfig::default()).unwrap()).unwrap()
+    }
+
+    fn json_body(response: &mut LocalResponse) -> Value {
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    }
+
+    fn put_json<'c>(client: &'c Client, uri: &'static str, body: Value) -> LocalResponse<'c> {
+        client
+            .put(uri)
+            .header(ContentType::JSON)
+            .body(body.to_string())
+            .dispatch()
+    }
+
+    #[test]
+    fn test_put_target() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        let client = api_client(controller.clone());
+
+        let mut response = put_json(&client, "/api/v1/target", json!({ "height": 100.0 }));
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json_body(&mut response)["target_height"], json!(100.0));
+        assert_eq!(controller.target_height(), Some(100.0));
+
+        let mut response = put_json(&client, "/api/v1/target", json!({ "height": 200.0 }));
+        assert_eq!(response.status(), Status::BadRequest);
+        let body = json_body(&mut response);
+        assert_eq!(body["error"]["code"], json!("height_out_of_range"));
+        assert_eq!(body["error"]["height"], json!(200.0));
+
+        let mut response = put_json(&client, "/api/v1/target", json!({ "height": 100.2 }));
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("height_not_multiple_of_zero_point_five")
+        );
+
+        let mut response = put_json(&client, "/api/v1/target", json!({ "h": 100.0 }));
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("unprocessable_entity")
+        );
+
+        // Invalid heights leave the target alone
+        assert_eq!(controller.target_height(), Some(100.0));
+
+        let response = client.delete("/api/v1/target").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(controller.target_height(), None);
+    }
+
+    #[test]
+    fn test_post_key() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        let client = api_client(controller);
+
+        let response = client.post("/api/v1/keys/up").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let mut response = client.post("/api/v1/keys/sideways").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("unknown_key")
+        );
+
+        let mut response = client.post("/api/v1/keys/two").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("missing_height")
+        );
+
+        let mut response = client.post("/api/v1/keys/one?height=300").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let body = json_body(&mut response);
+        assert_eq!(body["error"]["code"], json!("height_out_of_range"));
+        assert_eq!(body["error"]["height"], json!(300.0));
+
+        let mut response = client.post("/api/v1/keys/up?hold_ms=60000").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("hold_too_long")
+        );
+    }
+
+    #[test]
+    fn test_presets() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        let client = api_client(controller.clone());
+
+        let mut response = put_json(
+            &client,
+            "/api/v1/presets/standing",
+            json!({ "height": 110.0 }),
+        );
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            json!({ "name": "standing", "height": 110.0 })
+        );
+
+        let mut response = client.get("/api/v1/presets").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            json!({ "presets": [{ "name": "standing", "height": 110.0 }] })
+        );
+
+        let response = client.post("/api/v1/presets/standing/move").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(controller.target_height(), Some(110.0));
+
+        let mut response = put_json(
+            &client,
+            "/api/v1/presets/standing",
+            json!({ "height": 200.0 }),
+        );
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("height_out_of_range")
+        );
+
+        let mut response = client.get("/api/v1/presets/sitting").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("preset_not_found")
+        );
+
+        let response = client.post("/api/v1/presets/sitting/move").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client.delete("/api/v1/presets/standing").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(controller.presets().is_empty());
+
+        let response = client.delete("/api/v1/presets/standing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        let client = api_client(controller);
+
+        let schedule = json!({ "cooldown_minutes": 30, "rules": ["weekdays 10:00 110"] });
+        let mut response = put_json(&client, "/api/v1/schedule", schedule);
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response)["rules"],
+            json!(["weekdays 10:00 110"])
+        );
+
+        let mut response = client.get("/api/v1/schedule").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json_body(&mut response)["cooldown_minutes"], json!(30));
+
+        let schedule = json!({ "cooldown_minutes": 30, "rules": ["weekdays 25:00 110"] });
+        let mut response = put_json(&client, "/api/v1/schedule", schedule);
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("invalid_schedule_rule")
+        );
+
+        let schedule = json!({ "cooldown_minutes": 30, "rules": ["weekdays 10:00 200"] });
+        let mut response = put_json(&client, "/api/v1/schedule", schedule);
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("height_out_of_range")
+        );
+
+        let schedule = json!({ "cooldown_minutes": 100_000, "rules": [] });
+        let mut response = put_json(&client, "/api/v1/schedule", schedule);
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("invalid_schedule_cooldown")
+        );
+
+        // The schedule is unchanged by the rejected requests
+        let mut response = client.get("/api/v1/schedule").dispatch();
+        assert_eq!(
+            json_body(&mut response)["rules"],
+            json!(["weekdays 10:00 110"])
+        );
+    }
+
+    // Reads heights from the desk, but panics as soon as the panel is read
+    struct PanelFailsTransport;
+
+    impl Transport for PanelFailsTransport {
+        fn read_desk(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+            thread::sleep(Duration::from_millis(8));
+            Ok((Some(DeskToPanelMessage::Height(80.0).as_frame()), 0))
+        }
+
+        fn read_panel(&self) -> Result<(Option<DataFrame>, usize), DeskError> {
+            panic!("panel reader failed");
+        }
+
+        fn write_to_desk(&self, _: PanelToDeskMessage) -> Result<(), DeskError> {
+            Ok(())
+        }
+
+        fn write_to_panel(&self, _: DeskToPanelMessage) -> Result<(), DeskError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_health() {
+        let client = api_client(DeskController::new(Arc::new(MockTransport::new())));
+        let mut response = client.get("/api/v1/health").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json_body(&mut response)["status"], json!("ok"));
+
+        let controller = DeskController::new(Arc::new(PanelFailsTransport));
+        let (_ctl_tx, ctl_rx) = crossbeam_channel::unbounded::<bool>();
+        let run_controller = controller.clone();
+        let run = thread::spawn(move || run_controller.run(ctl_rx));
+
+        let started_at = std::time::Instant::now();
+        while controller.health().stopped.is_empty()
+            && started_at.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        controller.shutdown().unwrap();
+        run.join().unwrap().unwrap();
+
+        let client = api_client(controller);
+        let mut response = client.get("/api/v1/health").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let body = json_body(&mut response);
+        assert_eq!(body["status"], json!("failed"));
+        assert_eq!(body["stopped"], json!(["panel reader"]));
+    }
+
+    #[test]
+    fn test_desk_error_status() {
+        let controller = DeskController::new(Arc::new(MockTransport::new()));
+        let e = controller.move_to_height(200.0).unwrap_err();
+        assert_eq!(desk_error(e).0, Status::BadRequest);
+        assert_eq!(
+            desk_error(DeskError::Shutdown).0,
+            Status::ServiceUnavailable
+        );
+        assert_eq!(
+            desk_error(DeskError::transport("unplugged")).0,
+            Status::ServiceUnavailable
+        );
+    }
+
+    #[test]
+    fn test_not_found() {
+        let client = api_client(DeskController::new(Arc::new(MockTransport::new())));
+        let mut response = client.get("/api/v1/nothing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            json_body(&mut response)["error"]["code"],
+            json!("not_found")
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// Prometheus metrics, in the text exposition format
+
+use desk_controller::{DeskController, FrameCounts, Histogram, Metrics};
+use rocket::http::ContentType;
+use rocket::response::Content;
+use rocket::*;
+use std::fmt::{Display, Write};
+
+#[get("/metrics")]
+pub fn metrics(controller: State<DeskController>) -> Content<String> {
+    let body = render(
+        controller.desk_frame_counts(),
+        controller.panel_frame_counts(),
+        controller.current_height(),
+        controller.target_height(),
+        &controller.metrics(),
+    );
+
+    Content(
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        body,
+    )
+}
+
+struct Writer {
+    out: String,
+}
+
+impl Writer {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP desk_controller_{} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE desk_controller_{} {}", name, kind).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, value))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        if labels.is_empty() {
+            writeln!(self.out, "desk_controller_{} {}", name, value).unwrap();
+        } else {
+            writeln!(self.out, "desk_controller_{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+
+    fn histogram(&mut self, name: &str, label: (&str, &str), histogram: &Histogram) {
+        let bucket = format!("{}_bucket", name);
+        for (count, upper_bound) in histogram.buckets.iter().zip(histogram.upper_bounds) {
+            self.sample(&bucket, &[label, ("le", &upper_bound.to_string())], *count);
+        }
+        self.sample(&bucket, &[label, ("le", "+Inf")], histogram.count);
+        self.sample(&format!("{}_sum", name), &[label], histogram.sum);
+        self.sample(&format!("{}_count", name), &[label], histogram.count);
+    }
+}
+
+fn render(
+    desk_counts: FrameCounts,
+    panel_counts: FrameCounts,
+    current_height: f32,
+    target_height: Option<f32>,
+    metrics: &Metrics,
+) -> String {
+    let mut w = Writer { out: String::new() };
+
+    // Frames from the desk go to the panel, and vice versa
+    let directions = [
+        ("desk_to_panel", desk_counts),
+        ("panel_to_desk", panel_counts),
+    ];
+
+    w.header("frames_found_total", "counter", "Frames found on the UART");
+    for (direction, counts) in &directions {
+        w.sample(
+            "frames_found_total",
+            &[("direction", direction)],
+            counts.found_frames,
+        );
+    }
+
+    w.header(
+        "dropped_bytes_total",
+        "counter",
+        "Bytes dropped while looking for frames",
+    );
+    for (direction, counts) in &directions {
+        w.sample(
+            "dropped_bytes_total",
+            &[("direction", direction)],
+            counts.dropped_bytes,
+        );
+    }
+
+    w.header(
+        "checksum_failures_total",
+        "counter",
+        "Frames with an invalid checksum",
+    );
+    for (direction, counts) in &directions {
+        w.sample(
+            "checksum_failures_total",
+            &[("direction", direction)],
+            counts.checksum_failures,
+        );
+    }
+
+    w.header("height_cm", "gauge", "Current height of the desk");
+    w.sample("height_cm", &[], current_height);
+
+    // Left out while there is no target, rather than reported as a height
+    w.header("target_height_cm", "gauge", "Height the desk is moving to");
+    if let Some(target_height) = target_height {
+        w.sample("target_height_cm", &[], target_height);
+    }
+
+    w.header(
+        "movement_duration_seconds",
+        "histogram",
+        "How long movements took, by what started them",
+    );
+    for (source, histogram) in &metrics.movement_durations {
+        w.histogram(
+            "movement_duration_seconds",
+            ("source", source.as_str()),
+            histogram,
+        );
+    }
+
+    w.header(
+        "pass_through_latency_seconds",
+        "histogram",
+        "How long frames took from being read from one side to being written to the other",
+    );
+    for latency in &metrics.pass_through_latencies {
+        w.histogram(
+            "pass_through_latency_seconds",
+            ("direction", latency.direction.as_str()),
+            &latency.histogram,
+        );
+    }
+
+    w.header(
+        "pass_through_latency_max_seconds",
+        "gauge",
+        "Longest a frame has taken to pass through",
+    );
+    for latency in &metrics.pass_through_latencies {
+        w.sample(
+            "pass_through_latency_max_seconds",
+            &[("direction", latency.direction.as_str())],
+            latency.max,
+        );
+    }
+
+    w.header(
+        "pass_through_jitter_seconds",
+        "gauge",
+        "Mean difference between the pass-through latencies of consecutive frames",
+    );
+    for latency in &metrics.pass_through_latencies {
+        w.sample(
+            "pass_through_jitter_seconds",
+            &[("direction", latency.direction.as_str())],
+            latency.jitter,
+        );
+    }
+
+    w.header(
+        "incidents_total",
+        "counter",
+        "Stall recoveries, stalls and errors",
+    );
+    for (kind, count) in &metrics.incidents {
+        w.sample("incidents_total", &[("kind", kind.as_str())], *count);
+    }
+
+    w.header(
+        "height_band_seconds_total",
+        "counter",
+        "Time spent in each height band",
+    );
+    for band in &metrics.height_bands {
+        w.sample(
+            "height_band_seconds_total",
+            &[
+                ("min_height_cm", &band.min_height_cm.to_string()),
+                ("max_height_cm", &band.max_height_cm.to_string()),
+            ],
+            band.time.as_secs_f64(),
+        );
+    }
+
+    w.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use desk_controller::{
+        Direction, HeightBand, IncidentKind, MovementSource, PassThroughLatency,
+        MOVEMENT_DURATION_BUCKETS_S, PASS_THROUGH_LATENCY_BUCKETS_S,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics {
+            movement_durations: vec![(
+                MovementSource::Api,
+                Histogram {
+                    upper_bounds: &MOVEMENT_DURATION_BUCKETS_S,
+                    buckets: vec![0, 1, 1, 2, 2, 2, 2, 2],
+                    sum: 8.5,
+                    count: 3,
+                },
+            )],
+            incidents: vec![(IncidentKind::Stall, 1)],
+            height_bands: vec![HeightBand {
+                min_height_cm: 65.0,
+                max_height_cm: 70.0,
+                time: Duration::from_millis(1500),
+            }],
+            pass_through_latencies: vec![PassThroughLatency {
+                direction: Direction::PanelToDesk,
+                histogram: Histogram {
+                    upper_bounds: &PASS_THROUGH_LATENCY_BUCKETS_S,
+                    buckets: vec![0, 0, 1, 2, 2, 2, 2, 2, 2, 2],
+                    sum: 0.012,
+                    count: 2,
+                },
+                max: 0.009,
+                jitter: 0.006,
+            }],
+        };
+        let desk_counts = FrameCounts {
+            found_frames: 100,
+            dropped_bytes: 3,
+            checksum_failures: 1,
+        };
+
+        let text = render(desk_counts, FrameCounts::default(), 72.5, None, &metrics);
+
+        for expected in &[
+            "# TYPE desk_controller_frames_found_total counter",
+            r#"desk_controller_frames_found_total{direction="desk_to_panel"} 100"#,
+            r#"desk_controller_dropped_bytes_total{direction="panel_to_desk"} 0"#,
+            "desk_controller_height_cm 72.5",
+            r#"desk_controller_movement_duration_seconds_bucket{source="api",le="2"} 1"#,
+            r#"desk_controller_movement_duration_seconds_bucket{source="api",le="+Inf"} 3"#,
+            r#"desk_controller_movement_duration_seconds_sum{source="api"} 8.5"#,
+            r#"desk_controller_incidents_total{kind="stall"} 1"#,
+            r#"desk_controller_pass_through_latency_seconds_bucket{direction="panel_to_desk",le="0.005"} 1"#,
+            r#"desk_controller_pass_through_latency_seconds_count{direction="panel_to_desk"} 2"#,
+            r#"desk_controller_pass_through_latency_max_seconds{direction="panel_to_desk"} 0.009"#,
+            r#"desk_controller_pass_through_jitter_seconds{direction="panel_to_desk"} 0.006"#,
+            r#"desk_controller_height_band_seconds_total{min_height_cm="65",max_height_cm="70"} 1.5"#,
+        ] {
+            assert!(
+                text.lines().any(|line| line == *expected),
+                "missing {:?} in:\n{}",
+                expected,
+                text
+            );
+        }
+
+        assert!(!text
+            .lines()
+            .any(|line| line.starts_with("desk_controller_target_height_cm")));
+        assert!(
+            render(desk_counts, desk_counts, 72.5, Some(110.0), &metrics)
+                .contains("desk_controller_target_height_cm 110\n")
+        );
+    }
+}
@@ -0,0 +1,232 @@
+// Server-Sent Events stream of state changes.
+// Each connection holds on to one of Rocket's worker threads for as long as it is open,
+// so only so many are allowed at once - the rest of the workers are left for other requests.
+
+use crate::web::api::{Key, UnknownDeskFrameResponse};
+use desk_controller::{DeskController, Event, HealthStatus, MotionState, Subscription};
+use rocket::http::ContentType;
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use serde::Serialize;
+use std::io;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Subscribers receive at most one batch of changes in this interval
+const EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+// Stops proxies (and browsers) from closing an idle connection
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+pub struct StreamLimit {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl StreamLimit {
+    pub fn new(max: usize) -> StreamLimit {
+        StreamLimit {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // None if `max` streams are already open
+    fn acquire(&self) -> Option<StreamPermit> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                if open < self.max {
+                    Some(open + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| StreamPermit(self.open.clone()))
+    }
+}
+
+// Counts as an open stream until it is dropped (when the client disconnects)
+struct StreamPermit(Arc<AtomicUsize>);
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct EventStream {
+    subscription: Subscription,
+    _permit: StreamPermit,
+    pending: Vec<u8>,
+    position: usize,
+    flush: bool,
+}
+
+impl EventStream {
+    // Starts with the current state, so that subscribers don't have to fetch it separately.
+    // Returns None if the limit on open streams has been reached.
+    pub fn new(controller: &DeskController, limit: &StreamLimit) -> Option<EventStream> {
+        let permit = limit.acquire()?;
+        let subscription = controller.subscribe(EVENT_INTERVAL);
+
+        let snapshot = [
+            Event::Height(controller.current_height()),
+            Event::TargetHeight(controller.target_height()),
+            Event::PanelKey(controller.current_panel_key()),
+            Event::MotionState(controller.motion_state()),
+            Event::Health(controller.health().status),
+        ];
+        let snapshot = snapshot
+            .iter()
+            .copied()
+            .chain(
+                controller
+                    .last_unknown_desk_frame()
+                    .map(|frame| Event::DeskFrame(frame.message)),
+            )
+            .collect::<Vec<_>>();
+
+        Some(EventStream {
+            subscription,
+            _permit: permit,
+            pending: format_events(&snapshot).into_bytes(),
+            position: 0,
+            flush: false,
+        })
+    }
+}
+
+impl Read for EventStream {
+    // With Rocket's "sse" feature, returning WouldBlock flushes everything written so far to the client
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            if self.flush {
+                self.flush = false;
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+
+            self.pending = match self.subscription.next_batch(KEEP_ALIVE_INTERVAL) {
+                None => return Ok(0),
+                Some(events) if events.is_empty() => b": keep-alive\n\n".to_vec(),
+                Some(events) => format_events(&events).into_bytes(),
+            };
+            self.position = 0;
+        }
+
+        let remaining = &self.pending[self.position..];
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+
+        self.position += n;
+        if self.position == self.pending.len() {
+            self.flush = true;
+        }
+
+        Ok(n)
+    }
+}
+
+impl<'r> Responder<'r> for EventStream {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("text", "event-stream"))
+            .raw_header("Cache-Control", "no-cache")
+            .streamed_body(self)
+            .ok()
+    }
+}
+
+// Serialized as e.g. {"current_height":80.1}
+#[derive(Serialize)]
+#[serde(untagged)]
+enum EventData {
+    Height {
+        current_height: f32,
+    },
+    TargetHeight {
+        target_height: Option<f32>,
+    },
+    PanelKey {
+        panel_key: Option<Key>,
+    },
+    MotionState {
+        motion_state: MotionState,
+    },
+    Health {
+        health: HealthStatus,
+    },
+    DeskFrame {
+        desk_frame: UnknownDeskFrameResponse,
+    },
+}
+
+// e.g.
+// event: height
+// data: {"current_height":80.1}
+fn format_events(events: &[Event]) -> String {
+    events
+        .iter()
+        .map(|event| {
+            let (name, data) = match *event {
+                Event::Height(h) => ("height", EventData::Height { current_height: h }),
+                Event::TargetHeight(h) => (
+                    "target_height",
+                    EventData::TargetHeight { target_height: h },
+                ),
+                Event::PanelKey(key) => (
+                    "panel_key",
+                    EventData::PanelKey {
+                        panel_key: key.map(Key::new),
+                    },
+                ),
+                Event::MotionState(state) => (
+                    "motion_state",
+                    EventData::MotionState {
+                        motion_state: state,
+                    },
+                ),
+                Event::Health(status) => ("health", EventData::Health { health: status }),
+                Event::DeskFrame(message) => (
+                    "desk_frame",
+                    EventData::DeskFrame {
+                        desk_frame: UnknownDeskFrameResponse::from_message(message),
+                    },
+                ),
+            };
+
+            format!(
+                "event: {}\ndata: {}\n\n",
+                name,
+                serde_json::to_string(&data).expect("failed to serialize event")
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_limit() {
+        let limit = StreamLimit::new(2);
+        let first = limit.acquire().unwrap();
+        let second = limit.acquire().unwrap();
+        assert!(limit.acquire().is_none());
+
+        drop(first);
+        let third = limit.acquire().unwrap();
+        assert!(limit.acquire().is_none());
+
+        drop(second);
+        drop(third);
+        assert_eq!(limit.open.load(Ordering::SeqCst), 0);
+    }
+}
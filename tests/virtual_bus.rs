@@ -0,0 +1,286 @@
+// End-to-end tests of the serial transport and the run loop together, on any Unix host.
+// A VirtualBus is a pair of pseudo-terminals standing in for the wires to the desk and to the panel,
+// with a simulated desk on the far end of one and a simulated panel on the far end of the other.
+// The controller is connected through a SerialTransport, just as it would be to USB adapters.
+#![cfg(unix)]
+
+use crossbeam_channel::unbounded;
+use desk_controller::transport::{
+    DeskSimulation, SerialTransport, SimulatorTransport, Transport, UartConfig,
+};
+use desk_controller::{
+    DataFrame, DeskController, DeskError, DeskToPanelMessage, FrameDecoder, PanelToDeskMessage,
+};
+use log::debug;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::openpty;
+use nix::sys::termios;
+use nix::sys::termios::SetArg;
+use nix::unistd::ttyname;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// The panel sends a frame about as often as the desk does
+const PANEL_FRAME_INTERVAL: Duration = Duration::from_millis(8);
+
+// How long the bus's threads wait for bytes before checking whether the bus has been dropped
+const POLL_TIMEOUT_MS: i32 = 50;
+
+struct VirtualBus {
+    // The paths of the controller's ends of the ptys
+    config: UartConfig,
+    desk: Arc<SimulatorTransport>,
+    state: Arc<BusState>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+    // The controller's ends of the ptys are kept open, so that the far ends never see a hangup
+    // (e.g. while the transport reconnects)
+    _ports: Vec<File>,
+}
+
+#[derive(Default)]
+struct BusState {
+    // The key the simulated panel is sending (NoKey if None)
+    panel_key: Mutex<Option<PanelToDeskMessage>>,
+    received_by_desk: Mutex<Vec<DataFrame>>,
+    received_by_panel: Mutex<Vec<DataFrame>>,
+}
+
+impl VirtualBus {
+    // Starts a simulated desk at `height`, with nothing pressed on the simulated panel
+    fn new(height: f32) -> Result<VirtualBus, DeskError> {
+        let (desk_end, desk_port) = open_pty()?;
+        let (panel_end, panel_port) = open_pty()?;
+
+        let config = UartConfig {
+            desk_path: ttyname(desk_port.as_raw_fd()).map_err(DeskError::transport)?,
+            panel_path: ttyname(panel_port.as_raw_fd()).map_err(DeskError::transport)?,
+            ..UartConfig::default()
+        };
+
+        let desk = Arc::new(SimulatorTransport::with_height(height));
+        let state = Arc::new(BusState::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let desk_end = Arc::new(desk_end);
+        let panel_end = Arc::new(panel_end);
+
+        let mut threads = Vec::new();
+
+        // The desk reports its height every 8 ms
+        let (d, port, s) = (desk.clone(), desk_end.clone(), stop.clone());
+        threads.push(thread::spawn(move || {
+            while !s.load(Ordering::SeqCst) {
+                if let Ok((Some(frame), _)) = d.read_desk() {
+                    write_frame(&port, &frame);
+                }
+            }
+        }));
+
+        // ...and moves while it receives Up, Down or a memory key
+        let (d, port, st, s) = (desk.clone(), desk_end, state.clone(), stop.clone());
+        threads.push(thread::spawn(move || {
+            read_frames(&port, &s, |frame| {
+                st.received_by_desk.lock().unwrap().push(frame);
+                if let Ok(message) = PanelToDeskMessage::from_frame(&frame) {
+                    let _ = d.write_to_desk(message);
+                }
+            })
+        }));
+
+        let (port, st, s) = (panel_end.clone(), state.clone(), stop.clone());
+        threads.push(thread::spawn(move || {
+            while !s.load(Ordering::SeqCst) {
+                let key = st
+                    .panel_key
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(PanelToDeskMessage::NoKey);
+                write_frame(&port, &key.as_frame());
+                thread::sleep(PANEL_FRAME_INTERVAL);
+            }
+        }));
+
+        let (port, st, s) = (panel_end, state.clone(), stop.clone());
+        threads.push(thread::spawn(move || {
+            read_frames(&port, &s, |frame| {
+                st.received_by_panel.lock().unwrap().push(frame)
+            })
+        }));
+
+        Ok(VirtualBus {
+            config,
+            desk,
+            state,
+            stop,
+            threads,
+            _ports: vec![desk_port, panel_port],
+        })
+    }
+
+    fn open_transport(&self) -> Result<SerialTransport, DeskError> {
+        SerialTransport::new(&self.config)
+    }
+
+    fn desk(&self) -> DeskSimulation {
+        self.desk.simulation()
+    }
+
+    // The simulated panel sends `key` until it is changed (None sends NoKey)
+    fn set_panel_key(&self, key: Option<PanelToDeskMessage>) {
+        *self.state.panel_key.lock().unwrap() = key;
+    }
+
+    // Every frame received by the simulated desk
+    fn received_by_desk(&self) -> Vec<DataFrame> {
+        self.state.received_by_desk.lock().unwrap().clone()
+    }
+
+    // Every frame received by the simulated panel
+    fn received_by_panel(&self) -> Vec<DataFrame> {
+        self.state.received_by_panel.lock().unwrap().clone()
+    }
+}
+
+impl Drop for VirtualBus {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Returns the far (master) end of a new pty, and the controller's (slave) end.
+// The slave is put into raw mode straight away, so that nothing written before the transport
+// opens it is echoed back or translated.
+fn open_pty() -> Result<(File, File), DeskError> {
+    let pty = openpty(None, None).map_err(DeskError::transport)?;
+    let (master, slave) = unsafe { (File::from_raw_fd(pty.master), File::from_raw_fd(pty.slave)) };
+
+    let mut settings = termios::tcgetattr(slave.as_raw_fd()).map_err(DeskError::transport)?;
+    termios::cfmakeraw(&mut settings);
+    termios::tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &settings)
+        .map_err(DeskError::transport)?;
+
+    // Frames are dropped rather than blocking while nothing is reading the controller's end
+    fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+        .map_err(DeskError::transport)?;
+
+    Ok((master, slave))
+}
+
+fn write_frame(port: &File, frame: &[u8]) {
+    if let Err(e) = (&*port).write_all(frame) {
+        debug!("Virtual bus: dropped frame {:?}: {}", frame, e);
+    }
+}
+
+fn read_frames<F: FnMut(DataFrame)>(port: &File, stop: &AtomicBool, mut f: F) {
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = [0u8; 64];
+
+    while !stop.load(Ordering::SeqCst) {
+        let mut fds = [PollFd::new(port.as_raw_fd(), PollFlags::POLLIN)];
+        if !matches!(poll(&mut fds, POLL_TIMEOUT_MS), Ok(ready) if ready > 0) {
+            continue;
+        }
+
+        match (&*port).read(&mut buffer) {
+            Ok(count) => {
+                let mut bytes = &buffer[..count];
+                while let Some(frame) = decoder.decode(&mut bytes) {
+                    f(frame);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                debug!("Virtual bus: read failed: {}", e);
+                thread::sleep(Duration::from_millis(POLL_TIMEOUT_MS as u64));
+            }
+        }
+    }
+}
+
+// Runs a controller on the bus until `f` returns
+fn with_controller<F: FnOnce(&VirtualBus, &DeskController)>(height: f32, f: F) {
+    let bus = VirtualBus::new(height).unwrap();
+    let controller = DeskController::new(Arc::new(bus.open_transport().unwrap()));
+
+    let (_ctl_tx, ctl_rx) = unbounded::<bool>();
+    let run_controller = controller.clone();
+    let run = thread::spawn(move || run_controller.run(ctl_rx).is_ok());
+
+    f(&bus, &controller);
+
+    controller.shutdown().unwrap();
+    assert!(run.join().unwrap());
+}
+
+fn wait_for<F: Fn() -> bool>(timeout: Duration, f: F) -> bool {
+    let started_at = Instant::now();
+    while !f() {
+        if started_at.elapsed() > timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+#[test]
+fn test_virtual_bus_move_to_height() {
+    with_controller(95.0, |bus, controller| {
+        assert!(wait_for(Duration::from_secs(2), || {
+            (controller.current_height() - 95.0).abs() < 0.05
+        }));
+
+        controller.move_to_height(100.0).unwrap();
+        assert!(wait_for(Duration::from_secs(10), || {
+            controller.target_height().is_none() && bus.desk().velocity().abs() < f32::EPSILON
+        }));
+
+        let height = bus.desk().height();
+        assert!((height - 100.0).abs() <= 0.5, "desk stopped at {}", height);
+        assert!((controller.current_height() - height).abs() <= 0.1);
+
+        // The panel was shown the heights the desk reported
+        assert!(bus
+            .received_by_panel()
+            .contains(&DeskToPanelMessage::Height(bus.desk().reported_height()).as_frame()));
+    });
+}
+
+#[test]
+fn test_virtual_bus_panel_key_passes_through() {
+    with_controller(95.0, |bus, controller| {
+        bus.set_panel_key(Some(PanelToDeskMessage::Up));
+        assert!(wait_for(Duration::from_secs(5), || bus.desk().height() > 96.0));
+
+        bus.set_panel_key(None);
+        assert!(wait_for(Duration::from_secs(5), || {
+            bus.desk().velocity().abs() < f32::EPSILON
+        }));
+
+        // Every frame from the panel reached the desk unchanged
+        let received = bus.received_by_desk();
+        assert!(received.contains(&PanelToDeskMessage::Up.as_frame()));
+        assert!(received.iter().all(|frame| {
+            *frame == PanelToDeskMessage::Up.as_frame()
+                || *frame == PanelToDeskMessage::NoKey.as_frame()
+        }));
+
+        // Both directions' frames were timed as they passed through
+        for latency in controller.metrics().pass_through_latencies {
+            assert!(latency.histogram.count > 0, "{:?}", latency.direction);
+            assert!(latency.max < 1.0, "{:?}", latency);
+        }
+    });
+}